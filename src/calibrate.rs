@@ -0,0 +1,193 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Interactive calibration: Light one LED after the other and let the user
+ * press the matching key, then write a layout file (see layout.rs) mapping
+ * the LEDs to the keys' input event codes.
+ */
+
+use std::fs;
+use std::time::Duration;
+
+use crate::canvas::Canvas;
+use crate::heartbeat;
+use crate::input::Input;
+use crate::layout::{self, Layout, Led};
+use crate::signals;
+use crate::types::{Color, ColorMethods};
+
+
+/* How often to check for termination signals while waiting for a key */
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+
+/*
+ * Wait for a single key to be pressed and released, and return its code.
+ * Returns None when several keys were pressed at once (to skip an LED), or
+ * when a termination signal was received.
+ */
+fn wait_for_key(input: &Input) -> Result<Option<u16>, String> {
+    let mut pressed = Vec::new();
+    let mut first = None;
+    let mut chord = false;
+
+    while signals::received().is_none() {
+        let Some(ev) = input.next(POLL_INTERVAL)? else {
+            continue;
+        };
+
+        if ev.down {
+            if first.is_none() {
+                first = Some(ev.code);
+            } else {
+                chord = true;
+            }
+            pressed.push(ev.code);
+        } else if pressed.contains(&ev.code) {
+            /* (Ignore keys that were already down before, e.g. enter) */
+            pressed.retain(|c| *c != ev.code);
+            if pressed.is_empty() {
+                return Ok(if chord { None } else { first });
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/*
+ * Build the LED entry for @index in the calibrated layout, given the keycode
+ * the user pressed for it.  Names and positions are taken from the current
+ * layout, or from another one for the same model that knows the keycode.
+ */
+fn calibrated_led(current: &Layout, layouts: &[Layout], leds: &[Led],
+                  index: usize, keycode: u16)
+    -> Led
+{
+    if let Some(led) = current.leds.iter().find(|l| l.index == index) {
+        return Led {
+            keycode: Some(keycode),
+            ..led.clone()
+        };
+    }
+
+    let known =
+        layouts.iter()
+            .filter(|l| l.pid == current.pid)
+            .flat_map(|l| l.leds_by_keycode(keycode))
+            .find(|l| !leds.iter().any(|led| led.key == l.key));
+
+    match known {
+        Some(led) => Led {
+            index,
+            keycode: Some(keycode),
+            ..led.clone()
+        },
+
+        None => Led {
+            index,
+            key: format!("led-{}", index),
+            area: None,
+            keycode: Some(keycode),
+        },
+    }
+}
+
+/*
+ * Calibrate the (single) keyboard on @canvas, and write the resulting layout
+ * to @output (or stdout).  With @all, also try the LEDs that the current
+ * layout does not have.
+ */
+pub fn calibrate(canvas: &Canvas, all: bool, output: Option<&str>)
+    -> Result<(), String>
+{
+    if canvas.keyboards.len() != 1 {
+        return Err(String::from("Can only calibrate one keyboard at a time \
+                                 (choose it with --device)"));
+    }
+
+    let kbd = &canvas.keyboards[0];
+    let input = Input::open(&canvas.keyboards)?;
+    let layouts = layout::load_all()?;
+
+    eprintln!("Press the key that belongs to the lit LED.  To skip an LED \
+               (e.g. one that does not belong to a key), press two keys at \
+               once.");
+
+    let indices: Vec<usize> =
+        (0..kbd.led_count).filter(|i| all || kbd.layout.has_led(*i)).collect();
+
+    let mut leds = Vec::<Led>::new();
+    let res = heartbeat::with_heartbeat(canvas, || -> Result<bool, String> {
+        for index in indices {
+            let mut raw = vec![0u8; kbd.led_count * 3];
+            let color = Color::WHITE;
+            raw[index * 3] = color.0;
+            raw[index * 3 + 1] = color.1;
+            raw[index * 3 + 2] = color.2;
+
+            if all {
+                kbd.all_leds_raw(&raw)?;
+            } else {
+                kbd.all_keys_raw(&raw)?;
+            }
+
+            match kbd.layout.leds.iter().find(|l| l.index == index) {
+                Some(led) => eprint!("LED {} ({}): ", index, led.key),
+                None => eprint!("LED {}: ", index),
+            }
+
+            let keycode = wait_for_key(&input)?;
+            if signals::received().is_some() {
+                eprintln!();
+                return Ok(false);
+            }
+
+            match keycode {
+                Some(keycode) => {
+                    eprintln!("keycode {}", keycode);
+                    let led = calibrated_led(&kbd.layout, &layouts, &leds,
+                                             index, keycode);
+                    leds.push(led);
+                }
+
+                None => {
+                    eprintln!("skipped");
+                    /* Keep what the current layout knows about it */
+                    if let Some(led) =
+                        kbd.layout.leds.iter().find(|l| l.index == index)
+                    {
+                        leds.push(led.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    });
+
+    if !res? {
+        return Ok(());
+    }
+
+    let text = Layout {
+        leds,
+        ..kbd.layout.clone()
+    }.serialize();
+
+    match output {
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+
+        Some(path) => match fs::write(path, text) {
+            Ok(()) => {
+                eprintln!("Layout written to {}", path);
+                Ok(())
+            }
+            Err(e) => Err(format!("Failed to write {}: {}", path, e)),
+        },
+    }
+}
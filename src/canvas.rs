@@ -0,0 +1,151 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::keyboard::Keyboard;
+
+
+/*
+ * One or more keyboards placed on a common plane, so that effects can treat
+ * them as a single wide (or tall) keyboard.
+ *
+ * The canvas's LEDs are all keyboards' LEDs concatenated, i.e. LED j of
+ * keyboard i has index bases[i] + j.
+ */
+pub struct Canvas {
+    pub keyboards: Vec<Keyboard>,
+    pub bases: Vec<usize>,
+
+    /* In keyboard units (see layout.rs) */
+    pub width: f32,
+    pub height: f32,
+    pub led_count: usize,
+
+    /* Key center of every LED, in keyboard units (None if not placed) */
+    pub centers: Vec<Option<(f32, f32)>>,
+}
+
+
+impl Canvas {
+    /*
+     * Place each keyboard's top-left corner at the respective position (in
+     * keyboard units)
+     */
+    pub fn new(keyboards: Vec<Keyboard>, positions: &[(f32, f32)]) -> Self {
+        assert!(keyboards.len() == positions.len());
+
+        let mut bases = Vec::with_capacity(keyboards.len());
+        let mut led_count = 0;
+        for kbd in &keyboards {
+            bases.push(led_count);
+            led_count += kbd.led_count;
+        }
+
+        let width = keyboards.iter().zip(positions)
+            .map(|(kbd, pos)| pos.0 + kbd.width)
+            .fold(0.0, f32::max);
+        let height = keyboards.iter().zip(positions)
+            .map(|(kbd, pos)| pos.1 + kbd.height)
+            .fold(0.0, f32::max);
+
+        let mut centers = Vec::with_capacity(led_count);
+        for (kbd, pos) in keyboards.iter().zip(positions) {
+            for led in 0..kbd.led_count {
+                centers.push(kbd.led_position(led).map(|p| {
+                    let (x, y) = kbd.denormalize(p);
+                    (pos.0 + x, pos.1 + y)
+                }));
+            }
+        }
+
+        Canvas {
+            keyboards,
+            bases,
+
+            width,
+            height,
+            led_count,
+
+            centers,
+        }
+    }
+
+    /* Place the keyboards next to each other, from left to right */
+    pub fn side_by_side(keyboards: Vec<Keyboard>) -> Self {
+        let mut positions = Vec::with_capacity(keyboards.len());
+        let mut x = 0.0;
+        for kbd in &keyboards {
+            positions.push((x, 0.0));
+            x += kbd.width;
+        }
+
+        Self::new(keyboards, &positions)
+    }
+
+    /* Canvas index of the given keyboard's LED */
+    pub fn led(&self, kbd_i: usize, led: usize) -> usize {
+        self.bases[kbd_i] + led
+    }
+
+    pub fn software_effect_start(&mut self) {
+        for kbd in &mut self.keyboards {
+            kbd.software_effect_start();
+        }
+    }
+
+    /* Tries to restore all keyboards, even if one fails */
+    pub fn software_effect_end(&mut self) -> Result<(), String> {
+        let mut res = Ok(());
+        for kbd in &mut self.keyboards {
+            res = res.and(kbd.software_effect_end());
+        }
+        res
+    }
+
+    pub fn set_profile(&mut self, profile: u8) -> Result<(), String> {
+        for kbd in &mut self.keyboards {
+            kbd.set_profile(profile)?;
+        }
+        Ok(())
+    }
+
+    pub fn refresh_profile(&self) -> Result<(), String> {
+        for kbd in &self.keyboards {
+            kbd.refresh_profile()?;
+        }
+        Ok(())
+    }
+
+    /* See Keyboard::set_zone() */
+    pub fn set_zone(&mut self, name: Option<&str>) -> Result<(), String> {
+        for kbd in &mut self.keyboards {
+            kbd.set_zone(name)?;
+        }
+        Ok(())
+    }
+
+    pub fn zoned(&self) -> bool {
+        self.keyboards.iter().any(|kbd| kbd.zoned())
+    }
+
+    /* Whether any of the keyboards has been lost */
+    pub fn lost(&self) -> bool {
+        self.keyboards.iter().any(|kbd| kbd.lost())
+    }
+
+    /* Takes led_count RGB triplets and distributes them to the keyboards */
+    pub fn all_keys_raw(&self, raw_keys: &[u8]) -> Result<(), String> {
+        for (kbd, base) in self.keyboards.iter().zip(&self.bases) {
+            let end = base + kbd.led_count;
+            kbd.all_keys_raw(&raw_keys[(base * 3)..(end * 3)])?;
+        }
+        Ok(())
+    }
+
+    /* Keep the keyboards' preview alive (see heartbeat.rs) */
+    pub fn keep_alive(&self) -> Result<(), String> {
+        for kbd in &self.keyboards {
+            kbd.keep_alive()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * What the keyboards' firmware supports for each hardware effect, so that
+ * unsupported parameters can be refused (or replaced) before anything is
+ * written to a profile.
+ */
+
+use crate::types::Direction;
+
+
+/* Color modes (see doc.txt) */
+pub const MODE_COLOR: u8 = 0;
+pub const MODE_RAINBOW: u8 = 1;
+pub const MODE_RANDOMIZED: u8 = 2;
+pub const MODE_GRADIENT: u8 = 3;
+
+pub struct EffectCaps {
+    /* Effect name, as given on the command line */
+    pub name: &'static str,
+    /* Supported color modes */
+    pub modes: &'static [u8],
+    /* Unsupported modes that are replaced by a similar supported one */
+    pub substitutes: &'static [(u8, u8)],
+    /*
+     * Speeds that are known to work (others may or may not); None if the
+     * effect takes no speed
+     */
+    pub speeds: Option<(u8, u8)>,
+    pub directions: &'static [Direction],
+}
+
+pub struct ModelCaps {
+    /* Model identifier, as in layout files (see layout.rs) */
+    pub model: &'static str,
+    pub effects: &'static [EffectCaps],
+}
+
+
+const ALL_DIRECTIONS: [Direction; 4] =
+    [Direction::Right, Direction::Left, Direction::Down, Direction::Up];
+
+/* Both the miniSTREAK and the STREAK run the same firmware */
+static STREAK_EFFECTS: [EffectCaps; 8] = [
+    EffectCaps {
+        name: "all-keys",
+        /* Per-key colors are drawn key by key by leddy */
+        modes: &[MODE_COLOR, MODE_RAINBOW, MODE_RANDOMIZED, MODE_GRADIENT],
+        substitutes: &[],
+        speeds: None,
+        directions: &[],
+    },
+    EffectCaps {
+        name: "pulse",
+        modes: &[MODE_COLOR, MODE_RAINBOW, MODE_RANDOMIZED],
+        substitutes: &[],
+        speeds: Some((0, 100)),
+        directions: &[],
+    },
+    EffectCaps {
+        name: "wave",
+        modes: &[MODE_COLOR, MODE_RAINBOW, MODE_RANDOMIZED],
+        substitutes: &[],
+        speeds: Some((0, 100)),
+        directions: &ALL_DIRECTIONS,
+    },
+    EffectCaps {
+        name: "reactive",
+        modes: &[MODE_COLOR, MODE_RAINBOW, MODE_RANDOMIZED],
+        substitutes: &[],
+        speeds: Some((0, 100)),
+        directions: &[],
+    },
+    EffectCaps {
+        name: "reactive-ripple",
+        modes: &[MODE_COLOR, MODE_RAINBOW, MODE_RANDOMIZED],
+        substitutes: &[],
+        speeds: Some((0, 100)),
+        directions: &[],
+    },
+    EffectCaps {
+        name: "rain",
+        modes: &[MODE_COLOR, MODE_RANDOMIZED],
+        substitutes: &[(MODE_RAINBOW, MODE_RANDOMIZED)],
+        speeds: Some((0, 100)),
+        directions: &ALL_DIRECTIONS,
+    },
+    EffectCaps {
+        name: "gradient",
+        modes: &[MODE_COLOR, MODE_RAINBOW, MODE_RANDOMIZED, MODE_GRADIENT],
+        substitutes: &[],
+        speeds: None,
+        /* Vertical gradients are drawn key by key by leddy */
+        directions: &ALL_DIRECTIONS,
+    },
+    EffectCaps {
+        name: "fade",
+        modes: &[MODE_COLOR, MODE_RAINBOW, MODE_RANDOMIZED, MODE_GRADIENT],
+        substitutes: &[],
+        speeds: Some((0, 100)),
+        directions: &[],
+    },
+];
+
+/* Used for models that are not listed here (e.g. from user layout files) */
+static DEFAULT: ModelCaps = ModelCaps {
+    model: "",
+    effects: &STREAK_EFFECTS,
+};
+
+static MODELS: [ModelCaps; 2] = [
+    ModelCaps {
+        model: "ministreak",
+        effects: &STREAK_EFFECTS,
+    },
+    ModelCaps {
+        model: "streak",
+        effects: &STREAK_EFFECTS,
+    },
+];
+
+
+fn mode_name(mode: u8) -> &'static str {
+    match mode {
+        MODE_COLOR => "a single color",
+        MODE_RAINBOW => "rainbow",
+        MODE_RANDOMIZED => "randomized",
+        MODE_GRADIENT => "gradients",
+        _ => "this color mode",
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Right => "right",
+        Direction::Left => "left",
+        Direction::Down => "down",
+        Direction::Up => "up",
+    }
+}
+
+
+/* Capabilities of the model with the given identifier (e.g. “streak”) */
+pub fn model(model: &str) -> &'static ModelCaps {
+    MODELS.iter().find(|m| m.model == model).unwrap_or(&DEFAULT)
+}
+
+
+impl ModelCaps {
+    /* None for effects the firmware does not draw itself */
+    pub fn effect(&self, name: &str) -> Option<&'static EffectCaps> {
+        self.effects.iter().find(|e| e.name == name)
+    }
+}
+
+
+impl EffectCaps {
+    /* The mode to actually send for @mode */
+    pub fn mode(&self, mode: u8) -> u8 {
+        match self.substitutes.iter().find(|(from, _)| *from == mode) {
+            Some((_, to)) => *to,
+            None => mode,
+        }
+    }
+
+    /*
+     * Check the given parameters (None for those not given), on the keyboard
+     * models called @model_names (e.g. “miniSTREAK and STREAK”).  Returns
+     * warnings for parameters that will be replaced or may not work, and an
+     * error for those that cannot work.
+     */
+    pub fn check(&self, model_names: &str, mode: Option<u8>,
+                 speed: Option<u8>, direction: Option<Direction>)
+        -> Result<Vec<String>, String>
+    {
+        let mut warnings = Vec::new();
+
+        if let Some(mode) = mode {
+            if !self.modes.contains(&mode) {
+                let substitute = self.mode(mode);
+                if substitute == mode {
+                    return Err(format!("“{}” does not support {} on the {}",
+                                       self.name, mode_name(mode),
+                                       model_names));
+                }
+
+                warnings.push(format!("“{}” does not support {} on the {}, \
+                                       using {} instead",
+                                      self.name, mode_name(mode), model_names,
+                                      mode_name(substitute)));
+            }
+        }
+
+        match (speed, self.speeds) {
+            (None, _) => (),
+
+            (Some(_), None) =>
+                return Err(format!("“{}” does not take a speed", self.name)),
+
+            (Some(speed), Some((min, max))) => {
+                if speed < min || speed > max {
+                    warnings.push(format!("Speed {} for “{}” is outside of \
+                                           the known working range ({}..{}) \
+                                           on the {}",
+                                          speed, self.name, min, max,
+                                          model_names));
+                }
+            }
+        }
+
+        if let Some(direction) = direction {
+            if !self.directions.iter().any(|d| *d as u8 == direction as u8) {
+                return Err(format!("“{}” does not support direction “{}” \
+                                    on the {}",
+                                   self.name, direction_name(direction),
+                                   model_names));
+            }
+        }
+
+        Ok(warnings)
+    }
+}
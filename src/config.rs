@@ -0,0 +1,132 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * System-wide defaults for some global switches.  The configuration file is
+ * line-based; empty lines and lines starting with # are ignored, all others
+ * are “<option> <value>”:
+ *
+ *   layout <name>          Default for --layout (e.g. “iso”)
+ *   labels <name>          Default for --labels (e.g. “de”)
+ *   zone <name> <keys>     Defines a zone (see zones.rs), e.g.
+ *                          “zone gaming wasd,q,e,r,f,lshift,space”
+ */
+
+use std::fs;
+use std::io::ErrorKind;
+
+
+pub const CONFIG_FILE: &str = "/etc/leddy/config";
+
+
+#[derive(Default)]
+pub struct Config {
+    pub layout: Option<String>,
+    pub labels: Option<String>,
+    /* (name, key list) */
+    pub zones: Vec<(String, String)>,
+}
+
+
+impl Config {
+    /* Parse a configuration file's content; @source is used in errors */
+    pub fn parse(source: &str, text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+
+            if words[0] == "zone" {
+                if words.len() != 3 {
+                    return Err(format!("{}:{}: Expected “zone <name> <keys>”",
+                                       source, i + 1));
+                }
+
+                config.zones.push((String::from(words[1]),
+                                   String::from(words[2])));
+                continue;
+            }
+
+            if words.len() != 2 {
+                return Err(format!("{}:{}: Expected “<option> <value>”",
+                                   source, i + 1));
+            }
+
+            let value = Some(String::from(words[1]));
+            match words[0] {
+                "layout" => config.layout = value,
+                "labels" => config.labels = value,
+
+                x => return Err(format!("{}:{}: Unknown option “{}”",
+                                        source, i + 1, x)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+
+/* Load CONFIG_FILE (if it exists) */
+pub fn load() -> Result<Config, String> {
+    match fs::read_to_string(CONFIG_FILE) {
+        Ok(text) => Config::parse(CONFIG_FILE, &text),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(format!("Failed to read {}: {}", CONFIG_FILE, e)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        match Config::parse("test", text) {
+            Ok(_) => panic!("“{}” parsed", text),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let config = Config::parse("test", "# comment\n\
+                                            \n\
+                                            layout iso\n\
+                                            labels de\n\
+                                            zone gaming wasd,q,e\n\
+                                            zone fkeys f1..f12\n\
+                                            labels fr\n").unwrap();
+
+        assert_eq!(config.layout.as_deref(), Some("iso"));
+        /* The last one counts */
+        assert_eq!(config.labels.as_deref(), Some("fr"));
+        assert_eq!(config.zones,
+                   [(String::from("gaming"), String::from("wasd,q,e")),
+                    (String::from("fkeys"), String::from("f1..f12"))]);
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::parse("test", "# nothing\n").unwrap();
+        assert!(config.layout.is_none());
+        assert!(config.labels.is_none());
+        assert!(config.zones.is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("colour red\n"), "test:1: Unknown option “colour”");
+        assert_eq!(error("\nlayout\n"),
+                   "test:2: Expected “<option> <value>”");
+        assert_eq!(error("labels de fr\n"),
+                   "test:1: Expected “<option> <value>”");
+        assert_eq!(error("zone gaming\n"),
+                   "test:1: Expected “zone <name> <keys>”");
+        assert_eq!(error("zone gaming w, a\n"),
+                   "test:1: Expected “zone <name> <keys>”");
+    }
+}
@@ -0,0 +1,91 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::time::Duration;
+
+use crate::canvas::Canvas;
+use crate::keyboard::Keyboard;
+use crate::signals;
+
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/* HID paths of all STREAK keyboards currently connected */
+fn connected_devices() -> Vec<String> {
+    match Keyboard::list_devices() {
+        Ok(devs) => devs.iter().map(|d| d.path_str()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/*
+ * Open the keyboards selected by @device (waiting until they are connected)
+ * and apply the lighting, and do so again whenever the set of connected
+ * keyboards changes or writing to them fails.  Only returns on errors that
+ * are not caused by a keyboard being absent or lost (e.g. missing access
+ * rights or invalid effect parameters), or once a termination signal is
+ * received.
+ */
+pub fn run(device: Option<&str>,
+           open: impl Fn() -> Result<Canvas, String>,
+           apply: impl Fn(&mut Canvas) -> Result<(), String>)
+    -> Result<(), String>
+{
+    let mut last_error = None;
+
+    loop {
+        let devices = connected_devices();
+
+        let mut canvas =
+            match open() {
+                Ok(c) => c,
+                Err(e) => {
+                    /* The keyboards are there, so waiting will not help */
+                    if Keyboard::selected_present(device)? {
+                        return Err(e);
+                    }
+
+                    /* Do not repeat the same message every second */
+                    if last_error.as_ref() != Some(&e) {
+                        eprintln!("{}; waiting for keyboard", e);
+                        last_error = Some(e);
+                    }
+                    if signals::sleep(Some(POLL_INTERVAL)) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+
+        last_error = None;
+
+        /*
+         * Software effects only return once something went wrong or a
+         * termination signal has been received
+         */
+        let res = apply(&mut canvas);
+        if signals::received().is_some() {
+            return res;
+        }
+
+        if let Err(e) = res {
+            if !canvas.lost() {
+                return Err(e);
+            }
+
+            eprintln!("{}; waiting for keyboard", e);
+            if signals::sleep(Some(POLL_INTERVAL)) {
+                return Ok(());
+            }
+            continue;
+        }
+
+        /* The keyboards keep hardware effects by themselves */
+        while connected_devices() == devices {
+            if signals::sleep(Some(POLL_INTERVAL)) {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Turn captured USB traffic (e.g. from the official software) back into leddy
+ * command lines.
+ */
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::protocol::{Command, Effect, Reassembler};
+use crate::transport::{self, hex_bytes, Report, REPORT_SIZE};
+use crate::types::{Color, Direction, Gradient};
+
+
+struct Decoder {
+    reassembler: Reassembler,
+
+    /* Requests that leddy sends implicitly after the last one (13, 04) */
+    implied: Vec<Vec<u8>>,
+}
+
+
+fn rgb(color: Color) -> String {
+    format!("{:02x}{:02x}{:02x}", color.0, color.1, color.2)
+}
+
+/*
+ * The color parameter for @effect in the given mode; only fades and
+ * gradients (which carry a whole gradient) can be set in gradient mode
+ */
+fn color_param(effect: &str, mode: u8, color: Color)
+    -> Result<String, String>
+{
+    match mode {
+        0 => Ok(format!("rgb:{}", rgb(color))),
+        1 => Ok(String::from("rainbow")),
+        2 => Ok(String::from("randomized")),
+        3 => Err(format!("{} in gradient mode, with color {} (not supported \
+                          by leddy; sw-{} takes gradients instead)",
+                         effect, rgb(color), effect)),
+
+        x => Err(format!("Unknown color mode {:02x}", x)),
+    }
+}
+
+fn gradient_param(gradient: &Gradient) -> String {
+    let colors: Vec<String> =
+        gradient.colors.iter()
+            .map(|(color, pos)| format!("{}@{}", rgb(*color), pos))
+            .collect();
+
+    format!("gradient:{}", colors.join(","))
+}
+
+fn direction_param(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Right => "right",
+        Direction::Left => "left",
+        Direction::Down => "down",
+        Direction::Up => "up",
+    }
+}
+
+fn when_param(keydown: bool) -> &'static str {
+    if keydown {
+        "keydown"
+    } else {
+        "keyup"
+    }
+}
+
+/*
+ * Build the leddy command line that shows @effect, with the given global
+ * switches.  Returns an error for effects leddy cannot express.
+ */
+fn command_line(switches: &str, effect: &Effect) -> Result<String, String> {
+    let spec =
+        match effect {
+            Effect::AllKeys(colors) => {
+                let colors: Vec<String> =
+                    colors.iter().map(|c| rgb(*c)).collect();
+
+                return Ok(format!("printf '%s\\n' {} | leddy {} \
+                                   all-keys/color=stdin",
+                                  colors.join(" "), switches));
+            }
+
+            Effect::Pulse { mode, color, speed } =>
+                format!("pulse/color={}/speed={}",
+                        color_param("pulse", *mode, *color)?, speed),
+
+            Effect::Wave { mode, color, speed, direction } =>
+                format!("wave/color={}/speed={}/direction={}",
+                        color_param("wave", *mode, *color)?, speed,
+                        direction_param(*direction)),
+
+            Effect::Reactive { mode, color, speed, keydown } =>
+                format!("reactive/color={}/speed={}/{}",
+                        color_param("reactive", *mode, *color)?, speed,
+                        when_param(*keydown)),
+
+            Effect::Ripple { mode, color, speed, keydown } =>
+                format!("reactive-ripple/color={}/speed={}/{}",
+                        color_param("reactive-ripple", *mode, *color)?,
+                        speed,
+                        when_param(*keydown)),
+
+            Effect::Rain { mode, color, speed, direction } => {
+                /*
+                 * leddy sends rainbow rain as randomized, only the software
+                 * version draws it
+                 */
+                let name = if *mode == 1 { "sw-rain" } else { "rain" };
+
+                format!("{}/color={}/speed={}/direction={}",
+                        name, color_param("rain", *mode, *color)?, speed,
+                        direction_param(*direction))
+            }
+
+            Effect::Gradient(gradient) =>
+                format!("gradient/color={}", gradient_param(gradient)),
+
+            Effect::Fade { mode, gradient, speed } => {
+                let cp =
+                    match mode {
+                        3 => gradient_param(gradient),
+                        _ => color_param("fade", *mode,
+                                         gradient.colors[0].0)?,
+                    };
+
+                format!("fade/color={}/speed={}", cp, speed)
+            }
+
+            Effect::Unknown(id, _) =>
+                return Err(format!("Unknown effect {:02x}", id)),
+        };
+
+    Ok(format!("leddy {} {}", switches, spec))
+}
+
+fn describe(cmd: &Command) -> Result<String, String> {
+    match cmd {
+        Command::SwitchProfile(profile) => Ok(format!("leddy -p={}", profile)),
+        Command::KeepAlive => Ok(String::from("# 07: Keep-alive")),
+        Command::KeepAliveFe => Ok(String::from("# fe: Keep-alive")),
+        Command::Save => Ok(String::from("# 13: Save changes to profile")),
+
+        Command::SetProfileEffect(profile, effect) =>
+            command_line(&format!("-p={}", profile), effect),
+
+        Command::Preview(effect) => command_line("--preview", effect),
+
+        Command::Unknown(req) => Err(format!("Unknown command {:02x}", req[0])),
+    }
+}
+
+
+/*
+ * Parse a line from a usbmon text dump (see Documentation/usb/usbmon.rst in
+ * the kernel).  Only output reports (interrupt OUT and SET_REPORT) are taken
+ * into account.  Returns the report and how many of its bytes were captured.
+ */
+fn parse_usbmon(line: &str) -> Result<Option<(Report, usize)>, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+
+    /* <tag> <timestamp> <event> <address> ... */
+    if fields.len() < 4 || fields[2] != "S" {
+        return Ok(None);
+    }
+
+    let address = fields[3];
+    if address.starts_with("Co:") {
+        /* Setup packet: s <bmRequestType> <bRequest> ...; want SET_REPORT */
+        if fields.get(4) != Some(&"s") || fields.get(5) != Some(&"21") ||
+           fields.get(6) != Some(&"09")
+        {
+            return Ok(None);
+        }
+    } else if !address.starts_with("Io:") {
+        return Ok(None);
+    }
+
+    /* ... <length> = <data words> */
+    let data_i =
+        match fields.iter().position(|f| *f == "=") {
+            Some(i) if i >= 4 => i,
+            Some(_) => return Err(String::from("Data (“=”) before the \
+                                                address")),
+            None => return Ok(None),
+        };
+
+    let length =
+        match fields[data_i - 1].parse::<usize>() {
+            Ok(x) => x,
+            Err(_) => return Err(format!("Invalid data length “{}”",
+                                         fields[data_i - 1])),
+        };
+
+    /* The report ID (0) is not transferred */
+    let start =
+        match length {
+            x if x == REPORT_SIZE - 1 => 1,
+            x if x == REPORT_SIZE => 0,
+            _ => return Ok(None),
+        };
+
+    let hex: String = fields[(data_i + 1)..].concat();
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) ||
+       hex.len() / 2 > length
+    {
+        return Err(format!("Invalid data words “{}”",
+                           fields[(data_i + 1)..].join(" ")));
+    }
+
+    let mut report = [0u8; REPORT_SIZE];
+    for i in 0..(hex.len() / 2) {
+        report[start + i] =
+            match u8::from_str_radix(&hex[(i * 2)..(i * 2 + 2)], 16) {
+                Ok(x) => x,
+                Err(_) => return Err(format!("Invalid hex byte “{}”",
+                                             &hex[(i * 2)..(i * 2 + 2)])),
+            };
+    }
+
+    Ok(Some((report, start + hex.len() / 2)))
+}
+
+fn parse_line(line: &str) -> Result<Option<(Report, usize)>, String> {
+    /* usbmon lines have an event type (S, C, or E) as their third field */
+    let is_usbmon =
+        line.split_whitespace().nth(2)
+            .is_some_and(|e| e == "S" || e == "C" || e == "E");
+
+    if is_usbmon {
+        parse_usbmon(line)
+    } else {
+        Ok(transport::parse_report(line)?.map(|r| (r, REPORT_SIZE)))
+    }
+}
+
+
+impl Decoder {
+    fn new() -> Self {
+        Decoder {
+            reassembler: Reassembler::default(),
+            implied: Vec::new(),
+        }
+    }
+
+    fn print_request(&mut self, req: &[u8]) {
+        if self.implied.first().is_some_and(|r| r == req) {
+            self.implied.remove(0);
+            return;
+        }
+        self.implied.clear();
+
+        match Command::decode(req) {
+            Ok(cmd) => {
+                if let Command::SetProfileEffect(profile, _) = cmd {
+                    self.implied = vec![vec![0x13], vec![0x04, profile]];
+                }
+
+                match describe(&cmd) {
+                    Ok(line) => println!("{}", line),
+                    Err(e) => println!("# {}: {}", e, hex_bytes(req)),
+                }
+            }
+
+            Err(e) => println!("# Malformed request ({}): {}",
+                               e, hex_bytes(req)),
+        }
+    }
+
+    /* @captured tells how many bytes of @report are known */
+    fn push(&mut self, report: &Report, captured: usize)
+        -> Result<(), String>
+    {
+        let len = report[2] as usize
+                | (report[3] as usize) << 8
+                | (report[4] as usize) << 16;
+        let ofs = report[5] as usize
+                | (report[6] as usize) << 8
+                | (report[7] as usize) << 16;
+        let needed = 8 + len.saturating_sub(ofs).min(REPORT_SIZE - 8);
+
+        if captured < needed {
+            self.reassembler = Reassembler::default();
+            return Err(format!("Only {} of {} bytes captured, dropping request \
+                                {:02x} (usbmon’s text interface captures only \
+                                32 bytes per transfer; use a binary capture \
+                                instead)",
+                               captured, needed, report[1]));
+        }
+
+        if let Some(req) = self.reassembler.push(report)? {
+            self.print_request(&req);
+        }
+
+        Ok(())
+    }
+}
+
+
+/*
+ * Decode a usbmon text dump or a file with one report per line (as written
+ * by --record) from @path (stdin if None or “-”).
+ */
+pub fn run(path: Option<&str>) -> Result<(), String> {
+    let (name, input): (&str, Box<dyn BufRead>) =
+        match path {
+            None | Some("-") =>
+                ("<stdin>", Box::new(std::io::stdin().lock())),
+
+            Some(p) => match File::open(p) {
+                Ok(f) => (p, Box::new(BufReader::new(f))),
+                Err(e) => return Err(format!("Failed to open {}: {}", p, e)),
+            },
+        };
+
+    let mut decoder = Decoder::new();
+
+    for (i, line_opt) in input.lines().enumerate() {
+        let line =
+            match line_opt {
+                Ok(l) => l,
+                Err(e) => return Err(format!("Read error: {}", e)),
+            };
+
+        let res =
+            parse_line(&line).and_then(|r| match r {
+                Some((report, captured)) => decoder.push(&report, captured),
+                None => Ok(()),
+            });
+
+        /* Keep going, there may be more to learn from the rest */
+        if let Err(e) = res {
+            eprintln!("{}:{}: {}", name, i + 1, e);
+        }
+    }
+
+    if decoder.reassembler.pending() {
+        eprintln!("{}: Incomplete request at the end", name);
+    }
+
+    Ok(())
+}
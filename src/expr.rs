@@ -0,0 +1,630 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Math expressions for the expr effect, e.g. “sin(x*3+t)*0.5+0.5”.
+ *
+ * Values are numbers or colors (red, green, and blue, each nominally in
+ * [0, 1]); arithmetic on colors works per channel, with numbers applying to
+ * all channels.  Grammar (from the lowest precedence up):
+ *
+ *   compare    := sum [('<' | '>') sum]          (1 if true, 0 if not)
+ *   sum        := product {('+' | '-') product}
+ *   product    := unary {'*' unary}
+ *   unary      := '-' unary | power
+ *   power      := atom ['^' unary]
+ *   atom       := number | variable | function '(' compare {',' compare} ')'
+ *               | '(' compare ')'
+ *
+ * There is no “/”, because it separates effect parameters; div(a, b) divides
+ * instead.
+ */
+
+use std::f32::consts::PI;
+
+use crate::types::hsv_to_rgb;
+
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Color,
+}
+
+#[derive(Clone, Copy)]
+pub enum Value {
+    Number(f32),
+    Color(f32, f32, f32),
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Pow,
+    Less,
+    Greater,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Func {
+    Sin,
+    Cos,
+    Abs,
+    Sqrt,
+    Exp,
+    Floor,
+    Fract,
+    Min,
+    Max,
+    Mod,
+    Div,
+    Clamp,
+    Mix,
+    Noise,
+    Hsv,
+    Rgb,
+}
+
+enum Node {
+    Constant(Value),
+    /* Index into the variables given to Expr::parse() */
+    Variable(usize),
+    Neg(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+pub struct Expr {
+    root: Node,
+    kind: Kind,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    variables: &'a [&'a str],
+}
+
+
+/* Name, function, minimum and maximum number of arguments */
+const FUNCTIONS: [(&str, Func, usize, usize); 16] = [
+    ("sin",     Func::Sin,      1, 1),
+    ("cos",     Func::Cos,      1, 1),
+    ("abs",     Func::Abs,      1, 1),
+    ("sqrt",    Func::Sqrt,     1, 1),
+    ("exp",     Func::Exp,      1, 1),
+    ("floor",   Func::Floor,    1, 1),
+    ("fract",   Func::Fract,    1, 1),
+    ("min",     Func::Min,      2, 2),
+    ("max",     Func::Max,      2, 2),
+    ("mod",     Func::Mod,      2, 2),
+    ("div",     Func::Div,      2, 2),
+    ("clamp",   Func::Clamp,    1, 3),
+    ("mix",     Func::Mix,      3, 3),
+    ("noise",   Func::Noise,    1, 3),
+    ("hsv",     Func::Hsv,      3, 3),
+    ("rgb",     Func::Rgb,      3, 3),
+];
+
+
+/* Pseudo-random value in [0, 1] for the given lattice point */
+fn lattice(x: i32, y: i32, z: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6b343) ^
+                (y as u32).wrapping_mul(0xd8163841) ^
+                (z as u32).wrapping_mul(0xcb1ab31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1e995);
+    h ^= h >> 15;
+
+    (h & 0xffffff) as f32 / 0xffffff as f32
+}
+
+/* Smooth value noise in [0, 1] */
+fn noise(x: f32, y: f32, z: f32) -> f32 {
+    let (xi, yi, zi) = (x.floor() as i32, y.floor() as i32, z.floor() as i32);
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let (u, v, w) = (smooth(x - x.floor()),
+                     smooth(y - y.floor()),
+                     smooth(z - z.floor()));
+    let mix = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+    let plane = |z: i32| {
+        mix(mix(lattice(xi, yi, z), lattice(xi + 1, yi, z), u),
+            mix(lattice(xi, yi + 1, z), lattice(xi + 1, yi + 1, z), u),
+            v)
+    };
+
+    mix(plane(zi), plane(zi + 1), w)
+}
+
+
+impl Value {
+    /* Apply @f per channel */
+    fn map(self, f: impl Fn(f32) -> f32) -> Value {
+        match self {
+            Value::Number(a) => Value::Number(f(a)),
+            Value::Color(r, g, b) => Value::Color(f(r), f(g), f(b)),
+        }
+    }
+
+    /* Apply @f per channel; numbers are used for all channels */
+    fn zip(self, other: Value, f: impl Fn(f32, f32) -> f32) -> Value {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(f(a, b)),
+            (a, b) => {
+                let (a, b) = (a.channels(), b.channels());
+                Value::Color(f(a.0, b.0), f(a.1, b.1), f(a.2, b.2))
+            }
+        }
+    }
+
+    fn channels(self) -> (f32, f32, f32) {
+        match self {
+            Value::Number(a) => (a, a, a),
+            Value::Color(r, g, b) => (r, g, b),
+        }
+    }
+
+    /* Only used on values that are numbers (see Parser::call()) */
+    fn number(self) -> f32 {
+        match self {
+            Value::Number(a) => a,
+            Value::Color(r, _, _) => r,
+        }
+    }
+}
+
+impl Node {
+    fn eval(&self, variables: &[f32]) -> Value {
+        match self {
+            Node::Constant(value) => *value,
+            Node::Variable(i) => Value::Number(variables[*i]),
+            Node::Neg(a) => a.eval(variables).map(|a| -a),
+
+            Node::Binary(op, a, b) => {
+                let (a, b) = (a.eval(variables), b.eval(variables));
+                match op {
+                    Op::Add => a.zip(b, |a, b| a + b),
+                    Op::Sub => a.zip(b, |a, b| a - b),
+                    Op::Mul => a.zip(b, |a, b| a * b),
+                    Op::Pow => a.zip(b, f32::powf),
+                    Op::Less => a.zip(b, |a, b| (a < b) as u8 as f32),
+                    Op::Greater => a.zip(b, |a, b| (a > b) as u8 as f32),
+                }
+            }
+
+            Node::Call(func, args) => {
+                let args: Vec<Value> =
+                    args.iter().map(|a| a.eval(variables)).collect();
+                let arg = |i: usize| args[i];
+                let num = |i: usize| args.get(i).map(|a| a.number());
+
+                match func {
+                    Func::Sin => arg(0).map(f32::sin),
+                    Func::Cos => arg(0).map(f32::cos),
+                    Func::Abs => arg(0).map(f32::abs),
+                    Func::Sqrt => arg(0).map(f32::sqrt),
+                    Func::Exp => arg(0).map(f32::exp),
+                    Func::Floor => arg(0).map(f32::floor),
+                    Func::Fract => arg(0).map(|a| a - a.floor()),
+                    Func::Min => arg(0).zip(arg(1), f32::min),
+                    Func::Max => arg(0).zip(arg(1), f32::max),
+                    Func::Mod => arg(0).zip(arg(1), f32::rem_euclid),
+                    Func::Div => arg(0).zip(arg(1), |a, b| a / b),
+
+                    Func::Clamp => {
+                        let lo = args.get(1).copied()
+                                     .unwrap_or(Value::Number(0.0));
+                        let hi = args.get(2).copied()
+                                     .unwrap_or(Value::Number(1.0));
+                        arg(0).zip(lo, f32::max).zip(hi, f32::min)
+                    }
+
+                    Func::Mix => {
+                        let diff = arg(1).zip(arg(0), |b, a| b - a);
+                        arg(0).zip(diff.zip(arg(2), |d, t| d * t),
+                                   |a, d| a + d)
+                    }
+
+                    Func::Noise =>
+                        Value::Number(noise(num(0).unwrap_or(0.0),
+                                            num(1).unwrap_or(0.0),
+                                            num(2).unwrap_or(0.0))),
+
+                    Func::Hsv => {
+                        let (r, g, b) =
+                            hsv_to_rgb(arg(0).number().rem_euclid(1.0),
+                                       arg(1).number().clamp(0.0, 1.0),
+                                       arg(2).number().clamp(0.0, 1.0));
+                        Value::Color(r, g, b)
+                    }
+
+                    Func::Rgb =>
+                        Value::Color(arg(0).number(), arg(1).number(),
+                                     arg(2).number()),
+                }
+            }
+        }
+    }
+
+    fn uses(&self, variable: usize) -> bool {
+        match self {
+            Node::Constant(_) => false,
+            Node::Variable(i) => *i == variable,
+            Node::Neg(a) => a.uses(variable),
+            Node::Binary(_, a, b) => a.uses(variable) || b.uses(variable),
+            Node::Call(_, args) => args.iter().any(|a| a.uses(variable)),
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at position {} of “{}”", msg, self.pos + 1, self.text)
+    }
+
+    fn skip_space(&mut self) {
+        while self.text[self.pos..].starts_with(' ') {
+            self.pos += 1;
+        }
+    }
+
+    /* Consume @token if it comes next */
+    fn eat(&mut self, token: char) -> bool {
+        self.skip_space();
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    /* Consume the longest prefix whose characters satisfy @f */
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = &self.text[self.pos..];
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn compare(&mut self) -> Result<(Node, Kind), String> {
+        let (a, a_kind) = self.sum()?;
+
+        let op =
+            if self.eat('<') {
+                Op::Less
+            } else if self.eat('>') {
+                Op::Greater
+            } else {
+                return Ok((a, a_kind));
+            };
+
+        let (b, b_kind) = self.sum()?;
+        Ok(binary(op, a, a_kind, b, b_kind))
+    }
+
+    fn sum(&mut self) -> Result<(Node, Kind), String> {
+        let (mut a, mut a_kind) = self.product()?;
+
+        loop {
+            let op =
+                if self.eat('+') {
+                    Op::Add
+                } else if self.eat('-') {
+                    Op::Sub
+                } else {
+                    return Ok((a, a_kind));
+                };
+
+            let (b, b_kind) = self.product()?;
+            (a, a_kind) = binary(op, a, a_kind, b, b_kind);
+        }
+    }
+
+    fn product(&mut self) -> Result<(Node, Kind), String> {
+        let (mut a, mut a_kind) = self.unary()?;
+
+        while self.eat('*') {
+            let (b, b_kind) = self.unary()?;
+            (a, a_kind) = binary(Op::Mul, a, a_kind, b, b_kind);
+        }
+
+        Ok((a, a_kind))
+    }
+
+    fn unary(&mut self) -> Result<(Node, Kind), String> {
+        if self.eat('-') {
+            let (a, kind) = self.unary()?;
+            Ok((Node::Neg(Box::new(a)), kind))
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<(Node, Kind), String> {
+        let (a, a_kind) = self.atom()?;
+
+        if self.eat('^') {
+            let (b, b_kind) = self.unary()?;
+            Ok(binary(Op::Pow, a, a_kind, b, b_kind))
+        } else {
+            Ok((a, a_kind))
+        }
+    }
+
+    fn atom(&mut self) -> Result<(Node, Kind), String> {
+        self.skip_space();
+
+        if self.eat('(') {
+            let inner = self.compare()?;
+            if !self.eat(')') {
+                return Err(self.error("Expected “)”"));
+            }
+            return Ok(inner);
+        }
+
+        let start = self.pos;
+
+        let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+        if !number.is_empty() {
+            return match number.parse::<f32>() {
+                Ok(x) => Ok((Node::Constant(Value::Number(x)), Kind::Number)),
+                Err(_) => {
+                    self.pos = start;
+                    Err(self.error(&format!("Invalid number “{}”", number)))
+                }
+            };
+        }
+
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        if name.is_empty() {
+            return Err(self.error(if self.pos == self.text.len() {
+                                      "Unexpected end"
+                                  } else {
+                                      "Unexpected character"
+                                  }));
+        }
+
+        if self.eat('(') {
+            return self.call(name, start);
+        }
+
+        if let Some(i) = self.variables.iter().position(|v| *v == name) {
+            Ok((Node::Variable(i), Kind::Number))
+        } else if name == "pi" {
+            Ok((Node::Constant(Value::Number(PI)), Kind::Number))
+        } else {
+            self.pos = start;
+            Err(self.error(&format!("Unknown variable “{}” (available: {}, \
+                                     pi)",
+                                    name, self.variables.join(", "))))
+        }
+    }
+
+    /* Arguments of the function @name (starting at @start), after the “(” */
+    fn call(&mut self, name: &str, start: usize)
+        -> Result<(Node, Kind), String>
+    {
+        let Some(&(_, func, min_args, max_args)) =
+            FUNCTIONS.iter().find(|f| f.0 == name)
+        else {
+            self.pos = start;
+            let names: Vec<&str> = FUNCTIONS.iter().map(|f| f.0).collect();
+            return Err(self.error(&format!("Unknown function “{}” \
+                                            (available: {})",
+                                           name, names.join(", "))));
+        };
+
+        let mut args = Vec::new();
+        let mut kind = Kind::Number;
+
+        if !self.eat(')') {
+            loop {
+                let (arg, arg_kind) = self.compare()?;
+
+                if arg_kind == Kind::Color &&
+                   matches!(func, Func::Noise | Func::Hsv | Func::Rgb)
+                {
+                    return Err(self.error(&format!("{}() takes only \
+                                                    numbers",
+                                                   name)));
+                }
+
+                if arg_kind == Kind::Color {
+                    kind = Kind::Color;
+                }
+                args.push(arg);
+
+                if self.eat(')') {
+                    break;
+                }
+                if !self.eat(',') {
+                    return Err(self.error("Expected “,” or “)”"));
+                }
+            }
+        }
+
+        if args.len() < min_args || args.len() > max_args {
+            self.pos = start;
+            let count =
+                if min_args == max_args {
+                    format!("{}", min_args)
+                } else {
+                    format!("{} to {}", min_args, max_args)
+                };
+            return Err(self.error(&format!("{}() takes {} argument(s)",
+                                           name, count)));
+        }
+
+        if matches!(func, Func::Hsv | Func::Rgb) {
+            kind = Kind::Color;
+        } else if func == Func::Noise {
+            kind = Kind::Number;
+        }
+
+        Ok((Node::Call(func, args), kind))
+    }
+}
+
+fn binary(op: Op, a: Node, a_kind: Kind, b: Node, b_kind: Kind)
+    -> (Node, Kind)
+{
+    let kind =
+        if a_kind == Kind::Color || b_kind == Kind::Color {
+            Kind::Color
+        } else {
+            Kind::Number
+        };
+
+    (Node::Binary(op, Box::new(a), Box::new(b)), kind)
+}
+
+
+impl Expr {
+    /* Parse @text; it can refer to the given variables (by name) */
+    pub fn parse(text: &str, variables: &[&str]) -> Result<Expr, String> {
+        let mut parser = Parser {
+            text,
+            pos: 0,
+            variables,
+        };
+
+        let (root, kind) = parser.compare()?;
+
+        parser.skip_space();
+        if parser.pos < text.len() {
+            return Err(parser.error("Unexpected character"));
+        }
+
+        Ok(Expr {
+            root,
+            kind,
+        })
+    }
+
+    /*
+     * Evaluate the expression, given the variables' values (in the order in
+     * which their names were given to parse())
+     */
+    pub fn eval(&self, variables: &[f32]) -> Value {
+        self.root.eval(variables)
+    }
+
+    pub fn is_color(&self) -> bool {
+        self.kind == Kind::Color
+    }
+
+    /* Whether the expression refers to the given variable (by index) */
+    pub fn uses(&self, variable: usize) -> bool {
+        self.root.uses(variable)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn eval(text: &str, variables: &[(&str, f32)]) -> Value {
+        let names: Vec<&str> = variables.iter().map(|v| v.0).collect();
+        let values: Vec<f32> = variables.iter().map(|v| v.1).collect();
+        Expr::parse(text, &names).unwrap().eval(&values)
+    }
+
+    fn number(text: &str) -> f32 {
+        match eval(text, &[]) {
+            Value::Number(x) => x,
+            Value::Color(..) => panic!("“{}” is a color", text),
+        }
+    }
+
+    fn error(text: &str) -> String {
+        match Expr::parse(text, &["x", "t"]) {
+            Ok(_) => panic!("“{}” parsed", text),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(number("1+2*3"), 7.0);
+        assert_eq!(number("(1+2)*3"), 9.0);
+        assert_eq!(number("1-2-3"), -4.0);
+        assert_eq!(number("-2^2"), -4.0);
+        assert_eq!(number("2^-1"), 0.5);
+        assert_eq!(number("2^3^2"), 512.0);
+        assert_eq!(number("div(1, 4) * 2"), 0.5);
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(number("1 < 2"), 1.0);
+        assert_eq!(number("1 > 2"), 0.0);
+        assert_eq!(number("1+1 > 1*1"), 1.0);
+
+        match eval("x < 0.5", &[("x", 0.25)]) {
+            Value::Number(x) => assert_eq!(x, 1.0),
+            Value::Color(..) => panic!("Comparison is a color"),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("sin(1, 2)"),
+                   "sin() takes 1 argument(s) at position 1 of “sin(1, 2)”");
+        assert_eq!(error("clamp()"),
+                   "clamp() takes 1 to 3 argument(s) at position 1 of \
+                    “clamp()”");
+        assert!(error("1+foo(2)")
+                    .starts_with("Unknown function “foo” (available: sin, "));
+        assert!(error("1+foo(2)").ends_with(" at position 3 of “1+foo(2)”"));
+        assert_eq!(error("y*2"),
+                   "Unknown variable “y” (available: x, t, pi) at position 1 \
+                    of “y*2”");
+        assert_eq!(error("(1+2"), "Expected “)” at position 5 of “(1+2”");
+        assert_eq!(error("1+"), "Unexpected end at position 3 of “1+”");
+        assert_eq!(error("noise(rgb(1, 0, 0))"),
+                   "noise() takes only numbers at position 19 of \
+                    “noise(rgb(1, 0, 0))”");
+    }
+
+    #[test]
+    fn colors() {
+        let expr = Expr::parse("hsv(0, 1, 1)", &[]).unwrap();
+        assert!(expr.is_color());
+        match expr.eval(&[]) {
+            Value::Color(r, g, b) => assert_eq!((r, g, b), (1.0, 0.0, 0.0)),
+            Value::Number(_) => panic!("hsv() is a number"),
+        }
+
+        match eval("rgb(1, 0.5, 0) * 0.5 + x", &[("x", 0.25)]) {
+            Value::Color(r, g, b) => assert_eq!((r, g, b), (0.75, 0.5, 0.25)),
+            Value::Number(_) => panic!("rgb() is a number"),
+        }
+
+        assert!(!Expr::parse("noise(1, 2) + 1", &[]).unwrap().is_color());
+
+        let params = HashMap::from([("r", "hsv(0, 1, 1)")]);
+        match crate::software_effects::expr(params) {
+            Ok(_) => panic!("r= accepted a color"),
+            Err(e) => assert_eq!(e, "r= must be a number, not a color (use \
+                                     color= for colors)"),
+        }
+    }
+
+    #[test]
+    fn noise_range() {
+        for i in -200..200 {
+            let x = i as f32 * 0.37;
+            for v in [noise(x, 0.0, 0.0), noise(x, x * 0.5, -x),
+                      noise(1000.0 - x, x, x * 3.0)]
+            {
+                assert!((0.0..=1.0).contains(&v), "noise({}) = {}", x, v);
+            }
+        }
+    }
+}
@@ -0,0 +1,36 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::canvas::Canvas;
+
+
+/* The keyboard reverts a preview after a couple of seconds without this */
+pub const INTERVAL: Duration = Duration::from_millis(500);
+
+
+/*
+ * Run @f while keeping the keyboards' preview (0x0f) alive from a background
+ * thread.
+ */
+pub fn with_heartbeat<R>(canvas: &Canvas, f: impl FnOnce() -> R) -> R {
+    let stop = AtomicBool::new(false);
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            while !stop.load(Ordering::Relaxed) {
+                for kbd in &canvas.keyboards {
+                    /* If this fails, so will whatever @f sends */
+                    let _ = kbd.keep_alive();
+                }
+                std::thread::sleep(INTERVAL);
+            }
+        });
+
+        let result = f();
+        stop.store(true, Ordering::Relaxed);
+        result
+    })
+}
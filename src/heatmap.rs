@@ -0,0 +1,395 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Typing statistics for the heatmap effect: Key presses per LED, counted per
+ * hour (so that recent presses can be weighted higher than old ones), kept
+ * in $XDG_STATE_HOME/leddy/heatmap across runs.
+ *
+ * The file is line-based; empty lines and lines starting with # are ignored,
+ * all others are “<model> <LED index> <hour> <count>”, where <model> is the
+ * model identifier from the layout (see layout.rs) and <hour> counts the
+ * hours since the Unix epoch.  Counts older than KEEP_HOURS are merged into
+ * hour 0, so that the file does not grow forever; they still count fully
+ * when the heat does not decay.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::layout::{self, Layout};
+
+
+/* How long counts are kept per hour (30 days) */
+const KEEP_HOURS: u64 = 30 * 24;
+
+
+#[derive(Default)]
+pub struct Heatmap {
+    /* (model, LED index, hour) → presses */
+    counts: HashMap<(String, usize, u64), u64>,
+}
+
+
+/* Hours since the Unix epoch (fractional) */
+pub fn now_hours() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64() / 3600.0,
+        Err(_) => 0.0,
+    }
+}
+
+fn state_file() -> Result<PathBuf, String> {
+    let dir =
+        match std::env::var_os("XDG_STATE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".local/state"),
+                None => return Err(String::from("Cannot find the state \
+                                                 directory (set \
+                                                 $XDG_STATE_HOME)")),
+            },
+        };
+
+    Ok(dir.join("leddy").join("heatmap"))
+}
+
+fn parse_field<T: std::str::FromStr>(s: &str, what: &str)
+    -> Result<T, String>
+    where T::Err: std::fmt::Display
+{
+    match s.parse() {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!("Invalid {} “{}”: {}", what, s, e)),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+
+impl Heatmap {
+    /* Parse a statistics file's content; @source is used in errors */
+    pub fn parse(source: &str, text: &str) -> Result<Heatmap, String> {
+        let mut heatmap = Heatmap::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+
+            let res =
+                if words.len() == 4 {
+                    parse_field(words[1], "LED index").and_then(|led| {
+                        let hour = parse_field(words[2], "hour")?;
+                        let count = parse_field(words[3], "count")?;
+                        heatmap.add(words[0], led, hour, count);
+                        Ok(())
+                    })
+                } else {
+                    Err(String::from("Expected “<model> <LED index> <hour> \
+                                      <count>”"))
+                };
+
+            if let Err(e) = res {
+                return Err(format!("{}:{}: {}", source, i + 1, e));
+            }
+        }
+
+        Ok(heatmap)
+    }
+
+    /* Load the statistics file (empty statistics if there is none yet) */
+    pub fn load() -> Result<Heatmap, String> {
+        let path = state_file()?;
+
+        match fs::read_to_string(&path) {
+            Ok(text) => Heatmap::parse(&path.display().to_string(), &text),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Heatmap::default()),
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    /*
+     * Add the given presses to the statistics file (re-reading it first, so
+     * that presses counted by other leddy instances are kept).  Returns the
+     * file's new content.
+     */
+    pub fn save(&self) -> Result<Heatmap, String> {
+        let path = state_file()?;
+        let mut heatmap = Heatmap::load()?;
+        heatmap.merge(self);
+        heatmap.compact(now_hours() as u64);
+
+        let mut entries: Vec<_> = heatmap.counts.iter().collect();
+        entries.sort();
+
+        let mut text = String::from("# model  LED  hour  count\n");
+        for ((model, led, hour), count) in entries {
+            text += &format!("{} {} {} {}\n", model, led, hour, count);
+        }
+
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                return Err(format!("Failed to create {}: {}",
+                                   dir.display(), e));
+            }
+        }
+
+        /* Write a new file and move it over, so it is never half-written */
+        let tmp_path = path.with_extension("new");
+        if let Err(e) = fs::write(&tmp_path, text) {
+            return Err(format!("Failed to write {}: {}",
+                               tmp_path.display(), e));
+        }
+        if let Err(e) = fs::rename(&tmp_path, &path) {
+            return Err(format!("Failed to write {}: {}", path.display(), e));
+        }
+
+        Ok(heatmap)
+    }
+
+    pub fn add(&mut self, model: &str, led: usize, hour: u64, count: u64) {
+        *self.counts.entry((String::from(model), led, hour)).or_insert(0) +=
+            count;
+    }
+
+    pub fn merge(&mut self, other: &Heatmap) {
+        for ((model, led, hour), count) in &other.counts {
+            self.add(model, *led, *hour, *count);
+        }
+    }
+
+    /* Merge the counts older than KEEP_HOURS (before @now) into hour 0 */
+    pub fn compact(&mut self, now: u64) {
+        let old: Vec<_> =
+            self.counts.keys()
+                .filter(|(_, _, hour)| *hour > 0 && hour + KEEP_HOURS <= now)
+                .cloned()
+                .collect();
+
+        for key in old {
+            let count = self.counts.remove(&key).unwrap();
+            self.add(&key.0, key.1, 0, count);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /*
+     * Heat of every LED of the given model (by index), i.e. the number of
+     * presses.  With a half-life (in hours), presses count less the older
+     * they are (taking the middle of their hour as their time).
+     */
+    pub fn heat(&self, model: &str, half_life: Option<f64>)
+        -> HashMap<usize, f64>
+    {
+        let now = now_hours();
+        let mut heat = HashMap::new();
+
+        for ((m, led, hour), count) in &self.counts {
+            if m != model {
+                continue;
+            }
+
+            let weight =
+                match half_life {
+                    None => 1.0,
+                    Some(hl) => {
+                        let age = (now - (*hour as f64 + 0.5)).max(0.0);
+                        0.5f64.powf(age / hl)
+                    }
+                };
+
+            *heat.entry(*led).or_insert(0.0) += *count as f64 * weight;
+        }
+
+        heat
+    }
+
+    /* All-time presses per (model, LED index) */
+    fn totals(&self) -> BTreeMap<(&str, usize), u64> {
+        let mut totals = BTreeMap::new();
+        for ((model, led, _), count) in &self.counts {
+            *totals.entry((model.as_str(), *led)).or_insert(0) += count;
+        }
+        totals
+    }
+}
+
+
+/*
+ * The all-time presses per LED as “csv” or “json”, with the keys' names from
+ * the given layout (or each model's default one)
+ */
+fn export_text(heatmap: &Heatmap, layouts: &[Layout], format: &str,
+               layout_name: Option<&str>)
+    -> Result<String, String>
+{
+    let rows: Vec<(&str, usize, String, u64)> =
+        heatmap.totals().into_iter().map(|((model, led), presses)| {
+            let key =
+                layout::find_model(layouts, model, layout_name).ok()
+                    .and_then(|l| l.leds.iter().find(|l| l.index == led))
+                    .map(|l| l.key.clone())
+                    .unwrap_or_default();
+            (model, led, key, presses)
+        }).collect();
+
+    let mut text = String::new();
+    match format {
+        "csv" => {
+            text += "model,led,key,presses\n";
+            for (model, led, key, presses) in rows {
+                text += &format!("{},{},{},{}\n", model, led, key, presses);
+            }
+        }
+
+        "json" => {
+            text += "[\n";
+            for (i, (model, led, key, presses)) in rows.iter().enumerate() {
+                let sep = if i + 1 < rows.len() { "," } else { "" };
+                text += &format!("  {{\"model\": {}, \"led\": {}, \
+                                  \"key\": {}, \"presses\": {}}}{}\n",
+                                 json_string(model), led, json_string(key),
+                                 presses, sep);
+            }
+            text += "]\n";
+        }
+
+        _ => return Err(format!("Unknown export format “{}” (available: \
+                                 csv, json)",
+                                format)),
+    }
+
+    Ok(text)
+}
+
+/* Print the statistics file's content (see export_text()) */
+pub fn export(format: &str, layout_name: Option<&str>) -> Result<(), String> {
+    let text = export_text(&Heatmap::load()?, &layout::load_all()?, format,
+                           layout_name)?;
+    print!("{}", text);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Heatmap {
+        Heatmap::parse("test", text).unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        match Heatmap::parse("test", text) {
+            Ok(_) => panic!("“{}” parsed", text),
+            Err(e) => e,
+        }
+    }
+
+    fn layouts() -> Vec<Layout> {
+        vec![Layout::parse("test", "model test Test\n\
+                                    pid 0102\n\
+                                    layout ansi\n\
+                                    leds 4\n\
+                                    led 0 escape\n\
+                                    led 1 \"quote\n").unwrap()]
+    }
+
+    #[test]
+    fn parse_file() {
+        let heatmap = parse("# model  LED  hour  count\n\
+                             \n\
+                             test 0 100 3\n\
+                             test 0 100 2\n\
+                             test 0 101 1\n\
+                             test 1 100 4\n\
+                             other 0 100 7\n");
+
+        assert_eq!(heatmap.counts.len(), 4);
+        assert_eq!(heatmap.counts[&(String::from("test"), 0, 100)], 5);
+
+        let heat = heatmap.heat("test", None);
+        assert_eq!(heat.len(), 2);
+        assert_eq!(heat[&0], 6.0);
+        assert_eq!(heat[&1], 4.0);
+        assert!(heatmap.heat("nope", None).is_empty());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error("test 0 100\n"),
+                   "test:1: Expected “<model> <LED index> <hour> <count>”");
+        assert!(error("\ntest x 100 1\n")
+                    .starts_with("test:2: Invalid LED index “x”: "));
+        assert!(error("test 0 -1 1\n")
+                    .starts_with("test:1: Invalid hour “-1”: "));
+        assert!(error("test 0 100 1.5\n")
+                    .starts_with("test:1: Invalid count “1.5”: "));
+    }
+
+    #[test]
+    fn compact() {
+        let now = 1000;
+        let mut heatmap = parse(&format!("test 0 {} 1\n\
+                                          test 0 {} 2\n\
+                                          test 0 {} 4\n\
+                                          test 0 0 8\n\
+                                          test 1 {} 16\n",
+                                         now, now - KEEP_HOURS + 1,
+                                         now - KEEP_HOURS, 1));
+        heatmap.compact(now);
+
+        let mut counts: Vec<_> = heatmap.counts.into_iter().collect();
+        counts.sort();
+        assert_eq!(counts, [
+            ((String::from("test"), 0, 0), 12),
+            ((String::from("test"), 0, now - KEEP_HOURS + 1), 2),
+            ((String::from("test"), 0, now), 1),
+            ((String::from("test"), 1, 0), 16),
+        ]);
+    }
+
+    #[test]
+    fn export() {
+        let heatmap = parse("test 0 100 3\n\
+                             test 0 200 2\n\
+                             test 1 100 4\n\
+                             test 3 100 1\n\
+                             other 0 100 7\n");
+
+        assert_eq!(export_text(&heatmap, &layouts(), "csv", None).unwrap(),
+                   "model,led,key,presses\n\
+                    other,0,,7\n\
+                    test,0,escape,5\n\
+                    test,1,\"quote,4\n\
+                    test,3,,1\n");
+
+        assert_eq!(export_text(&heatmap, &layouts(), "json", None).unwrap(),
+                   "[\n  \
+                    {\"model\": \"other\", \"led\": 0, \"key\": \"\", \
+                    \"presses\": 7},\n  \
+                    {\"model\": \"test\", \"led\": 0, \"key\": \"escape\", \
+                    \"presses\": 5},\n  \
+                    {\"model\": \"test\", \"led\": 1, \"key\": \"\\\"quote\", \
+                    \"presses\": 4},\n  \
+                    {\"model\": \"test\", \"led\": 3, \"key\": \"\", \
+                    \"presses\": 1}\n\
+                    ]\n");
+
+        assert_eq!(export_text(&Heatmap::default(), &layouts(), "json", None)
+                       .unwrap(),
+                   "[\n]\n");
+        assert_eq!(export_text(&heatmap, &layouts(), "xml", None).unwrap_err(),
+                   "Unknown export format “xml” (available: csv, json)");
+    }
+}
@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Key events from the keyboards' own input devices (evdev, so Linux only).
+ * Every device is read by a background thread that forwards its key events.
+ * Layouts (see layout.rs) map the events' codes to LEDs.
+ */
+
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::Duration;
+
+use evdev::{Device, InputEventKind};
+
+use crate::keyboard::{self, Keyboard, VENDOR_ID};
+use crate::types::KeyEvent;
+
+
+pub struct Input {
+    events: Receiver<Result<KeyEvent, String>>,
+}
+
+
+/*
+ * Whether the event device at @path belongs to @kbd: The keyboard's HID
+ * interface for key events is the one next to interface 1 (which leddy uses
+ * for its requests), on the same USB port.  Emulated keyboards (--dry-run,
+ * --record) take events from any keyboard of the same model.
+ */
+fn belongs_to(path: &Path, dev: &Device, kbd: &Keyboard) -> bool {
+    let id = dev.input_id();
+    if id.vendor() != VENDOR_ID || id.product() != kbd.layout.pid ||
+       dev.supported_keys().is_none()
+    {
+        return false;
+    }
+
+    let Some(location) = &kbd.bus_location else {
+        return true;
+    };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    match keyboard::usb_location(&format!("/sys/class/input/{}/device",
+                                          name)) {
+        Some((port, iface)) => port == *location && iface != 1,
+        None => false,
+    }
+}
+
+fn forward_events(mut dev: Device, keyboard: usize,
+                  tx: Sender<Result<KeyEvent, String>>)
+{
+    loop {
+        let events =
+            match dev.fetch_events() {
+                Ok(e) => e,
+                Err(e) => {
+                    let _ = tx.send(Err(format!("Failed to read key events: \
+                                                 {}",
+                                                e)));
+                    return;
+                }
+            };
+
+        for ev in events {
+            if let InputEventKind::Key(key) = ev.kind() {
+                /* Value 2 is auto-repeat */
+                if ev.value() == 2 {
+                    continue;
+                }
+
+                let event = KeyEvent {
+                    keyboard,
+                    code: key.code(),
+                    down: ev.value() == 1,
+                };
+                if tx.send(Ok(event)).is_err() {
+                    /* Nobody is listening anymore */
+                    return;
+                }
+            }
+        }
+    }
+}
+
+
+impl Input {
+    /* Subscribe to the key events of the given keyboards */
+    pub fn open(kbds: &[Keyboard]) -> Result<Self, String> {
+        let (tx, rx) = mpsc::channel();
+        let mut found = vec![false; kbds.len()];
+
+        for (path, dev) in evdev::enumerate() {
+            let Some(i) = kbds.iter().position(|k| belongs_to(&path, &dev, k))
+            else {
+                continue;
+            };
+
+            let tx = tx.clone();
+            std::thread::spawn(move || forward_events(dev, i, tx));
+            found[i] = true;
+        }
+
+        if let Some(i) = found.iter().position(|f| !f) {
+            return Err(format!("No input device found for the {} (reading \
+                                /dev/input/event* may require root rights)",
+                               kbds[i].layout.model_name));
+        }
+
+        Ok(Input { events: rx })
+    }
+
+    /* Wait up to @timeout for the next key event */
+    pub fn next(&self, timeout: Duration) -> Result<Option<KeyEvent>, String> {
+        match self.events.recv_timeout(timeout) {
+            Ok(res) => res.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) =>
+                Err(String::from("All input devices are gone")),
+        }
+    }
+
+    /* All key events that have arrived since the last call, without waiting */
+    pub fn pending(&self) -> Result<Vec<KeyEvent>, String> {
+        let mut events = Vec::new();
+
+        loop {
+            match self.events.try_recv() {
+                Ok(res) => events.push(res?),
+                Err(TryRecvError::Empty) => return Ok(events),
+                Err(TryRecvError::Disconnected) =>
+                    return Err(String::from("All input devices are gone")),
+            }
+        }
+    }
+}
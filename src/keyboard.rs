@@ -1,374 +1,509 @@
 // SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use hidapi::{HidApi, HidDevice};
-use crate::types::{ColorParam, Direction, KeyMap};
+use std::ffi::{CStr, CString};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use hidapi::HidApi;
+use crate::capabilities;
+use crate::labels::Labels;
+use crate::layout::{self, Layout};
+use crate::protocol::{self, Command, Effect};
+use crate::transport::{HidTransport, Report, Transport};
+use crate::types::{Color, ColorMethods, ColorParam, Direction, KeyMap};
+use crate::zones::Zones;
+
+
+/* USB vendor ID of all STREAK keyboards */
+pub const VENDOR_ID: u16 = 0x2f0e;
 
 
 pub struct Keyboard {
-    dev: HidDevice,
-    color_cmd_prefix: Vec<u8>,
+    /* Locked for the whole duration of a (multi-report) request */
+    dev: Mutex<Box<dyn Transport>>,
+    lost: AtomicBool,
+    /* Whether to only temporarily show effects (0x0f) */
+    preview: bool,
     profile: u8,
+    /* LEDs the current effect is limited to (see set_zone()) */
+    zone: Option<Vec<bool>>,
+
+    pub layout: Layout,
+    pub labels: Labels,
+    pub zones: Zones,
+    /* USB bus and port (see StreakDevice), None for emulated keyboards */
+    pub bus_location: Option<String>,
+
+    /* Size in keyboard units (see layout.rs) */
+    pub width: f32,
+    pub height: f32,
+    pub led_count: usize,
+}
 
-    pub mini: bool,
+/* A STREAK keyboard found on the system */
+pub struct StreakDevice {
+    pub pid: u16,
+    pub model_name: String,
+    pub serial: Option<String>,
+    pub path: CString,
+    pub bus_location: String,
+}
 
-    pub width: usize,
-    pub led_count: usize,
 
-    /* width * height, row first (unmapped entries are 0xff) */
-    /* FIXME: Use floating-point coordinates for better precision */
-    pub ledmap: Vec<u8>,
+impl StreakDevice {
+    pub fn path_str(&self) -> String {
+        self.path.to_string_lossy().into_owned()
+    }
+}
+
+/*
+ * USB bus and port (e.g. “3-2”) of the device at the given sysfs path, and
+ * the number of the USB interface it belongs to
+ */
+#[cfg(target_os = "linux")]
+pub fn usb_location(sys_path: &str) -> Option<(String, u8)> {
+    let sys_path = std::fs::canonicalize(sys_path).ok()?;
+
+    /* The USB interface directory looks like <bus>-<port>:<config>.<iface> */
+    for component in sys_path.iter().rev() {
+        let component = component.to_string_lossy();
+        if let Some((port, config_iface)) = component.split_once(':') {
+            if port.contains('-') && !port.contains(' ') {
+                let iface = config_iface.split_once('.')?.1.parse().ok()?;
+                return Some((String::from(port), iface));
+            }
+        }
+    }
+
+    None
+}
+
+/* USB bus and port the given hidraw device is connected to (e.g. “3-2”) */
+#[cfg(target_os = "linux")]
+fn bus_location(path: &CStr) -> String {
+    let path = path.to_string_lossy();
+    let name = path.rsplit('/').next().unwrap_or("");
+
+    match usb_location(&format!("/sys/class/hidraw/{}/device", name)) {
+        Some((port, _)) => port,
+        None => String::from("unknown"),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bus_location(_path: &CStr) -> String {
+    String::from("unknown")
 }
 
 
-#[allow(unused)]
 impl Keyboard {
-    /* LED indices */
-    pub const FN_LOCK:          usize = 0;
-    pub const ESCAPE:           usize = 1;
-    pub const BACKTICK:         usize = 2;
-    pub const TAB:              usize = 3;
-    pub const CAPS_LOCK:        usize = 4;
-    pub const LSHIFT:           usize = 5;
-    pub const LCONTROL:         usize = 6;
-    pub const F1:               usize = 7;
-    pub const TW_1:             usize = 8;
-    pub const Q:                usize = 9;
-    pub const A:                usize = 10;
-    pub const ISO_PIPE:         usize = 11;
-    pub const META:             usize = 12;
-    pub const F2:               usize = 13;
-    pub const TW_2:             usize = 14;
-    pub const W:                usize = 15;
-    pub const S:                usize = 16;
-    pub const Z:                usize = 17;
-    pub const LALT:             usize = 18;
-    pub const F3:               usize = 19;
-    pub const TW_3:             usize = 20;
-    pub const E:                usize = 21;
-    pub const D:                usize = 22;
-    pub const X:                usize = 23;
-
-    pub const F4:               usize = 25;
-    pub const TW_4:             usize = 26;
-    pub const R:                usize = 27;
-    pub const F:                usize = 28;
-    pub const C:                usize = 29;
-
-    pub const F5:               usize = 31;
-    pub const TW_5:             usize = 32;
-    pub const T:                usize = 33;
-    pub const G:                usize = 34;
-    pub const V:                usize = 35;
-    pub const SPACE:            usize = 36;
-    pub const F6:               usize = 37;
-    pub const TW_6:             usize = 38;
-    pub const Y:                usize = 39;
-    pub const H:                usize = 40;
-    pub const B:                usize = 41;
-
-    pub const F7:               usize = 43;
-    pub const TW_7:             usize = 44;
-    pub const U:                usize = 45;
-    pub const J:                usize = 46;
-    pub const N:                usize = 47;
-
-    pub const F8:               usize = 49;
-    pub const TW_8:             usize = 50;
-    pub const I:                usize = 51;
-    pub const K:                usize = 52;
-    pub const M:                usize = 53;
-
-    pub const F9:               usize = 55;
-    pub const TW_9:             usize = 56;
-    pub const O:                usize = 57;
-    pub const L:                usize = 58;
-    pub const COMMA:            usize = 59;
-    pub const RALT:             usize = 60;
-    pub const TW_0:             usize = 61;
-    pub const MINUS:            usize = 62;
-    pub const P:                usize = 63;
-    pub const SEMICOLON:        usize = 64;
-    pub const DOT:              usize = 65;
-    pub const SLASH:            usize = 66;
-    pub const F10:              usize = 67;
-    pub const EQUAL:            usize = 68;
-    pub const LBRACKET:         usize = 69;
-    pub const QUOTE:            usize = 70;
-
-    pub const FN:               usize = 72;
-    pub const F11:              usize = 73;
-
-    pub const RBRACKET:         usize = 75;
-    pub const ISO_BACKSLASH:    usize = 76;
-    pub const RSHIFT:           usize = 77;
-    pub const MENU:             usize = 78;
-    pub const F12:              usize = 79;
-    pub const BACKSPACE:        usize = 80;
-    pub const ANSI_BACKSLASH:   usize = 81;
-    pub const ENTER:            usize = 82;
-    pub const RCONTROL:         usize = 83;
-    pub const LEFT:             usize = 84;
-    pub const DOWN:             usize = 85;
-    pub const RIGHT:            usize = 86;
-    pub const UP:               usize = 87;
-    pub const DELETE:           usize = 88;
-    pub const INSERT:           usize = 89;
-    pub const PRINT:            usize = 90;
-    pub const MUTE_MIC:         usize = 91;
-    pub const MUTE_SPEAKER:     usize = 92;
-    pub const SCROLL_LOCK:      usize = 93;
-    pub const HOME:             usize = 94;
-    pub const END:              usize = 95;
-    pub const PAGE_DOWN:        usize = 96;
-    pub const GAMING_MODE:      usize = 97;
-    pub const PAUSE:            usize = 98;
-    pub const PAGE_UP:          usize = 99;
-
-    pub const MINI_SIG_PLATE:   usize = 103;
-
-    pub const NUM_LOCK:         usize = 100;
-    pub const NUM_7:            usize = 101;
-    pub const NUM_4:            usize = 102;
-    pub const NUM_1:            usize = 103;
-    pub const NUM_0:            usize = 104;
-    pub const NUM_2:            usize = 105;
-    pub const NUM_5:            usize = 106;
-    pub const NUM_8:            usize = 107;
-    pub const NUM_SLASH:        usize = 108;
-    pub const NUM_ASTERISK:     usize = 109;
-    pub const NUM_9:            usize = 110;
-    pub const NUM_6:            usize = 111;
-    pub const NUM_3:            usize = 112;
-    pub const NUM_DECIMAL:      usize = 113;
-    pub const NUM_ENTER:        usize = 114;
-    pub const NUM_PLUS:         usize = 115;
-    pub const NUM_MINUS:        usize = 116;
-
-    pub const VOLUME_KNOB:      usize = 118;
-
-    pub const FULL_SIG_PLATE:   usize = 120;
-
-
-    pub fn new() -> Result<Self, String> {
-        let hidapi = HidApi::new().unwrap();
-
-        /* TODO: Look for all matching devices and let the user choose */
-        let dev_info =
-            match hidapi.device_list().find(|dev|
-                dev.vendor_id() == 0x2f0e &&
-                (dev.product_id() == 0x0101 || dev.product_id() == 0x0102) &&
-                dev.interface_number() == 1)
-            {
-                Some(di) => di,
-                None =>
-                    return Err(String::from("No miniSTREAK or STREAK keyboard \
-                                             found")),
+    /*
+     * Open all keyboards matching @selector (see open_devices()), using the
+     * layout with the given name (or the model's default layout)
+     */
+    pub fn open(selector: Option<&str>, layout_name: Option<&str>,
+                labels: &Labels, zones: &Zones)
+        -> Result<Vec<Self>, String>
+    {
+        let layouts = layout::load_all()?;
+
+        let mut kbds = Vec::new();
+        for (dev, pid, location) in Self::open_devices(selector)? {
+            let layout = layout::find(&layouts, pid, layout_name)?;
+            let mut kbd = Self::with_transport(Box::new(dev), layout.clone(),
+                                               labels.clone(), zones.clone());
+            kbd.bus_location = Some(location);
+            kbds.push(kbd);
+        }
+
+        Ok(kbds)
+    }
+
+    /* List all STREAK keyboards connected to the system */
+    pub fn list_devices() -> Result<Vec<StreakDevice>, String> {
+        let hidapi = Self::hidapi()?;
+        Ok(Self::enumerate(&hidapi, &layout::load_all()?))
+    }
+
+    /*
+     * Look up keyboards and open them; returns each one's USB product ID and
+     * bus location.  @selector is a comma-separated list of serial numbers,
+     * HID paths, or indices into the list returned by list_devices(), or
+     * “all”.  If it is None, the first keyboard found is used.
+     */
+    pub fn open_devices(selector: Option<&str>)
+        -> Result<Vec<(HidTransport, u16, String)>, String>
+    {
+        let hidapi = Self::hidapi()?;
+        let devices = Self::enumerate(&hidapi, &layout::load_all()?);
+        let selected = Self::select(&devices, selector)?;
+
+        let mut opened = Vec::with_capacity(selected.len());
+        for dev_info in selected {
+            let dev = match hidapi.open_path(&dev_info.path) {
+                Ok(x) => x,
+                Err(e) =>
+                    return Err(format!("Failed to open HID device: {}\n\
+                                        Check whether you have the required \
+                                        access rights.",
+                                       e)),
+            };
+
+            opened.push((HidTransport::new(dev), dev_info.pid,
+                         dev_info.bus_location.clone()));
+        }
+
+        Ok(opened)
+    }
+
+    /* The keyboards in @devices that @selector selects (see open_devices()) */
+    fn select<'a>(devices: &'a [StreakDevice], selector: Option<&str>)
+        -> Result<Vec<&'a StreakDevice>, String>
+    {
+        if devices.is_empty() {
+            return Err(String::from("No miniSTREAK or STREAK keyboard found"));
+        }
+
+        let sels =
+            match selector {
+                None => return Ok(vec![&devices[0]]),
+                Some("all") => return Ok(devices.iter().collect()),
+                Some(sels) => sels,
             };
 
-        let mini = dev_info.product_id() == 0x0102;
-
-        let dev = match dev_info.open_device(&hidapi) {
-            Ok(x) => x,
-            Err(e) =>
-                return Err(format!("Failed to open HID device: {}\n\
-                                    Check whether you have the required access \
-                                    rights.",
-                                   e)),
-        };
-
-        Ok(
-            Keyboard {
-                dev,
-                color_cmd_prefix: vec![0x05, 0x01, 0x02],
-                profile: 1,
-
-                mini,
-
-                width: if mini { 18 } else { 22 },
-                led_count: if mini { 106 } else { 124 },
-
-                ledmap:
-                    if mini {
-                        vec![
-                           1,    0,    7,   13,   19,   25,   31,   37,   43,   49,  103,   55,   67,   73,   79,   90,   93,   98,
-                           2,    8,   14,   20,   26,   32,   38,   44,   50,   56,   61,   62,   68,   74,   80,   89,   94,   99,
-                           3,    9,   15,   21,   27,   33,   39,   45,   51,   57,   63,   69,   75, 0xff,   81,   88,   95,   96,
-                           4, 0xff,   10,   16,   22,   28,   34,   40,   46,   52,   58,   64,   70,   76,   82, 0xff, 0xff, 0xff,
-                           5,   11,   17,   23,   29,   35,   41,   47,   53,   59,   65,   66,   71,   77, 0xff, 0xff,   87, 0xff,
-                           6,   12, 0xff,   18,   24,   30,   36,   42,   48,   54,   60,   72, 0xff,   78,   83,   84,   85,   86,
-                        ]
-                    } else {
-                        vec![
-                           1,    0,    7,   13,   19,   25,   31,   37,   43,   49,  120,   55,   67,   73,   79,   90,   93,   98,   91,   97,   92,  118,
-                           2,    8,   14,   20,   26,   32,   38,   44,   50,   56,   61,   62,   68,   74,   80,   89,   94,   99,  100,  108,  109,  116,
-                           3,    9,   15,   21,   27,   33,   39,   45,   51,   57,   63,   69,   75, 0xff,   81,   88,   95,   96,  101,  107,  110,  115,
-                           4, 0xff,   10,   16,   22,   28,   34,   40,   46,   52,   58,   64,   70,   76,   82, 0xff, 0xff, 0xff,  102,  106,  111, 0xff,
-                           5,   11,   17,   23,   29,   35,   41,   47,   53,   59,   65,   66,   71,   77, 0xff, 0xff,   87, 0xff,  103,  105,  112,  114,
-                           6,   12, 0xff,   18,   24,   30,   36,   42,   48,   54,   60,   72, 0xff,   78,   83,   84,   85,   86,  104, 0xff,  113, 0xff,
-                        ]
-                    },
+        let mut selected = Vec::new();
+
+        for sel in sels.split(',') {
+            let dev_info =
+                devices.iter().find(|d| d.path_str() == sel)
+                    .or_else(|| devices.iter().find(|d|
+                        d.serial.as_deref() == Some(sel)))
+                    .or_else(|| sel.parse::<usize>().ok()
+                                   .and_then(|i| devices.get(i)));
+
+            match dev_info {
+                Some(di) => selected.push(di),
+                None =>
+                    return Err(format!("No miniSTREAK or STREAK keyboard \
+                                        matching “{}” found (see \
+                                        --list-devices)",
+                                       sel)),
             }
-        )
+        }
+
+        Ok(selected)
     }
 
-    pub fn software_effect_start(&mut self) {
-        self.color_cmd_prefix = vec![0x0f];
+    /*
+     * Whether all keyboards that @selector selects (see open_devices()) are
+     * connected
+     */
+    pub fn selected_present(selector: Option<&str>) -> Result<bool, String> {
+        let devices = Self::list_devices()?;
+        Ok(Self::select(&devices, selector).is_ok())
     }
 
-    pub fn software_effect_end(&mut self) {
-        self.color_cmd_prefix = vec![0x05, self.profile, 0x02];
-        self.refresh_profile();
+    fn hidapi() -> Result<HidApi, String> {
+        match HidApi::new() {
+            Ok(x) => Ok(x),
+            Err(e) => Err(format!("Failed to initialize hidapi: {}", e)),
+        }
     }
 
-    pub fn set_profile(&mut self, profile: u8) {
-        self.profile = profile;
-        self.software_effect_end();
+    /* Find all keyboards for which there is a layout */
+    fn enumerate(hidapi: &HidApi, layouts: &[Layout]) -> Vec<StreakDevice> {
+        hidapi.device_list()
+            .filter(|dev|
+                dev.vendor_id() == VENDOR_ID && dev.interface_number() == 1)
+            .filter_map(|dev| Some(
+                StreakDevice {
+                    pid: dev.product_id(),
+                    model_name: layouts.iter()
+                                    .find(|l| l.pid == dev.product_id())?
+                                    .model_name.clone(),
+                    serial: dev.serial_number().map(String::from),
+                    path: CString::from(dev.path()),
+                    bus_location: bus_location(dev.path()),
+                }))
+            .collect()
+    }
+
+    pub fn with_transport(dev: Box<dyn Transport>, layout: Layout,
+                          labels: Labels, zones: Zones)
+        -> Self
+    {
+        let (width, height) = layout.size();
+
+        Keyboard {
+            dev: Mutex::new(dev),
+            lost: AtomicBool::new(false),
+            preview: false,
+            profile: 1,
+            zone: None,
+
+            bus_location: None,
+            width,
+            height,
+            led_count: layout.led_count,
+
+            layout,
+            labels,
+            zones,
+        }
     }
 
-    pub fn refresh_profile(&self) {
-        self.send_req(&[0x04], &[self.profile]);
+    /* LED of the key with the given name or (localized) label */
+    pub fn led(&self, key: &str) -> Option<usize> {
+        self.layout.led(self.labels.key(key))
     }
 
-    pub fn send_req(&self, prefix: &[u8], raw_data: &[u8]) {
-        let plen = prefix.len();
-        let len = raw_data.len() + plen;
-        let mut ofs = 0;
+    /*
+     * LEDs of the given zone (None if there is no such zone).  Keys this
+     * keyboard does not have are left out.
+     */
+    pub fn zone_leds(&self, name: &str) -> Option<Vec<usize>> {
+        self.zones.leds(name, &self.layout, &self.labels)
+    }
 
-        let cmd =
-            if plen > 0 {
-                prefix[0]
-            } else {
-                raw_data[0]
+    /*
+     * Limit all following per-key effects to the given zone (or lift the
+     * limit with None): Keys outside of it stay off.  Hardware effects cannot
+     * be limited and are refused while a zone is set.
+     */
+    pub fn set_zone(&mut self, name: Option<&str>) -> Result<(), String> {
+        self.zone =
+            match name {
+                None => None,
+                Some(name) => {
+                    let leds =
+                        self.zone_leds(name).ok_or_else(|| {
+                            format!("Unknown zone “{}” (available: {})",
+                                    name, self.zones.names().join(", "))
+                        })?;
+
+                    let mut mask = vec![false; self.led_count];
+                    for led in leds {
+                        mask[led] = true;
+                    }
+                    Some(mask)
+                }
             };
 
-        while ofs < len {
-            let mut data = [0u8; 65];
+        Ok(())
+    }
 
-            data[0] = 0x00;
+    /* Whether effects are currently limited to a zone */
+    pub fn zoned(&self) -> bool {
+        self.zone.is_some()
+    }
 
-            data[1] = cmd;
+    /*
+     * Normalized coordinates are the position on the keyboard in [0, 1]
+     * (left to right, top to bottom).  These convert from and to keyboard
+     * units.
+     */
+    pub fn normalize(&self, pos: (f32, f32)) -> (f32, f32) {
+        (pos.0 / self.width, pos.1 / self.height)
+    }
 
-            data[2] = len as u8;
-            data[3] = (len >> 8) as u8;
-            data[4] = (len >> 16) as u8;
+    pub fn denormalize(&self, pos: (f32, f32)) -> (f32, f32) {
+        (pos.0 * self.width, pos.1 * self.height)
+    }
 
-            data[5] = ofs as u8;
-            data[6] = (ofs >> 8) as u8;
-            data[7] = (ofs >> 16) as u8;
+    /* Normalized center of the given LED's key (None if it has no position) */
+    pub fn led_position(&self, led: usize) -> Option<(f32, f32)> {
+        Some(self.normalize(self.layout.area(led)?.center()))
+    }
 
-            for i in ofs..std::cmp::min(ofs + 57, len) {
-                data[i - ofs + 8] =
-                    if i >= plen {
-                        raw_data[i - plen]
-                    } else {
-                        prefix[i]
-                    };
-            }
+    pub fn software_effect_start(&mut self) {
+        self.preview = true;
+    }
 
-            self.dev.write(&data).unwrap();
+    pub fn software_effect_end(&mut self) -> Result<(), String> {
+        self.preview = false;
+        self.refresh_profile()
+    }
 
-            ofs += 57;
-        }
+    pub fn set_profile(&mut self, profile: u8) -> Result<(), String> {
+        self.profile = profile;
+        self.software_effect_end()
+    }
+
+    pub fn refresh_profile(&self) -> Result<(), String> {
+        self.send(&Command::SwitchProfile(self.profile))
+    }
+
+    /* Whether writing to the keyboard has failed (e.g. it was unplugged) */
+    pub fn lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
 
-        if cmd == 0x05 {
+    /* Tell the keyboard we are still there, so it keeps showing a preview */
+    pub fn keep_alive(&self) -> Result<(), String> {
+        self.send(&Command::KeepAlive)?;
+        self.send(&Command::KeepAliveFe)
+    }
+
+    /* Wait up to @timeout for an input report from the keyboard */
+    pub fn read_report(&self, timeout: Duration)
+        -> Result<Option<Vec<u8>>, String>
+    {
+        self.dev.lock().unwrap().read(timeout)
+    }
+
+    pub fn send(&self, cmd: &Command) -> Result<(), String> {
+        self.send_req(&cmd.encode())
+    }
+
+    /* Send a raw request (starting with the command byte) */
+    pub fn send_req(&self, req: &[u8]) -> Result<(), String> {
+        let dev = self.dev.lock().unwrap();
+
+        self.write_reports(dev.as_ref(), &protocol::chunk(req))?;
+
+        if req[0] == 0x05 {
             /* Save changes? */
-            self.send_req(&[0x13], &[]);
+            self.write_reports(dev.as_ref(), &Command::Save.reports())?;
             /* Show profile 1 */
-            self.send_req(&[0x04], &[self.profile]);
+            let switch = Command::SwitchProfile(self.profile);
+            self.write_reports(dev.as_ref(), &switch.reports())?;
         }
+
+        Ok(())
     }
 
-    pub fn all_keys_raw(&self, raw_keys: &[u8]) {
-        if self.color_cmd_prefix[0] == 0x05 {
-            self.send_req(&[0x05, self.profile, 0x02, 0x03], raw_keys);
-        } else {
-            self.send_req(&[0x0f, 0x03], raw_keys);
+    fn write_reports(&self, dev: &dyn Transport, reports: &[Report])
+        -> Result<(), String>
+    {
+        for report in reports {
+            if let Err(e) = dev.write(report) {
+                self.lost.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
         }
-    }
 
-    pub fn all_keys(&self, keys: &KeyMap) {
-        self.all_keys_raw(keys.raw());
+        Ok(())
     }
 
-    pub fn pulse(&self, cp: ColorParam, speed: u8) {
-        let rgb = cp.rgb();
+    /* Show the effect (software effects) or store it in the profile */
+    pub fn effect(&self, effect: Effect) -> Result<(), String> {
+        if self.zone.is_some() && !matches!(effect, Effect::AllKeys(_)) {
+            return Err(String::from("Hardware effects cannot be limited to \
+                                     a zone"));
+        }
 
-        self.send_req(self.color_cmd_prefix.as_slice(),
-                      &[0x06,
-                        cp.mode(),
-                        rgb.0, rgb.1, rgb.2,
-                        speed]);
+        if self.preview {
+            self.send(&Command::Preview(effect))
+        } else {
+            self.send(&Command::SetProfileEffect(self.profile, effect))
+        }
     }
 
-    pub fn wave(&self, cp: ColorParam, speed: u8, direction: Direction) {
-        let rgb = cp.rgb();
+    /*
+     * Set all keys' colors; LEDs the layout does not have and those outside
+     * of the current zone stay off
+     */
+    pub fn all_keys_raw(&self, raw_keys: &[u8]) -> Result<(), String> {
+        let mut colors: Vec<Color> =
+            raw_keys.chunks(3).map(|c| (c[0], c[1], c[2])).collect();
 
-        self.send_req(self.color_cmd_prefix.as_slice(),
-                      &[0x07,
-                        cp.mode(),
-                        rgb.0, rgb.1, rgb.2,
-                        speed,
-                        direction as u8]);
-    }
+        for (led, color) in colors.iter_mut().enumerate() {
+            let in_zone =
+                self.zone.as_ref().is_none_or(|z| z.get(led) == Some(&true));
 
-    pub fn reactive(&self, cp: ColorParam, speed: u8, keyup: bool) {
-        let rgb = cp.rgb();
+            if !self.layout.has_led(led) || !in_zone {
+                *color = Color::BLACK;
+            }
+        }
 
-        self.send_req(self.color_cmd_prefix.as_slice(),
-                      &[0x09,
-                        cp.mode(),
-                        rgb.0, rgb.1, rgb.2,
-                        speed,
-                        !keyup as u8]);
+        self.effect(Effect::AllKeys(colors))
     }
 
-    pub fn reactive_ripple(&self, cp: ColorParam, speed: u8, keyup: bool) {
-        let rgb = cp.rgb();
+    /* Like all_keys_raw(), but including LEDs the layout does not have */
+    pub fn all_leds_raw(&self, raw_leds: &[u8]) -> Result<(), String> {
+        self.effect(Effect::AllKeys(
+            raw_leds.chunks(3).map(|c| (c[0], c[1], c[2])).collect()
+        ))
+    }
 
-        self.send_req(self.color_cmd_prefix.as_slice(),
-                      &[0x0a,
-                        cp.mode(),
-                        rgb.0, rgb.1, rgb.2,
-                        speed,
-                        !keyup as u8]);
+    pub fn all_keys(&self, keys: &KeyMap) -> Result<(), String> {
+        self.all_keys_raw(keys.raw())
     }
 
-    pub fn rain(&self, cp: ColorParam, speed: u8, direction: Direction) {
-        let rgb = cp.rgb();
+    /*
+     * Color mode to send for @cp in the given effect, replacing modes the
+     * firmware does not support (see capabilities.rs)
+     */
+    fn mode(&self, effect: &str, cp: &ColorParam) -> u8 {
+        match capabilities::model(&self.layout.model).effect(effect) {
+            Some(caps) => caps.mode(cp.mode()),
+            None => cp.mode(),
+        }
+    }
 
-        /* This effect does not support rainbow mode */
-        let mode =
-            match cp {
-                ColorParam::Rainbow => (ColorParam::Randomized).mode(),
-                _ => cp.mode()
-            };
+    pub fn pulse(&self, cp: ColorParam, speed: u8) -> Result<(), String> {
+        self.effect(Effect::Pulse {
+            mode: self.mode("pulse", &cp),
+            color: cp.rgb(),
+            speed,
+        })
+    }
 
-        self.send_req(self.color_cmd_prefix.as_slice(),
-                      &[0x0b,
-                        mode,
-                        rgb.0, rgb.1, rgb.2,
-                        speed,
-                        direction as u8]);
+    pub fn wave(&self, cp: ColorParam, speed: u8, direction: Direction)
+        -> Result<(), String>
+    {
+        self.effect(Effect::Wave {
+            mode: self.mode("wave", &cp),
+            color: cp.rgb(),
+            speed,
+            direction,
+        })
     }
 
-    pub fn gradient(&self, cp: ColorParam) {
-        let mut req = [0u8; 42];
+    pub fn reactive(&self, cp: ColorParam, speed: u8, keyup: bool)
+        -> Result<(), String>
+    {
+        self.effect(Effect::Reactive {
+            mode: self.mode("reactive", &cp),
+            color: cp.rgb(),
+            speed,
+            keydown: !keyup,
+        })
+    }
 
-        req[0] = 0x0c;
-        cp.gradient().serialize(&mut req[1..42]);
-        self.send_req(self.color_cmd_prefix.as_slice(), &req);
+    pub fn reactive_ripple(&self, cp: ColorParam, speed: u8, keyup: bool)
+        -> Result<(), String>
+    {
+        self.effect(Effect::Ripple {
+            mode: self.mode("reactive-ripple", &cp),
+            color: cp.rgb(),
+            speed,
+            keydown: !keyup,
+        })
     }
 
-    pub fn fade(&self, cp: ColorParam, speed: u8) {
-        let mut req = [0u8; 44];
+    pub fn rain(&self, cp: ColorParam, speed: u8, direction: Direction)
+        -> Result<(), String>
+    {
+        self.effect(Effect::Rain {
+            mode: self.mode("rain", &cp),
+            color: cp.rgb(),
+            speed,
+            direction,
+        })
+    }
 
-        req[0] = 0x0d;
-        req[1] = cp.mode();
-        cp.gradient().serialize(&mut req[2..43]);
-        req[43] = speed;
+    pub fn gradient(&self, cp: ColorParam) -> Result<(), String> {
+        self.effect(Effect::Gradient(cp.gradient()))
+    }
 
-        self.send_req(self.color_cmd_prefix.as_slice(), &req);
+    pub fn fade(&self, cp: ColorParam, speed: u8) -> Result<(), String> {
+        self.effect(Effect::Fade {
+            mode: self.mode("fade", &cp),
+            gradient: cp.gradient(),
+            speed,
+        })
     }
 }
-
@@ -0,0 +1,289 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Per-key color specifications, e.g. for “all-keys/color=stdin”.
+ *
+ * A specification consists of entries, separated by newlines or semicolons.
+ * A # at the start of a word starts a comment that lasts until the end of
+ * the line.  Entries are:
+ *
+ *   <keys>=RRGGBB          Colors the given keys; <keys> is a comma-separated
+ *                          list of key names or labels (see labels.rs), zones
+ *                          (see zones.rs), and ranges (e.g. “f1..f12”)
+ *   default=RRGGBB         Color for all LEDs not given otherwise (default:
+ *                          black)
+ *   RRGGBB                 Colors the next LED by index (the first such entry
+ *                          colors LED 0, the next one LED 1, and so on)
+ *
+ * Later entries override earlier ones, except that entries by index are
+ * always overridden by those by key.
+ */
+
+use std::fs;
+use std::io::Read;
+
+use crate::keyboard::Keyboard;
+use crate::types::{Color, ColorMethods, KeyMap};
+use crate::zones::parse_keys;
+
+
+#[derive(Clone)]
+pub struct KeySpec {
+    /* Colors by LED index */
+    sequence: Vec<Color>,
+    /* Key names, labels, or zones */
+    entries: Vec<(Vec<String>, Color)>,
+    default: Option<Color>,
+}
+
+
+/* Cut off a comment (a # at the start of a word) */
+fn strip_comment(line: &str) -> &str {
+    let mut prev = ' ';
+
+    for (i, c) in line.char_indices() {
+        if c == '#' && prev.is_whitespace() {
+            return &line[..i];
+        }
+        prev = c;
+    }
+
+    line
+}
+
+
+impl KeySpec {
+    /* Parse a specification; @source is used in error messages */
+    pub fn parse(source: &str, text: &str) -> Result<KeySpec, String> {
+        let mut spec = KeySpec {
+            sequence: Vec::new(),
+            entries: Vec::new(),
+            default: None,
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            for entry in strip_comment(line).split(';').map(|e| e.trim()) {
+                if entry.is_empty() {
+                    continue;
+                }
+
+                let res =
+                    match entry.rsplit_once('=') {
+                        Some((keys, color)) =>
+                            Color::from_str(color.trim()).and_then(|color| {
+                                let keys = keys.trim();
+                                if keys.eq_ignore_ascii_case("default") {
+                                    spec.default = Some(color);
+                                } else {
+                                    spec.entries.push((parse_keys(keys)?,
+                                                       color));
+                                }
+                                Ok(())
+                            }),
+
+                        None => Color::from_str(entry)
+                                    .map(|color| spec.sequence.push(color)),
+                    };
+
+                if let Err(e) = res {
+                    return Err(format!("{}:{}: {}", source, i + 1, e));
+                }
+            }
+        }
+
+        if spec.sequence.is_empty() && spec.entries.is_empty() &&
+           spec.default.is_none()
+        {
+            return Err(format!("{}: No key colors given", source));
+        }
+
+        Ok(spec)
+    }
+
+    pub fn from_stdin() -> Result<KeySpec, String> {
+        let mut text = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut text) {
+            return Err(format!("Read error: {}", e));
+        }
+
+        KeySpec::parse("<stdin>", &text)
+    }
+
+    pub fn from_file(path: &str) -> Result<KeySpec, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => KeySpec::parse(path, &text),
+            Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+        }
+    }
+
+    /* All colors given, in order (for effects that cannot color keys) */
+    pub fn colors(&self) -> Vec<Color> {
+        self.sequence.iter()
+            .chain(self.entries.iter().map(|(_, color)| color))
+            .chain(self.default.iter())
+            .copied()
+            .collect()
+    }
+
+    /*
+     * Build the LED colors for @kbd.  Also returns the keys given by the user
+     * that @kbd does not have.
+     */
+    fn resolve(&self, kbd: &Keyboard) -> (KeyMap, Vec<&str>) {
+        let mut map = vec![self.default.unwrap_or(Color::BLACK); kbd.led_count];
+        let mut missing = Vec::new();
+
+        for (led, color) in self.sequence.iter().enumerate() {
+            if led < map.len() {
+                map[led] = *color;
+            }
+        }
+
+        for (keys, color) in &self.entries {
+            for key in keys {
+                /* Zones may well be empty on some keyboards */
+                let leds =
+                    match (kbd.zone_leds(key), kbd.led(key)) {
+                        (Some(leds), _) => leds,
+                        (None, Some(led)) => vec![led],
+                        (None, None) => {
+                            missing.push(key.as_str());
+                            continue;
+                        }
+                    };
+
+                for led in leds {
+                    map[led] = *color;
+                }
+            }
+        }
+
+        (KeyMap { map }, missing)
+    }
+
+    /*
+     * Build the LED colors for all keyboards.  Keys that only some keyboards
+     * have are fine, but keys that none has are an error.
+     */
+    pub fn keymaps(&self, kbds: &[Keyboard]) -> Result<Vec<KeyMap>, String> {
+        let mut keymaps = Vec::with_capacity(kbds.len());
+        let mut missing: Option<Vec<&str>> = None;
+
+        for kbd in kbds {
+            let (keymap, kbd_missing) = self.resolve(kbd);
+            keymaps.push(keymap);
+
+            missing = Some(match missing {
+                None => kbd_missing,
+                Some(m) => m.into_iter()
+                             .filter(|k| kbd_missing.contains(k))
+                             .collect(),
+            });
+        }
+
+        if let Some(key) = missing.unwrap_or_default().first() {
+            return Err(format!("Unknown key “{}” (see --list-keys)", key));
+        }
+
+        Ok(keymaps)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::labels::Labels;
+    use crate::layout::Layout;
+    use crate::transport::DryRunTransport;
+    use crate::zones::Zones;
+
+    const RED: Color = (0xff, 0x00, 0x00);
+    const GREEN: Color = (0x00, 0xff, 0x00);
+    const GRAY: Color = (0x10, 0x10, 0x10);
+
+    fn keyboard(leds: &str, labels: &str) -> Keyboard {
+        let layout = Layout::parse("test", &format!("model test Test\n\
+                                                     pid 0102\n\
+                                                     layout ansi\n\
+                                                     leds 5\n\
+                                                     {}",
+                                                    leds)).unwrap();
+        let labels = Labels::parse("test", labels).unwrap();
+
+        Keyboard::with_transport(Box::new(DryRunTransport), layout, labels,
+                                 Zones::new(&[]).unwrap())
+    }
+
+    fn us() -> Keyboard {
+        keyboard("led 0 escape\nled 1 y\nled 2 z\nled 3 w\n", "labels us\n")
+    }
+
+    fn colors(text: &str, kbds: &[Keyboard])
+        -> Result<Vec<Vec<Color>>, String>
+    {
+        let keymaps = KeySpec::parse("test", text)?.keymaps(kbds)?;
+        Ok(keymaps.into_iter().map(|k| k.map).collect())
+    }
+
+    fn error(text: &str) -> String {
+        match colors(text, &[us()]) {
+            Ok(_) => panic!("“{}” was accepted", text),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(colors("escape=ff0000; default=101010 # comment\n\
+                           Y , z = 00ff00",
+                          &[us()]).unwrap(),
+                   [[RED, GREEN, GREEN, GRAY, GRAY]]);
+
+        /* Zones, and entries by index (overridden by those by key) */
+        assert_eq!(colors("00ff00\n00ff00\nwasd=ff0000\ny=ff0000",
+                          &[us()]).unwrap(),
+                   [[GREEN, RED, Color::BLACK, RED, Color::BLACK]]);
+    }
+
+    #[test]
+    fn labels() {
+        let de = keyboard("led 0 escape\nled 1 y\nled 2 z\nled 3 w\n",
+                          "labels de\nz y\ny z\n");
+
+        /* “z” is the key right of “t” on a German keyboard */
+        assert_eq!(colors("z=ff0000", &[de]).unwrap(),
+                   [[Color::BLACK, RED, Color::BLACK, Color::BLACK,
+                     Color::BLACK]]);
+        assert_eq!(colors("z=ff0000", &[us()]).unwrap(),
+                   [[Color::BLACK, Color::BLACK, RED, Color::BLACK,
+                     Color::BLACK]]);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert_eq!(error("space=ff0000"),
+                   "Unknown key “space” (see --list-keys)");
+
+        /* Fine as long as one keyboard has the key */
+        let other = keyboard("led 4 space\n", "labels us\n");
+        assert_eq!(colors("space=ff0000", &[us(), other]).unwrap(),
+                   [vec![Color::BLACK; 5],
+                    vec![Color::BLACK, Color::BLACK, Color::BLACK,
+                         Color::BLACK, RED]]);
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(error("# nothing\n;;\n"), "test: No key colors given");
+        assert_eq!(error("escape=ff0000\n=00ff00"),
+                   "test:2: Empty key name in “”");
+        assert_eq!(error("escape,,y=ff0000"),
+                   "test:1: Empty key name in “escape,,y”");
+        assert_eq!(error("f12..f1=ff0000"),
+                   "test:1: Invalid key range “f12..f1”");
+        assert!(error("escape=red").starts_with("test:1: "));
+        assert!(error("ff00").starts_with("test:1: "));
+    }
+}
@@ -0,0 +1,202 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Localized key labels: Key names as used in layout files refer to the keys'
+ * positions on a US keyboard (e.g. “y” is the key right of “t”).  A label
+ * set maps the labels printed on a national keyboard to these key names (so
+ * that with German labels, “z” is the key right of “t”).
+ *
+ * Label files are line-based; empty lines and lines starting with # are
+ * ignored.  The first other line must be “labels <name>”, all others are
+ * “<label> <key>”.  A backslash at the start of a label is dropped, so that
+ * “\#” is the label “#”.
+ */
+
+use crate::layout::read_data_dir;
+
+
+/* Directory with additional label sets (and overrides for built-in ones) */
+pub const LABEL_DIR: &str = "/etc/leddy/labels";
+
+const BUILTIN: [(&str, &str); 3] = [
+    ("us.labels", include_str!("../labels/us.labels")),
+    ("de.labels", include_str!("../labels/de.labels")),
+    ("fr.labels", include_str!("../labels/fr.labels")),
+];
+
+
+#[derive(Clone)]
+pub struct Labels {
+    pub name: String,
+    /* (label, key), labels in lower case */
+    pub map: Vec<(String, String)>,
+}
+
+
+impl Labels {
+    /* Parse a label file's content; @source is used in error messages */
+    pub fn parse(source: &str, text: &str) -> Result<Labels, String> {
+        let mut name = None;
+        let mut map = Vec::<(String, String)>::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+
+            if words.len() != 2 {
+                return Err(format!("{}:{}: Expected “<label> <key>”",
+                                   source, i + 1));
+            }
+
+            if name.is_none() {
+                if words[0] != "labels" {
+                    return Err(format!("{}:{}: Expected “labels <name>”",
+                                       source, i + 1));
+                }
+                name = Some(String::from(words[1]));
+                continue;
+            }
+
+            let label = words[0].strip_prefix('\\').unwrap_or(words[0])
+                            .to_lowercase();
+            if map.iter().any(|(l, _)| *l == label) {
+                return Err(format!("{}:{}: Label “{}” given twice",
+                                   source, i + 1, label));
+            }
+            map.push((label, String::from(words[1])));
+        }
+
+        match name {
+            Some(name) => Ok(Labels { name, map }),
+            None => Err(format!("{}: No label set name given", source)),
+        }
+    }
+
+    /*
+     * Key name for the given label; anything that is not a label is taken
+     * to be a key name already.
+     */
+    pub fn key<'a>(&'a self, label: &'a str) -> &'a str {
+        let lower = label.to_lowercase();
+
+        match self.map.iter().find(|(l, _)| *l == lower) {
+            Some((_, key)) => key,
+            None => label,
+        }
+    }
+
+    /* All labels for the given key */
+    pub fn labels(&self, key: &str) -> Vec<&str> {
+        self.map.iter()
+            .filter(|(_, k)| k == key)
+            .map(|(l, _)| l.as_str())
+            .collect()
+    }
+}
+
+
+/*
+ * Load the label set with the given name, from LABEL_DIR or the built-in
+ * ones (in that order).
+ */
+pub fn load(name: &str) -> Result<Labels, String> {
+    let mut available = Vec::new();
+
+    for (path, text) in read_data_dir(LABEL_DIR, "labels")? {
+        let labels = Labels::parse(&path, &text)?;
+        if labels.name == name {
+            return Ok(labels);
+        }
+        available.push(labels.name);
+    }
+
+    for (source, text) in BUILTIN {
+        let labels = Labels::parse(source, text)?;
+        if labels.name == name {
+            return Ok(labels);
+        }
+        available.push(labels.name);
+    }
+
+    available.sort();
+    available.dedup();
+    Err(format!("No label set “{}” (available: {})",
+                name, available.join(", ")))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        match Labels::parse("test", text) {
+            Ok(_) => panic!("“{}” parsed", text),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let labels = Labels::parse("test", "# comment\n\
+                                            \n\
+                                            labels xx\n\
+                                            # label key\n\
+                                            Z   y\n\
+                                            \\#  iso-backslash\n\
+                                            \\\\ backslash\n").unwrap();
+
+        assert_eq!(labels.name, "xx");
+        assert_eq!(labels.map.len(), 3);
+        assert_eq!(labels.key("z"), "y");
+        assert_eq!(labels.key("Z"), "y");
+        assert_eq!(labels.key("#"), "iso-backslash");
+        assert_eq!(labels.key("\\"), "backslash");
+        assert_eq!(labels.labels("y"), ["z"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("# nothing\n"), "test: No label set name given");
+        assert_eq!(error("z y\n"), "test:1: Expected “labels <name>”");
+        assert_eq!(error("labels xx\nz\n"),
+                   "test:2: Expected “<label> <key>”");
+        assert_eq!(error("labels xx\nz y q\n"),
+                   "test:2: Expected “<label> <key>”");
+        assert_eq!(error("labels xx\nz y\nZ x\n"),
+                   "test:3: Label “z” given twice");
+    }
+
+    #[test]
+    fn fallback() {
+        /* Anything that is not a label is a key name */
+        let labels = Labels::parse("test", "labels xx\nz y\n").unwrap();
+        assert_eq!(labels.key("y"), "y");
+        assert_eq!(labels.key("escape"), "escape");
+        assert_eq!(labels.key("no-such-key"), "no-such-key");
+        assert!(labels.labels("escape").is_empty());
+    }
+
+    #[test]
+    fn builtin() {
+        for (source, text) in BUILTIN {
+            Labels::parse(source, text).unwrap();
+        }
+
+        let de = load("de").unwrap();
+        assert_eq!(de.key("z"), "y");
+        assert_eq!(de.key("y"), "z");
+        assert_eq!(de.key("ä"), "quote");
+        assert_eq!(de.key("Ä"), "quote");
+        assert_eq!(de.key("escape"), "escape");
+
+        match load("xx") {
+            Ok(_) => panic!("Unknown label set loaded"),
+            Err(e) => assert!(e.starts_with("No label set “xx” (available: "),
+                              "{}", e),
+        }
+    }
+}
@@ -0,0 +1,500 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Keyboard layout descriptions: Which LEDs a keyboard model has, what keys
+ * they belong to, and where they are.
+ *
+ * Layout files are line-based; empty lines and lines starting with # are
+ * ignored.  Every other line is a directive:
+ *
+ *   model <id> <name>      Model identifier (for --model) and display name
+ *   pid <hex>              USB product ID of the model
+ *   layout <name>          Name of this layout (for --layout), e.g. “iso”
+ *   leds <count>           Number of LEDs in an all-keys (03) request
+ *   led <index> <key> [<x> <y> [<width> <height>]]
+ *                          An LED, the key it belongs to, and the position
+ *                          of the key's top-left corner and its size (in
+ *                          keyboard units, i.e. the width of a normal key;
+ *                          size defaults to 1×1).  LEDs without a position
+ *                          are ignored by effects that need one.
+ *   keycode <index> <code> Linux input event code (KEY_*, see
+ *                          linux/input-event-codes.h) of the key the given
+ *                          (previously declared) LED belongs to
+ */
+
+use std::fs;
+use std::path::Path;
+
+
+/* Directory with additional layouts (and overrides for the built-in ones) */
+pub const LAYOUT_DIR: &str = "/etc/leddy/layouts";
+
+/* The first layout for each model is its default */
+const BUILTIN: [(&str, &str); 4] = [
+    ("ministreak-ansi.layout",
+     include_str!("../layouts/ministreak-ansi.layout")),
+    ("ministreak-iso.layout",
+     include_str!("../layouts/ministreak-iso.layout")),
+    ("streak-ansi.layout", include_str!("../layouts/streak-ansi.layout")),
+    ("streak-iso.layout", include_str!("../layouts/streak-iso.layout")),
+];
+
+
+/* A key's area on the keyboard, in keyboard units */
+#[derive(Clone)]
+pub struct Area {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Clone)]
+pub struct Led {
+    pub index: usize,
+    pub key: String,
+    pub area: Option<Area>,
+    pub keycode: Option<u16>,
+}
+
+#[derive(Clone)]
+pub struct Layout {
+    /* e.g. “ministreak” */
+    pub model: String,
+    /* e.g. “miniSTREAK” */
+    pub model_name: String,
+    pub pid: u16,
+    /* e.g. “us” */
+    pub name: String,
+
+    pub led_count: usize,
+    pub leds: Vec<Led>,
+}
+
+
+fn parse_number(s: &str, what: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!("Invalid {} “{}”: {}", what, s, e)),
+    }
+}
+
+fn parse_coord(s: &str, what: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if x.is_finite() && x >= 0.0 => Ok(x),
+        Ok(_) => Err(format!("Invalid {} “{}”", what, s)),
+        Err(e) => Err(format!("Invalid {} “{}”: {}", what, s, e)),
+    }
+}
+
+fn parse_led(args: &[&str]) -> Result<Led, String> {
+    if args.len() < 2 || args.len() == 3 || args.len() == 5 ||
+       args.len() > 6
+    {
+        return Err(String::from("Expected “led <index> <key> [<x> <y> \
+                                 [<width> <height>]]”"));
+    }
+
+    let area =
+        if args.len() >= 4 {
+            Some(Area {
+                x: parse_coord(args[2], "x position")?,
+                y: parse_coord(args[3], "y position")?,
+                width: match args.get(4) {
+                    Some(w) => parse_coord(w, "width")?,
+                    None => 1.0,
+                },
+                height: match args.get(5) {
+                    Some(h) => parse_coord(h, "height")?,
+                    None => 1.0,
+                },
+            })
+        } else {
+            None
+        };
+
+    Ok(Led {
+        index: parse_number(args[0], "LED index")?,
+        key: String::from(args[1]),
+        area,
+        keycode: None,
+    })
+}
+
+fn parse_keycode(leds: &mut [Led], args: &[&str]) -> Result<(), String> {
+    let index = parse_number(args[0], "LED index")?;
+    let keycode =
+        match args[1].parse::<u16>() {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Invalid keycode “{}”: {}",
+                                         args[1], e)),
+        };
+
+    match leds.iter_mut().find(|l| l.index == index) {
+        Some(led) => {
+            led.keycode = Some(keycode);
+            Ok(())
+        }
+        None => Err(format!("Keycode for undeclared LED {}", index)),
+    }
+}
+
+
+impl Layout {
+    /* Parse a layout file's content; @source is used in error messages */
+    pub fn parse(source: &str, text: &str) -> Result<Layout, String> {
+        let mut model = None;
+        let mut pid = None;
+        let mut name = None;
+        let mut led_count = None;
+        let mut leds = Vec::<Led>::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+
+            let res =
+                match words[0] {
+                    "model" if words.len() >= 3 => {
+                        model = Some((String::from(words[1]),
+                                      words[2..].join(" ")));
+                        Ok(())
+                    }
+
+                    "pid" if words.len() == 2 =>
+                        match u16::from_str_radix(words[1], 16) {
+                            Ok(x) => {
+                                pid = Some(x);
+                                Ok(())
+                            }
+                            Err(e) => Err(format!("Invalid PID “{}”: {}",
+                                                  words[1], e)),
+                        },
+
+                    "layout" if words.len() == 2 => {
+                        name = Some(String::from(words[1]));
+                        Ok(())
+                    }
+
+                    "leds" if words.len() == 2 =>
+                        parse_number(words[1], "LED count")
+                            .map(|x| led_count = Some(x)),
+
+                    "led" => parse_led(&words[1..]).and_then(|led| {
+                        if leds.iter().any(|l| l.index == led.index) {
+                            Err(format!("LED {} given twice", led.index))
+                        } else {
+                            leds.push(led);
+                            Ok(())
+                        }
+                    }),
+
+                    "keycode" if words.len() == 3 =>
+                        parse_keycode(&mut leds, &words[1..]),
+
+                    x => Err(format!("Invalid directive “{}”", x)),
+                };
+
+            if let Err(e) = res {
+                return Err(format!("{}:{}: {}", source, i + 1, e));
+            }
+        }
+
+        let (model, model_name) =
+            model.ok_or_else(|| format!("{}: No model given", source))?;
+        let pid = pid.ok_or_else(|| format!("{}: No PID given", source))?;
+        let name = name.ok_or_else(|| format!("{}: No layout name given",
+                                              source))?;
+        let led_count =
+            led_count.ok_or_else(|| format!("{}: No LED count given",
+                                            source))?;
+
+        if let Some(led) = leds.iter().find(|l| l.index >= led_count) {
+            return Err(format!("{}: LED {} is out of range (only {} LEDs)",
+                               source, led.index, led_count));
+        }
+
+        Ok(Layout {
+            model,
+            model_name,
+            pid,
+            name,
+
+            led_count,
+            leds,
+        })
+    }
+
+    /* Index of the LED belonging to the given key, if there is one */
+    pub fn led(&self, key: &str) -> Option<usize> {
+        self.leds.iter().find(|l| l.key == key).map(|l| l.index)
+    }
+
+    /*
+     * The LEDs of the key with the given input event code (e.g. the space
+     * bar has several); see Led.keycode for the other direction
+     */
+    pub fn leds_by_keycode(&self, keycode: u16) -> Vec<&Led> {
+        self.leds.iter().filter(|l| l.keycode == Some(keycode)).collect()
+    }
+
+    /* Whether the keyboard (in this layout) has the given LED */
+    pub fn has_led(&self, led: usize) -> bool {
+        self.leds.iter().any(|l| l.index == led)
+    }
+
+    /* Area of the key the given LED belongs to, if it has a position */
+    pub fn area(&self, led: usize) -> Option<&Area> {
+        self.leds.iter().find(|l| l.index == led)?.area.as_ref()
+    }
+
+    /* The layout in the file format described above */
+    pub fn serialize(&self) -> String {
+        let mut text =
+            format!("model {} {}\npid {:04x}\nlayout {}\nleds {}\n\n\
+                     # index  key                 x      y      width  \
+                     height\n",
+                    self.model, self.model_name, self.pid, self.name,
+                    self.led_count);
+
+        for led in &self.leds {
+            let mut line = format!("led {:5}  {:18}", led.index, led.key);
+
+            if let Some(area) = &led.area {
+                line += &format!(" {:<6} {:<6}", area.x, area.y);
+                if area.width != 1.0 || area.height != 1.0 {
+                    line += &format!(" {:<6} {:<6}", area.width, area.height);
+                }
+            }
+
+            text += line.trim_end();
+            text.push('\n');
+        }
+
+        text += "\n# Linux input event codes (KEY_*) of the keys\n\
+                 # index  code\n";
+        for led in &self.leds {
+            if let Some(keycode) = led.keycode {
+                text += &format!("keycode {:3}  {}\n", led.index, keycode);
+            }
+        }
+
+        text
+    }
+
+    /* Width and height of the keyboard, in keyboard units */
+    pub fn size(&self) -> (f32, f32) {
+        self.leds.iter().filter_map(|l| l.area.as_ref())
+            .fold((0.0, 0.0), |(w, h), a| (f32::max(w, a.x + a.width),
+                                           f32::max(h, a.y + a.height)))
+    }
+}
+
+
+impl Area {
+    pub fn center(&self) -> (f32, f32) {
+        (self.x + self.width * 0.5, self.y + self.height * 0.5)
+    }
+}
+
+
+/*
+ * Read all files with the given extension from @dir (if it exists), sorted by
+ * name.  Returns each file's path and content.
+ */
+pub fn read_data_dir(dir: &str, ext: &str)
+    -> Result<Vec<(String, String)>, String>
+{
+    if !Path::new(dir).is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let entries =
+        match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Failed to read {}: {}", dir, e)),
+        };
+
+    let mut paths: Vec<_> =
+        entries.filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == ext))
+            .collect();
+    /* Load in a predictable order */
+    paths.sort();
+
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        match fs::read_to_string(&path) {
+            Ok(text) => files.push((path.display().to_string(), text)),
+            Err(e) => return Err(format!("Failed to read {}: {}",
+                                         path.display(), e)),
+        }
+    }
+
+    Ok(files)
+}
+
+/*
+ * Load the built-in layouts and those from LAYOUT_DIR.  A layout from
+ * LAYOUT_DIR replaces a built-in one with the same PID and name.
+ */
+pub fn load_all() -> Result<Vec<Layout>, String> {
+    let mut layouts = Vec::new();
+    for (name, text) in BUILTIN {
+        layouts.push(Layout::parse(name, text)?);
+    }
+
+    for (path, text) in read_data_dir(LAYOUT_DIR, "layout")? {
+        let layout = Layout::parse(&path, &text)?;
+
+        layouts.retain(|l| l.pid != layout.pid || l.name != layout.name);
+        layouts.push(layout);
+    }
+
+    Ok(layouts)
+}
+
+/*
+ * Find the layout with the given name for the keyboard model with the given
+ * PID (by default the first one found, i.e. a built-in one).
+ */
+pub fn find<'a>(layouts: &'a [Layout], pid: u16, name: Option<&str>)
+    -> Result<&'a Layout, String>
+{
+    let mut candidates = layouts.iter().filter(|l| l.pid == pid).peekable();
+
+    let model_name =
+        match candidates.peek() {
+            Some(l) => l.model_name.clone(),
+            None => return Err(format!("No layout for keyboards with PID \
+                                        {:04x}",
+                                       pid)),
+        };
+
+    let Some(name) = name else {
+        return Ok(candidates.next().unwrap());
+    };
+
+    let mut available = Vec::new();
+    for l in candidates {
+        if l.name == name {
+            return Ok(l);
+        }
+        available.push(l.name.as_str());
+    }
+
+    Err(format!("No layout “{}” for the {} (available: {})",
+                name, model_name, available.join(", ")))
+}
+
+/* Like find(), but look up the model by its identifier (e.g. “streak”) */
+pub fn find_model<'a>(layouts: &'a [Layout], model: &str,
+                      name: Option<&str>)
+    -> Result<&'a Layout, String>
+{
+    match layouts.iter().find(|l| l.model == model) {
+        Some(l) => find(layouts, l.pid, name),
+        None => Err(format!("Invalid keyboard model “{}”", model)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "model test Test Keyboard\n\
+                          pid 0102\n\
+                          layout ansi\n\
+                          leds 4\n";
+
+    fn error(body: &str) -> String {
+        match Layout::parse("test", &format!("{}{}", HEADER, body)) {
+            Ok(_) => panic!("“{}” parsed", body),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let layout = Layout::parse("test", &format!("{}\
+            # comment\n\
+            \n\
+            led 0  escape  0 0\n\
+            led 1  tab     0 2.5  1.5 1\n\
+            led 3  mute-mic\n\
+            keycode 1 15\n", HEADER)).unwrap();
+
+        assert_eq!(layout.model, "test");
+        assert_eq!(layout.model_name, "Test Keyboard");
+        assert_eq!(layout.pid, 0x0102);
+        assert_eq!(layout.name, "ansi");
+        assert_eq!(layout.led_count, 4);
+        assert_eq!(layout.leds.len(), 3);
+
+        assert_eq!(layout.led("tab"), Some(1));
+        assert_eq!(layout.led("space"), None);
+        assert!(!layout.has_led(2));
+
+        let tab = layout.area(1).unwrap();
+        assert_eq!((tab.x, tab.y, tab.width, tab.height), (0.0, 2.5, 1.5, 1.0));
+        let escape = layout.area(0).unwrap();
+        assert_eq!((escape.width, escape.height), (1.0, 1.0));
+        assert!(layout.area(3).is_none());
+
+        assert_eq!(layout.leds_by_keycode(15).len(), 1);
+        assert_eq!(layout.leds_by_keycode(15)[0].key, "tab");
+    }
+
+    #[test]
+    fn builtin() {
+        for (name, text) in BUILTIN {
+            Layout::parse(name, text).unwrap();
+        }
+    }
+
+    #[test]
+    fn duplicate_leds() {
+        assert_eq!(error("led 1 a\nled 1 b\n"),
+                   "test:6: LED 1 given twice");
+        assert_eq!(error("led 4 a\n"),
+                   "test: LED 4 is out of range (only 4 LEDs)");
+        assert_eq!(error("keycode 2 30\n"),
+                   "test:5: Keycode for undeclared LED 2");
+    }
+
+    #[test]
+    fn bad_positions() {
+        assert_eq!(error("led 0 a 1\n"),
+                   "test:5: Expected “led <index> <key> [<x> <y> [<width> \
+                    <height>]]”");
+        assert_eq!(error("led 0 a 1 2 3\n"),
+                   "test:5: Expected “led <index> <key> [<x> <y> [<width> \
+                    <height>]]”");
+        assert_eq!(error("led 0 a -1 0\n"),
+                   "test:5: Invalid x position “-1”");
+        assert_eq!(error("led 0 a 0 inf\n"),
+                   "test:5: Invalid y position “inf”");
+        assert!(error("led 0 a 0 0 wide 1\n")
+                    .starts_with("test:5: Invalid width “wide”: "));
+        assert!(error("led x a\n").starts_with("test:5: Invalid LED index "));
+    }
+
+    #[test]
+    fn unknown_directives() {
+        assert_eq!(error("colour 0 red\n"),
+                   "test:5: Invalid directive “colour”");
+        /* Known directives with the wrong number of arguments */
+        assert_eq!(error("pid 0102 0103\n"), "test:5: Invalid directive “pid”");
+        assert_eq!(error("keycode 1\n"),
+                   "test:5: Invalid directive “keycode”");
+
+        match Layout::parse("test", "pid 0102\nlayout ansi\nleds 4\n") {
+            Ok(_) => panic!("Layout without a model parsed"),
+            Err(e) => assert_eq!(e, "test: No model given"),
+        }
+    }
+}
@@ -2,17 +2,47 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::collections::HashMap;
-
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+mod calibrate;
+mod canvas;
+mod capabilities;
+mod config;
+mod daemon;
+mod decode;
+mod expr;
+mod heartbeat;
+mod heatmap;
+#[cfg(target_os = "linux")]
+mod input;
 mod keyboard;
+mod keyspec;
+mod labels;
+mod layout;
+mod probe;
+mod protocol;
+mod signals;
 mod software_effects;
+mod transport;
 mod types;
+mod zones;
 
+use canvas::Canvas;
+use capabilities::EffectCaps;
 use keyboard::Keyboard;
+use keyspec::KeySpec;
+use labels::Labels;
+use software_effects::SoftwareEffect;
+use software_effects::layers::{self, Layer};
+use transport::{DryRunTransport, FileTransport, Transport};
 use types::{Color, ColorParam, ColorMethods, Direction, Gradient, KeyMap};
+use zones::Zones;
 
 
 fn print_usage() {
     eprintln!("Usage: leddy [global switches] <[effect/]{{parameters...}}>
+       leddy [global switches] <subcommand> [arguments...]
 
 Effect parameters are separated by slashes.
 
@@ -26,12 +56,154 @@ Global switches are options that control leddy’s overall behavior:
 
         (Default: 1)
 
+  --list-devices
+        Lists all connected STREAK keyboards and exits.
+
+  --device=<serial|path|index>
+        Selects the keyboard to use, by its serial number, its HID path, or its
+        index as printed by --list-devices.
+
+        Multiple keyboards can be given, separated by commas, or “all” to
+        use all connected keyboards.  Effects are then applied to all of them;
+        software effects treat them as one large keyboard (see
+        --arrangement).
+
+        (Default: The first keyboard found)
+
+  --arrangement=<x:y>{{,<x:y>}}
+        Places the keyboards selected with --device on a common plane for
+        software effects: Gives the position of each keyboard’s top-left key,
+        in the order in which the keyboards were selected.  Positions are in
+        keyboard units, i.e. the width of a normal key (the miniSTREAK is
+        18.25×6.5 units large, the STREAK is 22.5×6.5).
+
+        (Default: All keyboards next to each other, from left to right)
+
+  --preview[=<seconds>]
+        Only demonstrate the given (hardware) effects for the given number of
+        seconds instead of storing them in the profile, then switch back to
+        the profile’s effect.  Without a duration, the preview is shown until
+        leddy is interrupted.
+
+  --layers
+        Instead of applying the effects one after another, stack them as
+        layers of a single software effect, the first one at the bottom.
+        Software effects, all-keys and gradient (as static key maps), and the
+        software versions of pulse, wave, reactive, rain, and fade can be
+        layers.  Every layer takes these parameters:
+          · opacity=<0..100>
+                How much the layer covers those below, in percent (default:
+                100)
+          · blend-mode=<normal|add|multiply|screen|max>
+                How the layer’s colors are combined with those below:
+                replacing them, adding up, multiplying (darkens), multiplying
+                the inverse colors (brightens), or taking the brighter color
+                channels (default: normal)
+          · zone=<zone>
+                Limits the layer to the keys in the given zone; it leaves all
+                other keys as the layers below show them
+
+  --daemon
+        Keep running and watch for keyboards being connected or disconnected.
+        Whenever that happens (or writing to a keyboard fails), reopen the
+        keyboards and apply the given effects again, resuming software effects.
+
+  --dry-run
+        Do not look for a keyboard, but print all HID reports that would be
+        sent to it (command, length, offset and payload, in hex).
+
+  --record=<file>
+        Do not look for a keyboard, but write all HID reports that would be
+        sent to it into the given file (one report per line, in hex).
+
+  --format=<csv|json>
+        Output format of “heatmap export”.
+
+        (Default: csv)
+
+  --layout=<ansi|iso|...>
+        Selects the keyboard layout, i.e. which keys the keyboard has and
+        where they are.  LEDs of keys the layout does not have are left out of
+        all software effects.  Layouts are built in or read from
+        /etc/leddy/layouts/*.layout (see README.md for the format).
+
+        (Default: From /etc/leddy/config, or ansi)
+
+  --labels=<us|de|fr|...>
+        Selects the language of the labels on the keys, so that keys can be
+        referred to by what is printed on them.  Label sets are built in or
+        read from /etc/leddy/labels/*.labels.
+
+        (Default: From /etc/leddy/config, or us)
+
+  --list-keys[=<key>,...]
+        Lists the LEDs of the layout (see --model, --layout) with their keys’
+        names, input event codes, and labels (see --labels), then exits.  If
+        keys (names, labels, or zones) are given, lists only those.
+
+  --model=<model>
+        Selects the keyboard model to emulate when no keyboard is used (i.e.,
+        with --dry-run or --record): streak, ministreak, or any other model
+        described by a layout file.
+
+        (Default: ministreak)
+
+  --replay=<file>
+        Send all HID reports recorded with --record to the keyboard, then
+        exit.
+
+
+Subcommands:
+  · decode [<file>]
+        Reads captured USB traffic from the given file (or stdin) and prints
+        the leddy command lines that have the same effect.  The input can be a
+        usbmon text dump or a file written by --record.  Commands and effects
+        that leddy does not know are shown as hex.
+
+  · raw <hex bytes...>
+        Sends the given request (starting with the command byte) to the
+        keyboard and prints the input reports it returns.  Like all requests
+        sent by leddy, “05” (set profile effect) requests are followed by
+        “13” (save) and “04 <profile>” (switch to --profile).
+
+  · probe[/parameters...]
+        Demonstrates (with “0f”) effect IDs in all given modes, one after the
+        other, and prints what was sent and the input reports returned.  Every
+        effect is assumed to take <mode> <RGB> <speed> <param>.  All values
+        are given in hex:
+          · ids=<list>
+                Effect IDs to try, e.g. 04,08-0e (default: 04,05,08,0e)
+          · modes=<list>
+                Modes to try (default: 00-03)
+          · color=RRGGBB
+                Color to use (default: ff0000)
+          · speed=<byte>
+                Speed to use (default: 32)
+          · param=<byte>
+                Last parameter byte (default: 01)
+          · pause=<seconds>
+                How long to show each combination, in decimal (default: 3)
+
+  · heatmap export
+        Prints how often each LED’s key has been pressed while the heatmap
+        effect was running (see --format), with the keys’ names from the
+        layout (see --layout).
+
+  · calibrate[/all] [<file>]
+        Lights one LED after the other; press the key each one belongs to
+        (or two keys at once to skip it).  Then writes a layout file for the
+        keyboard (or prints it) that maps the LEDs to the keys’ input event
+        codes, keeping names and positions from the current layout (see
+        --layout).  Reads key events from /dev/input, so it is only
+        available on Linux.
+          · all
+                Also try the LEDs that the current layout does not have
+
 
 Effects:
   · all-keys (default)
         Set all keys’ colors.  Effectively the same as “gradient”, unless
-        color=stdin.  Then, RGB values are read from stdin (format RRGGBB in
-        hex, separated by LF).
+        the colors are given per key (color=stdin, file:, or keys:).
 
         Parameters: color
 
@@ -58,7 +230,8 @@ Effects:
 
   · rain
         Like wave, but activate only a small number of random LEDs per
-        row/column
+        row/column (the keyboards do not support color=rainbow here, so
+        color=randomized is used instead)
 
         Parameters: color, speed, direction
 
@@ -74,6 +247,9 @@ Effects:
 
   · And custom software effects, see below.
 
+  pulse, wave, reactive, rain, and fade can also be drawn by leddy instead of
+  the keyboard (as software effects, see render= below), e.g. “sw-wave”.
+
 
 Parameters:
   · color=<color parameter>
@@ -85,13 +261,30 @@ Parameters:
         · rgb:RRGGBB
               A single color by its HTML notation
         · gradient:{{RRGGBB@index,}}
-              A gradient (up to ten colors), indices are in the [0, 100] range
-              (only works for “gradient” and “fade”)
-        · stdin (only for “all-keys”)
-              Read all keys’ colors from stdin
+              A gradient (up to ten colors, any number for software effects),
+              indices are in the [0, 100] range (only works for “gradient”,
+              “fade”, and software effects)
+        · stdin, file:<path>, keys:<spec> (only for “all-keys”)
+              Per-key colors from stdin, a file (this parameter must come
+              last), or inline.  Entries are separated by newlines or “;”;
+              “#” starts a comment:
+              · <keys>=RRGGBB: Colors the given keys (comma-separated names
+                or labels, see --list-keys and --labels), zones (see zone=),
+                or ranges (e.g. f1..f12)
+              · default=RRGGBB: Color of all other keys (default: black)
+              · RRGGBB: Colors the next LED by index, starting at 0
+              For example: keys:default=101010;wasd=00ff00;f1..f12=0000ff
+
+  · zone=<zone>
+        Limits the effect to the keys in the given zone; all other keys stay
+        off.  Works for “all-keys”, “gradient”, and software effects.
+        Built-in zones are frow, numrow, letters, wasd, modifiers, arrows,
+        nav, numpad, media, plate, and knob; more can be defined in
+        /etc/leddy/config (see README.md).
 
   · speed=<0..100>
-        Sets an effect’s speed.  Some effects may work with speeds above 100.
+        Sets an effect’s speed.  Some effects may work with speeds above 100
+        (leddy warns about them, though).
 
         (Default: 50)
 
@@ -106,11 +299,99 @@ Parameters:
 
         (Default: keydown)
 
+  · render=<hardware|software>
+        Whether the keyboard draws pulse, wave, reactive, rain, and fade, or
+        leddy does, as a software effect (same as prefixing the effect name
+        with “sw-”).  The software versions take any color parameter
+        (including per-key colors and rainbow rain); reactive is only
+        available on Linux.  They also take:
+          · angle=<degrees>
+                Direction of wave and rain, clockwise from “right”; instead
+                of direction=
+          · easing=<linear|sine|ease-in|ease-out|ease-in-out>
+                How colors and brightness change over time (default: sine
+                for pulse, linear otherwise)
+
+        (Default: hardware)
+
 
 Software effects:
+  Software effects keep running until they are interrupted (SIGINT or
+  SIGTERM) or their input ends.  The profile’s effect is then restored; when
+  interrupted, leddy exits with status 128 + <signal number>.
+
+  · expr
+        Colors every key by evaluating math expressions, e.g.
+        “expr/r=sin(x*3+t)*0.5+0.5/g=y/b=0.2”.  Expressions can use:
+          · x, y: Key position, from 0 (left, top) to 1 (right, bottom)
+          · i, n: LED index and number of LEDs
+          · t: Seconds since the effect started
+          · p: Seconds since the key was last pressed (infinite if never;
+            reads key events from /dev/input, so only available on Linux)
+          · pi, numbers, + - * ^, < > (1 if true, 0 if not), parentheses
+          · sin, cos, abs, sqrt, exp, floor, fract, min, max, mod,
+            div(a, b) (because “/” separates parameters), clamp(v[, lo,
+            hi]) (to [0, 1] by default), mix(a, b, t), noise(x[, y[, z]])
+            (smooth noise in [0, 1]), hsv(h, s, v) (h in turns), rgb(r, g,
+            b)
+
+        Parameters:
+          · r=<expr>, g=<expr>, b=<expr>
+                Red, green, and blue, each from 0 to 1 (default: 0)
+          · color=<expr>
+                The whole color, instead of r, g, and b (e.g.
+                “hsv(x+t*0.2,1,1)”)
+          · fps=<number>
+                Frames per second (default: 60)
+
+  · heatmap
+        Counts key presses and shows how often each key has been pressed,
+        from cold (rarely) to hot (often).  The counts are kept in
+        $XDG_STATE_HOME/leddy/heatmap (see “heatmap export”).  Reads key
+        events from /dev/input, so it is only available on Linux.
+
+        Parameters:
+          · color=<color parameter>
+                Palette from cold to hot: gradient: or rainbow; rgb: goes
+                from black to the given color (default: dark blue, blue,
+                red, yellow, white)
+          · decay=<hours>
+                Show recent heat: Presses count half as much after the
+                given number of hours; those older than 30 days do not
+                count (default: all presses count the same)
+
+  · ripple
+        Like reactive-ripple, but any number of ripples can be underway at
+        the same time.  Every ripple starts at the pressed key’s position.
+        Reads key events from /dev/input, so it is only available on Linux.
+
+        Parameters:
+          · color=<color parameter>
+                rgb: colors all ripples the same; rainbow and gradient: give
+                each ripple the color at its key’s horizontal position;
+                randomized: random colors; stdin, file:, keys: give each
+                ripple its key’s color (default: rainbow)
+          · speed=<0..100>
+                How fast the ripples spread (default: 50)
+          · keyup/keydown
+                Trigger event (default: keydown)
+          · radius=<keys>
+                How far the ripples spread, in key widths (default: 6)
+          · width=<keys>
+                Width of the rings, in key widths (default: 1.5)
+          · decay=<linear|quadratic|exponential>
+                How the ripples fade while spreading (default: linear)
+          · blend=<add|max|over>
+                How overlapping ripples are combined: adding their colors,
+                taking the brighter one per channel, or drawing newer ones
+                over older ones (default: add)
+          · fps=<number>
+                Frames per second (default: 60)
+
   · screen-capture
         Captures the screen (with ffmpeg) and mirrors it to the keyboard (scaled
-        down to 18×6 (miniStreak) or 22×6 (normal Streak))
+        down to the keyboard’s size, or to the size of all keyboards in use
+        (see --arrangement); every key shows the color at its center)
 
         Parameters:
           · ffmpeg-bin=<path>
@@ -132,7 +413,17 @@ Software effects:
 
   · sound-spectrum
         Expects 44100 Hz s16 little-endian mono channels from stdin, performs an
-        FFT, and visualizes the result on the keyboard.");
+        FFT, and visualizes the result on the keyboard.
+
+
+Script effects:
+  Software effects can also be written as Rhai scripts (if leddy was built
+  with the “scripting” feature): $XDG_CONFIG_HOME/leddy/effects/<name>.rhai
+  (default: ~/.config/leddy/effects) is used as the effect <name> (which must
+  not be the name of a built-in effect).  Scripts declare their parameters
+  (see README.md for the API); all of them also take:
+          · fps=<number>
+                Frames per second (default: 30)");
 }
 
 fn strip_prefix<'a>(string: &'a str, prefix: &str) -> Option<&'a str> {
@@ -143,7 +434,8 @@ fn strip_prefix<'a>(string: &'a str, prefix: &str) -> Option<&'a str> {
     }
 }
 
-fn parse_color(color_param: &str) -> Result<ColorParam, String> {
+/* Like parse_color(), but without the keyboards' limits */
+pub fn parse_sw_color(color_param: &str) -> Result<ColorParam, String> {
     if color_param == "rainbow" {
         Ok(ColorParam::Rainbow)
     } else if color_param == "random" || color_param == "randomized" {
@@ -153,13 +445,30 @@ fn parse_color(color_param: &str) -> Result<ColorParam, String> {
     } else if let Some(gradient) = strip_prefix(color_param, "gradient:") {
         Ok(ColorParam::Gradient(Gradient::from_str(gradient)?))
     } else if color_param == "stdin" {
-        Ok(ColorParam::PerKey(KeyMap::from_stdin()?))
+        Ok(ColorParam::PerKey(KeySpec::from_stdin()?))
+    } else if let Some(path) = strip_prefix(color_param, "file:") {
+        Ok(ColorParam::PerKey(KeySpec::from_file(path)?))
+    } else if let Some(spec) = strip_prefix(color_param, "keys:") {
+        Ok(ColorParam::PerKey(KeySpec::parse("keys", spec)?))
     } else {
         Err(format!("Unrecognized color parameter “{}”", color_param))
     }
 }
 
-fn parse_speed(speed_param: &str) -> Result<u8, String> {
+pub fn parse_color(color_param: &str) -> Result<ColorParam, String> {
+    let cp = parse_sw_color(color_param)?;
+
+    if let ColorParam::Gradient(g) = &cp {
+        if g.colors.len() > Gradient::MAX_COLORS {
+            return Err(String::from("Gradients cannot have more than ten \
+                                     colors (except in software effects)"));
+        }
+    }
+
+    Ok(cp)
+}
+
+pub fn parse_speed(speed_param: &str) -> Result<u8, String> {
     match speed_param.parse() {
         Ok(x) => Ok(x),
         Err(e) => Err(format!("{} is not an 8-bit unsigned integer: {}",
@@ -167,7 +476,7 @@ fn parse_speed(speed_param: &str) -> Result<u8, String> {
     }
 }
 
-fn parse_direction(dir_param: &str) -> Result<Direction, String> {
+pub fn parse_direction(dir_param: &str) -> Result<Direction, String> {
     match dir_param {
         "right" => Ok(Direction::Right),
         "left"  => Ok(Direction::Left),
@@ -178,7 +487,29 @@ fn parse_direction(dir_param: &str) -> Result<Direction, String> {
     }
 }
 
-fn parse_keyup(up_param: Option<&str>, down_param: Option<&str>)
+fn parse_arrangement(arr_param: &str) -> Result<Vec<(f32, f32)>, String> {
+    let mut positions = Vec::new();
+
+    for pos in arr_param.split(',') {
+        let coords =
+            pos.split_once(':')
+                .and_then(|(x, y)| Some((x.parse::<f32>().ok()?,
+                                         y.parse::<f32>().ok()?)))
+                .filter(|(x, y)| x.is_finite() && y.is_finite() &&
+                                 *x >= 0.0 && *y >= 0.0);
+
+        match coords {
+            Some(c) => positions.push(c),
+            None => return Err(format!("Invalid keyboard position “{}” \
+                                        (expected x:y)",
+                                       pos)),
+        }
+    }
+
+    Ok(positions)
+}
+
+pub fn parse_keyup(up_param: Option<&str>, down_param: Option<&str>)
     -> Result<bool, String>
 {
     if up_param.is_some() && down_param.is_some() {
@@ -210,22 +541,30 @@ pub fn check_superfluous_params(params: HashMap<&str, &str>)
 }
 
 
-fn do_all_keys(kbd: &Keyboard, mut params: HashMap<&str, &str>)
+fn do_all_keys(canvas: &Canvas, mut params: HashMap<&str, &str>)
     -> Result<(), String>
 {
     let cp = parse_color(params.remove("color").unwrap_or("rainbow"))?;
 
     check_superfluous_params(params)?;
 
-    match cp {
-        ColorParam::PerKey(km) => kbd.all_keys(&km),
-        _ => kbd.gradient(cp),
+    if let ColorParam::PerKey(spec) = &cp {
+        let keymaps = spec.keymaps(&canvas.keyboards)?;
+        for (kbd, keymap) in canvas.keyboards.iter().zip(keymaps) {
+            kbd.all_keys(&keymap)?;
+        }
+    } else if canvas.zoned() {
+        do_key_gradient(canvas, cp, Direction::Right)?;
+    } else {
+        for kbd in &canvas.keyboards {
+            kbd.gradient(cp.clone())?;
+        }
     }
 
     Ok(())
 }
 
-fn do_pulse(kbd: &Keyboard, mut params: HashMap<&str, &str>)
+fn do_pulse(canvas: &Canvas, mut params: HashMap<&str, &str>)
     -> Result<(), String>
 {
     let cp = parse_color(params.remove("color").unwrap_or("rainbow"))?;
@@ -233,12 +572,14 @@ fn do_pulse(kbd: &Keyboard, mut params: HashMap<&str, &str>)
 
     check_superfluous_params(params)?;
 
-    kbd.pulse(cp, speed);
+    for kbd in &canvas.keyboards {
+        kbd.pulse(cp.clone(), speed)?;
+    }
 
     Ok(())
 }
 
-fn do_wave(kbd: &Keyboard, mut params: HashMap<&str, &str>)
+fn do_wave(canvas: &Canvas, mut params: HashMap<&str, &str>)
     -> Result<(), String>
 {
     let cp = parse_color(params.remove("color").unwrap_or("rainbow"))?;
@@ -247,12 +588,14 @@ fn do_wave(kbd: &Keyboard, mut params: HashMap<&str, &str>)
 
     check_superfluous_params(params)?;
 
-    kbd.wave(cp, speed, dir);
+    for kbd in &canvas.keyboards {
+        kbd.wave(cp.clone(), speed, dir)?;
+    }
 
     Ok(())
 }
 
-fn do_reactive(kbd: &Keyboard, mut params: HashMap<&str, &str>)
+fn do_reactive(canvas: &Canvas, mut params: HashMap<&str, &str>)
     -> Result<(), String>
 {
     let cp = parse_color(params.remove("color").unwrap_or("rainbow"))?;
@@ -261,12 +604,14 @@ fn do_reactive(kbd: &Keyboard, mut params: HashMap<&str, &str>)
 
     check_superfluous_params(params)?;
 
-    kbd.reactive(cp, speed, keyup);
+    for kbd in &canvas.keyboards {
+        kbd.reactive(cp.clone(), speed, keyup)?;
+    }
 
     Ok(())
 }
 
-fn do_reactive_ripple(kbd: &Keyboard, mut params: HashMap<&str, &str>)
+fn do_reactive_ripple(canvas: &Canvas, mut params: HashMap<&str, &str>)
     -> Result<(), String>
 {
     let cp = parse_color(params.remove("color").unwrap_or("rainbow"))?;
@@ -275,12 +620,14 @@ fn do_reactive_ripple(kbd: &Keyboard, mut params: HashMap<&str, &str>)
 
     check_superfluous_params(params)?;
 
-    kbd.reactive_ripple(cp, speed, keyup);
+    for kbd in &canvas.keyboards {
+        kbd.reactive_ripple(cp.clone(), speed, keyup)?;
+    }
 
     Ok(())
 }
 
-fn do_rain(kbd: &Keyboard, mut params: HashMap<&str, &str>)
+fn do_rain(canvas: &Canvas, mut params: HashMap<&str, &str>)
     -> Result<(), String>
 {
     let cp = parse_color(params.remove("color").unwrap_or("randomized"))?;
@@ -289,79 +636,67 @@ fn do_rain(kbd: &Keyboard, mut params: HashMap<&str, &str>)
 
     check_superfluous_params(params)?;
 
-    kbd.rain(cp, speed, dir);
+    for kbd in &canvas.keyboards {
+        kbd.rain(cp.clone(), speed, dir)?;
+    }
 
     Ok(())
 }
 
-fn do_vgradient(kbd: &Keyboard, cp: ColorParam, up: bool)
-    -> Result<(), String>
+/*
+ * Sample the gradient by the keyboard's keys' positions, in the given
+ * direction
+ */
+fn key_gradient(kbd: &Keyboard, gradient: &Gradient, dir: Direction)
+    -> KeyMap
 {
-    let cv = cp.gradient().colors;
-
-    let mut raw_cv = Vec::<Color>::with_capacity(6);
-    for i in 0..6 {
-        let pos =
-            if up {
-                (5 - i) as f32 * (100.0 / 5.0)
-            } else {
-                i as f32 * (100.0 / 5.0)
-            };
-
-        let mut j = 0;
-        while j < cv.len() && (cv[j].1 as f32) < pos {
-            j += 1;
-        }
-
-        let col =
-            if j == cv.len() {
-                ((cv[j - 1].0).0,
-                 (cv[j - 1].0).1,
-                 (cv[j - 1].0).2)
-            } else if j == 0 {
-                ((cv[j].0).0,
-                 (cv[j].0).1,
-                 (cv[j].0).2)
-            } else {
-                let a = (pos - cv[j - 1].1 as f32)
-                      / (cv[j].1 - cv[j - 1].1) as f32;
-
-                (((1.0 - a) * (cv[j - 1].0).0 as f32 +
-                  a * (cv[j].0).0 as f32 + 0.5) as u8,
-                 ((1.0 - a) * (cv[j - 1].0).1 as f32 +
-                  a * (cv[j].0).1 as f32 + 0.5) as u8,
-                 ((1.0 - a) * (cv[j - 1].0).2 as f32 +
-                  a * (cv[j].0).2 as f32 + 0.5) as u8)
-            };
+    let vertical = matches!(dir, Direction::Down | Direction::Up);
+    let reverse = matches!(dir, Direction::Left | Direction::Up);
+
+    let coords: Vec<Option<f32>> =
+        (0..kbd.led_count)
+            .map(|led| kbd.led_position(led)
+                          .map(|pos| if vertical { pos.1 } else { pos.0 }))
+            .collect();
+
+    /* The first and last keys get the gradient's ends */
+    let first =
+        coords.iter().flatten().fold(f32::INFINITY, |a, b| a.min(*b));
+    let last =
+        coords.iter().flatten().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+
+    KeyMap {
+        map: coords.iter().map(|c| {
+            /* Keys without a position are treated like the first ones */
+            let pos =
+                if last > first {
+                    (c.unwrap_or(first) - first) / (last - first) * 100.0
+                } else {
+                    0.0
+                };
 
-        raw_cv.push(col);
+            gradient.sample(if reverse { 100.0 - pos } else { pos })
+        }).collect(),
     }
+}
 
-    let mut keymap = KeyMap {
-        map: Vec::with_capacity(kbd.led_count),
-    };
-
-    /* Map key index to row index (default to 0) */
-    let mut map = vec![0u8; kbd.led_count];
-    for y in 0..6 {
-        for x in 0..kbd.width {
-            let i = kbd.ledmap[y * kbd.width + x];
-            if i != 0xff {
-                map[i as usize] = y as u8;
-            }
-        }
-    }
+/*
+ * Let the keys show the gradient (instead of letting the keyboard draw it),
+ * in the given direction
+ */
+fn do_key_gradient(canvas: &Canvas, cp: ColorParam, dir: Direction)
+    -> Result<(), String>
+{
+    let gradient = cp.gradient();
 
-    for row_i in map.iter() {
-        keymap.map.push(raw_cv[*row_i as usize]);
+    for kbd in &canvas.keyboards {
+        kbd.all_keys(&key_gradient(kbd, &gradient, dir))?;
     }
 
-    kbd.all_keys(&keymap);
-
     Ok(())
 }
 
-fn do_gradient(kbd: &Keyboard, mut params: HashMap<&str, &str>)
+fn do_gradient(canvas: &Canvas, mut params: HashMap<&str, &str>)
     -> Result<(), String>
 {
     let cp = parse_color(params.remove("color").unwrap_or("rainbow"))?;
@@ -369,9 +704,16 @@ fn do_gradient(kbd: &Keyboard, mut params: HashMap<&str, &str>)
 
     check_superfluous_params(params)?;
 
+    /* The keyboard's own gradients cannot be limited to a zone */
+    if canvas.zoned() {
+        return do_key_gradient(canvas, cp, dir);
+    }
+
     match dir {
         Direction::Right => {
-            kbd.gradient(cp);
+            for kbd in &canvas.keyboards {
+                kbd.gradient(cp.clone())?;
+            }
             Ok(())
         }
 
@@ -383,23 +725,24 @@ fn do_gradient(kbd: &Keyboard, mut params: HashMap<&str, &str>)
                 col_pos.1 = 100 - col_pos.1;
             }
 
-            kbd.gradient(
-                ColorParam::Gradient(
-                    Gradient {
-                        colors: rcv
-                    }
-                )
+            let rcp = ColorParam::Gradient(
+                Gradient {
+                    colors: rcv
+                }
             );
 
+            for kbd in &canvas.keyboards {
+                kbd.gradient(rcp.clone())?;
+            }
+
             Ok(())
         }
 
-        Direction::Down => do_vgradient(kbd, cp, false),
-        Direction::Up   => do_vgradient(kbd, cp, true),
+        Direction::Down | Direction::Up => do_key_gradient(canvas, cp, dir),
     }
 }
 
-fn do_fade(kbd: &Keyboard, mut params: HashMap<&str, &str>)
+fn do_fade(canvas: &Canvas, mut params: HashMap<&str, &str>)
     -> Result<(), String>
 {
     let cp = parse_color(params.remove("color").unwrap_or("rainbow"))?;
@@ -407,21 +750,448 @@ fn do_fade(kbd: &Keyboard, mut params: HashMap<&str, &str>)
 
     check_superfluous_params(params)?;
 
-    kbd.fade(cp, speed);
+    for kbd in &canvas.keyboards {
+        kbd.fade(cp.clone(), speed)?;
+    }
 
     Ok(())
 }
 
 
-fn do_software_effect(kbd: &mut Keyboard, params: HashMap<&str, &str>,
-                      efn: fn(&Keyboard, HashMap<&str, &str>)
+/* Effects and subcommands that take precedence over script effects */
+#[cfg(feature = "scripting")]
+const BUILTIN_EFFECTS: [&str; 18] = [
+    "all-keys", "pulse", "wave", "reactive", "reactive-ripple", "rain",
+    "gradient", "fade", "expr", "heatmap", "ripple", "screen-capture",
+    "sound-spectrum", "x-key-ids", "decode", "raw", "probe", "calibrate",
+];
+
+
+/*
+ * The software version of the given hardware effect to run instead, if
+ * selected by a “sw-” prefix or render=software (see emulated.rs)
+ */
+fn software_render<'a>(name: &'a str, params: &mut HashMap<&str, &str>)
+    -> Result<Option<&'a str>, String>
+{
+    let (hw_name, prefixed) =
+        match strip_prefix(name, "sw-") {
+            Some(hw_name) => (hw_name, true),
+            None => (name, false),
+        };
+
+    if !software_effects::emulated::EFFECTS.contains(&hw_name) {
+        return Ok(None);
+    }
+
+    let software =
+        match params.remove("render") {
+            None => prefixed,
+            Some("software") => true,
+            Some("hardware") if !prefixed => false,
+            Some("hardware") =>
+                return Err(format!("{} is always rendered in software", name)),
+            Some(x) => return Err(format!("Invalid render mode “{}”", x)),
+        };
+
+    Ok(software.then_some(hw_name))
+}
+
+/*
+ * Refuse to run effects that have both a built-in and a script version,
+ * instead of silently ignoring the script
+ */
+#[cfg(feature = "scripting")]
+fn check_script_names(names: &[&str]) -> Result<(), String> {
+    for name in names {
+        let sw_name = strip_prefix(name, "sw-");
+        if !BUILTIN_EFFECTS.contains(name) &&
+           !sw_name.is_some_and(|n| software_effects::emulated::EFFECTS
+                                        .contains(&n))
+        {
+            continue;
+        }
+
+        if let Some(path) = software_effects::script::find(name) {
+            return Err(format!("{}: “{}” is a built-in effect; please \
+                                rename the script",
+                               path.display(), name));
+        }
+    }
+
+    Ok(())
+}
+
+fn do_software_effect(canvas: &mut Canvas, params: HashMap<&str, &str>,
+                      efn: fn(&Canvas, HashMap<&str, &str>)
                                -> Result<(), String>)
     -> Result<(), String>
 {
-    kbd.software_effect_start();
-    let res = efn(kbd, params);
-    kbd.software_effect_end();
-    res
+    canvas.software_effect_start();
+    let res = efn(canvas, params);
+    let end_res = canvas.software_effect_end();
+    res.and(end_res)
+}
+
+
+/*
+ * Split an effect argument (“name/key=value/flag”) into its parameters; the
+ * effect name is stored under “name”.
+ */
+fn parse_params(arg: &str) -> Result<HashMap<&str, &str>, String> {
+    let mut effect = HashMap::<&str, &str>::new();
+
+    /* File paths may contain slashes, so a file takes the rest of @arg */
+    let (params, file_param) =
+        match arg.find("=file:") {
+            Some(i) => match arg[..i].rfind('/') {
+                Some(j) => (Some(&arg[..j]), Some(&arg[(j + 1)..])),
+                None => (None, Some(arg)),
+            },
+            None => (Some(arg), None),
+        };
+
+    for param in params.into_iter().flat_map(|p| p.split('/'))
+                       .chain(file_param)
+    {
+        let mut ps = param.splitn(2, '=');
+        let pkey = ps.next().unwrap();
+
+        let old_val_opt =
+            match ps.next() {
+                Some(pval) => effect.insert(pkey, pval),
+                None => {
+                    if effect.contains_key("name") {
+                        effect.insert(pkey, "")
+                    } else {
+                        effect.insert("name", pkey)
+                    }
+                }
+            };
+
+        if let Some(old_val) = old_val_opt {
+            return Err(format!("Effect parameter “{}” already set to “{}”",
+                               pkey, old_val));
+        }
+    }
+
+    Ok(effect)
+}
+
+/*
+ * all-keys and gradient as a layer's key map (per-key colors, or the gradient
+ * across each keyboard)
+ */
+fn static_layer(canvas: &Canvas, name: &str, mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let cp = parse_sw_color(params.remove("color").unwrap_or("rainbow"))?;
+    let dir =
+        if name == "gradient" {
+            parse_direction(params.remove("direction").unwrap_or("right"))?
+        } else {
+            Direction::Right
+        };
+
+    check_superfluous_params(params)?;
+
+    let keymaps =
+        match &cp {
+            ColorParam::PerKey(spec) => spec.keymaps(&canvas.keyboards)?,
+            _ => {
+                let gradient = cp.gradient();
+                canvas.keyboards.iter()
+                    .map(|kbd| key_gradient(kbd, &gradient, dir))
+                    .collect()
+            }
+        };
+
+    Ok(layers::static_map(KeyMap {
+        map: keymaps.into_iter().flat_map(|keymap| keymap.map).collect(),
+    }))
+}
+
+/* The effect to show in a layer (see --layers) */
+fn layer_effect(canvas: &Canvas, name: &str, mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let hw_name = strip_prefix(name, "sw-").unwrap_or(name);
+    if software_effects::emulated::EFFECTS.contains(&hw_name) {
+        return match params.remove("render") {
+            None | Some("software") =>
+                software_effects::emulated(canvas, hw_name, params),
+            Some(_) => Err(String::from("Layers are always rendered in \
+                                         software")),
+        };
+    }
+
+    match name {
+        "all-keys" | "gradient" => static_layer(canvas, name, params),
+
+        "expr"              => software_effects::expr(params),
+        "heatmap"           => software_effects::heatmap(params),
+        "ripple"            => software_effects::ripple(params),
+        "screen-capture"    => software_effects::screen_capture(params),
+        "sound-spectrum"    => software_effects::sound_spectrum(params),
+
+        x => {
+            #[cfg(feature = "scripting")]
+            if let Some(path) = software_effects::script::find(x) {
+                return software_effects::script(&path, params);
+            }
+
+            Err(format!("“{}” cannot be used as a layer", x))
+        }
+    }
+}
+
+/* Stack all effects as layers (see layers.rs) and run them */
+fn apply_layers(canvas: &mut Canvas, argv: &[String]) -> Result<(), String> {
+    let mut stack = Vec::new();
+
+    for arg in argv {
+        if arg.starts_with('-') {
+            continue;
+        }
+
+        let mut params = parse_params(arg)?;
+        let name = params.remove("name").unwrap_or("all-keys");
+
+        stack.push(Layer::new(canvas, params, |params| {
+            layer_effect(canvas, name, params)
+        })?);
+    }
+
+    software_effects::run(canvas, layers::layers(stack))
+}
+
+/*
+ * Check the hardware effects in @argv against the keyboards' capabilities
+ * (see capabilities.rs), before anything is sent.  Prints warnings for
+ * parameters that are replaced or may not work.
+ */
+fn check_effects(canvas: &Canvas, argv: &[String]) -> Result<(), String> {
+    for arg in argv {
+        if arg.starts_with('-') {
+            continue;
+        }
+
+        let mut params = parse_params(arg)?;
+        let name = params.remove("name").unwrap_or("all-keys");
+
+        /* Software versions are not bound by the keyboards' capabilities */
+        if software_render(name, &mut params)?.is_some() {
+            continue;
+        }
+
+        /* Models that share the same capabilities are checked together */
+        let mut groups: Vec<(&EffectCaps, Vec<&str>)> = Vec::new();
+        for kbd in &canvas.keyboards {
+            let model = capabilities::model(&kbd.layout.model);
+            let Some(caps) = model.effect(name) else {
+                continue;
+            };
+
+            let model_name = kbd.layout.model_name.as_str();
+            match groups.iter_mut().find(|(c, _)| std::ptr::eq(*c, caps)) {
+                Some((_, names)) if names.contains(&model_name) => (),
+                Some((_, names)) => names.push(model_name),
+                None => groups.push((caps, vec![model_name])),
+            }
+        }
+
+        /* Software effects parse their parameters (e.g. expr's) themselves */
+        if groups.is_empty() {
+            continue;
+        }
+
+        /*
+         * Per-key colors are read only once, when the effect is applied;
+         * hardware effects get their average color (see ColorParam::mode())
+         */
+        let mode =
+            match params.get("color") {
+                Some(c) if *c == "stdin" || c.starts_with("file:") ||
+                           c.starts_with("keys:") =>
+                    Some(capabilities::MODE_COLOR),
+                Some(c) => Some(parse_color(c)?.mode()),
+                None => None,
+            };
+        let speed = params.get("speed").map(|s| parse_speed(s)).transpose()?;
+        let direction =
+            params.get("direction").map(|d| parse_direction(d)).transpose()?;
+
+        for (caps, mut model_names) in groups {
+            model_names.sort_unstable();
+            let model_names = model_names.join(" and ");
+
+            for warning in caps.check(&model_names, mode, speed, direction)? {
+                eprintln!("Warning: {}", warning);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_effects(canvas: &mut Canvas, argv: &[String], layers: bool)
+    -> Result<(), String>
+{
+    if layers {
+        return apply_layers(canvas, argv);
+    }
+
+    for arg in argv {
+        if arg.starts_with('-') {
+            continue;
+        }
+
+        let mut effect = parse_params(arg)?;
+        let name = effect.remove("name").unwrap_or("all-keys");
+
+        canvas.set_zone(effect.remove("zone"))?;
+        let res = apply_effect(canvas, name, effect);
+        canvas.set_zone(None)?;
+        res?;
+    }
+
+    Ok(())
+}
+
+fn apply_effect(canvas: &mut Canvas, name: &str,
+                mut effect: HashMap<&str, &str>)
+    -> Result<(), String>
+{
+    if let Some(hw_name) = software_render(name, &mut effect)? {
+        let sw_effect = software_effects::emulated(canvas, hw_name, effect)?;
+        return software_effects::run(canvas, sw_effect);
+    }
+
+    match name {
+        "all-keys"          => do_all_keys(canvas, effect),
+        "pulse"             => do_pulse(canvas, effect),
+        "wave"              => do_wave(canvas, effect),
+        "reactive"          => do_reactive(canvas, effect),
+        "reactive-ripple"   => do_reactive_ripple(canvas, effect),
+        "rain"              => do_rain(canvas, effect),
+        "gradient"          => do_gradient(canvas, effect),
+        "fade"              => do_fade(canvas, effect),
+
+        "expr" =>
+            software_effects::run(canvas, software_effects::expr(effect)?),
+
+        "heatmap" =>
+            software_effects::run(canvas, software_effects::heatmap(effect)?),
+
+        "ripple" =>
+            software_effects::run(canvas, software_effects::ripple(effect)?),
+
+        "screen-capture" =>
+            software_effects::run(canvas,
+                                  software_effects::screen_capture(effect)?),
+
+        "sound-spectrum" =>
+            software_effects::run(canvas,
+                                  software_effects::sound_spectrum(effect)?),
+
+        "x-key-ids" =>
+            software_effects::run(canvas, software_effects::x_key_ids(effect)?),
+
+        x => {
+            #[cfg(feature = "scripting")]
+            if let Some(path) = software_effects::script::find(x) {
+                return software_effects::run(canvas,
+                                             software_effects::script(&path,
+                                                                      effect)?);
+            }
+
+            Err(format!("Unrecognized effect “{}”", x))
+        }
+    }
+}
+
+
+/*
+ * Only demonstrate the given effects (without storing them in the profile),
+ * for the given duration (or forever), then switch back to the profile.
+ */
+fn do_preview(canvas: &mut Canvas, duration: Option<Duration>,
+              argv: &[String], layers: bool)
+    -> Result<(), String>
+{
+    canvas.software_effect_start();
+
+    let res = apply_effects(canvas, argv, layers).map(|_| {
+        heartbeat::with_heartbeat(canvas, || signals::sleep(duration));
+    });
+
+    let end_res = canvas.software_effect_end();
+    res.and(end_res)
+}
+
+
+fn list_devices() {
+    let devices =
+        match Keyboard::list_devices() {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+    if devices.is_empty() {
+        eprintln!("No miniSTREAK or STREAK keyboard found");
+        return;
+    }
+
+    for (i, dev) in devices.iter().enumerate() {
+        println!("{}: {}", i, dev.model_name);
+        println!("    Serial: {}", dev.serial.as_deref().unwrap_or("(none)"));
+        println!("    Path:   {}", dev.path_str());
+        println!("    Bus:    {}", dev.bus_location);
+    }
+}
+
+
+/*
+ * Print the LEDs of the given model's layout with their keys' labels; only
+ * those for the given keys (names, labels, or zones) if @filter is given.
+ */
+fn print_keys(model: &str, layout_name: Option<&str>, labels: &Labels,
+              zones: &Zones, filter: Option<&str>)
+    -> Result<(), String>
+{
+    let layouts = layout::load_all()?;
+    let layout = layout::find_model(&layouts, model, layout_name)?;
+
+    let mut leds = Vec::new();
+    for key in filter.map(zones::parse_keys).transpose()?.unwrap_or_default() {
+        match (zones.leds(&key, layout, labels),
+               layout.led(labels.key(&key)))
+        {
+            (Some(zone_leds), _) => leds.extend(zone_leds),
+            (None, Some(led)) => leds.push(led),
+            (None, None) =>
+                return Err(format!("The {} ({} layout) has no key “{}”",
+                                   layout.model_name, layout.name, key)),
+        }
+    }
+
+    println!("{} ({} layout, {} labels):",
+             layout.model_name, layout.name, labels.name);
+    for led in &layout.leds {
+        if filter.is_some() && !leds.contains(&led.index) {
+            continue;
+        }
+        let keycode = led.keycode.map(|c| c.to_string()).unwrap_or_default();
+        let line = format!("{:5}  {:18} {:4}  {}",
+                           led.index, led.key, keycode,
+                           labels.labels(&led.key).join(" "));
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
 }
 
 
@@ -429,6 +1199,28 @@ fn main() {
     /* Skip argv[0] */
     let argv: Vec<String> = std::env::args().skip(1).collect();
     let mut profile = 1;
+    let mut record_file = None;
+    let mut replay_file = None;
+    let mut dry_run = false;
+    let mut daemon = false;
+    let mut layers = false;
+    let mut preview = None;
+    let mut device = None;
+    let mut arrangement = None;
+    let mut model = "ministreak";
+    let mut list_keys = None;
+    let mut format = None;
+
+    let config =
+        match config::load() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+    let mut layout_name = config.layout.as_deref();
+    let mut labels_name = config.labels.as_deref().unwrap_or("us");
 
     /* Look for global switches before trying to open the keyboard */
     for arg in &argv {
@@ -464,12 +1256,137 @@ fn main() {
                         }
                     };
 
-                if profile < 1 || profile > 4 {
+                if !(1..=4).contains(&profile) {
                     eprintln!("Profile index must be between 1 and 4 (incl.)");
                     std::process::exit(1);
                 }
             }
 
+            "--list-devices" => {
+                list_devices();
+                std::process::exit(0);
+            }
+
+            "--list-keys" => {
+                list_keys = Some(arg_split.next());
+            }
+
+            "--device" => {
+                device =
+                    match arg_split.next() {
+                        Some(x) => Some(x),
+                        None => {
+                            eprintln!("--device requires an argument");
+                            std::process::exit(1);
+                        }
+                    };
+            }
+
+            "--arrangement" => {
+                arrangement =
+                    match parse_arrangement(arg_split.next().unwrap_or("")) {
+                        Ok(x) => Some(x),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    };
+            }
+
+            "--dry-run" => {
+                dry_run = true;
+            }
+
+            "--preview" => {
+                preview =
+                    match arg_split.next() {
+                        None => Some(None),
+                        Some(x) => match x.parse::<u64>() {
+                            Ok(secs) => Some(Some(Duration::from_secs(secs))),
+                            Err(e) => {
+                                eprintln!("{} is not a valid number of \
+                                           seconds: {}",
+                                          x, e);
+                                std::process::exit(1);
+                            }
+                        },
+                    };
+            }
+
+            "--layers" => {
+                layers = true;
+            }
+
+            "--daemon" => {
+                daemon = true;
+            }
+
+            "--record" => {
+                record_file =
+                    match arg_split.next() {
+                        Some(x) => Some(x),
+                        None => {
+                            eprintln!("--record requires an argument");
+                            std::process::exit(1);
+                        }
+                    };
+            }
+
+            "--replay" => {
+                replay_file =
+                    match arg_split.next() {
+                        Some(x) => Some(x),
+                        None => {
+                            eprintln!("--replay requires an argument");
+                            std::process::exit(1);
+                        }
+                    };
+            }
+
+            "--model" => {
+                model =
+                    match arg_split.next() {
+                        Some(x) => x,
+                        None => {
+                            eprintln!("--model requires an argument");
+                            std::process::exit(1);
+                        }
+                    };
+            }
+
+            "--layout" => {
+                layout_name =
+                    match arg_split.next() {
+                        Some(x) => Some(x),
+                        None => {
+                            eprintln!("--layout requires an argument");
+                            std::process::exit(1);
+                        }
+                    };
+            }
+
+            "--format" => {
+                format =
+                    match arg_split.next() {
+                        Some(x) => Some(x),
+                        None => {
+                            eprintln!("--format requires an argument");
+                            std::process::exit(1);
+                        }
+                    };
+            }
+
+            "--labels" => {
+                labels_name =
+                    match arg_split.next() {
+                        Some(x) => x,
+                        None => {
+                            eprintln!("--labels requires an argument");
+                            std::process::exit(1);
+                        }
+                    };
+            }
+
             x => {
                 eprintln!("Unrecognized switch “{}”", x);
                 eprintln!();
@@ -479,75 +1396,224 @@ fn main() {
         }
     }
 
-    let mut kbd = match Keyboard::new() {
-        Ok(k) => k,
-        Err(e) => {
+    let labels =
+        match labels::load(labels_name) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+    let zones =
+        match Zones::new(&config.zones) {
+            Ok(z) => z,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+    if let Some(filter) = list_keys {
+        if let Err(e) = print_keys(model, layout_name, &labels, &zones,
+                                   filter)
+        {
             eprintln!("{}", e);
             std::process::exit(1);
-        },
-    };
-
-    kbd.set_profile(profile);
+        }
+        std::process::exit(0);
+    }
 
-    for arg in &argv {
-        if arg.starts_with('-') {
-            continue;
+    let positional: Vec<&str> =
+        argv.iter()
+            .filter(|arg| !arg.starts_with('-'))
+            .map(|arg| arg.as_str())
+            .collect();
+    #[cfg(feature = "scripting")]
+    {
+        let names: Vec<&str> =
+            positional.iter().map(|arg| arg.split('/').next().unwrap())
+                      .collect();
+        if let Err(e) = check_script_names(&names) {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    }
 
-        let mut effect = HashMap::<&str, &str>::new();
+    let subcommand =
+        match positional.first().map(|arg| arg.split('/').next().unwrap()) {
+            Some(x @ ("decode" | "raw" | "probe" | "calibrate")) => Some(x),
+            Some("heatmap") if positional.get(1) == Some(&"export") =>
+                Some("heatmap export"),
+            _ => None,
+        };
+
+    if format.is_some() && subcommand != Some("heatmap export") {
+        eprintln!("--format can only be used with “heatmap export”");
+        std::process::exit(1);
+    }
 
-        for param in arg.split('/') {
-            let mut ps = param.splitn(2, '=');
-            let pkey = ps.next().unwrap();
+    if subcommand == Some("heatmap export") {
+        if positional.len() > 2 {
+            eprintln!("heatmap export takes no further arguments");
+            std::process::exit(1);
+        }
 
-            let old_val_opt =
-                match ps.next() {
-                    Some(pval) => effect.insert(pkey, pval),
-                    None => {
-                        if effect.contains_key("name") {
-                            effect.insert(pkey, "")
-                        } else {
-                            effect.insert("name", pkey)
-                        }
-                    }
-                };
+        if let Err(e) = heatmap::export(format.unwrap_or("csv"), layout_name) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
 
-            if let Some(old_val) = old_val_opt {
-                eprintln!("Effect parameter “{}” already set to “{}”",
-                          pkey, old_val);
-                std::process::exit(1);
-            }
+    if subcommand == Some("decode") {
+        if positional.len() > 2 {
+            eprintln!("decode takes at most one file");
+            std::process::exit(1);
+        }
+
+        if let Err(e) = decode::run(positional.get(1).copied()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+        std::process::exit(0);
+    }
 
+    if let Some(file) = replay_file {
         let result =
-            match effect.remove("name").unwrap_or("all-keys") {
-                "all-keys"          => do_all_keys(&kbd, effect),
-                "pulse"             => do_pulse(&kbd, effect),
-                "wave"              => do_wave(&kbd, effect),
-                "reactive"          => do_reactive(&kbd, effect),
-                "reactive-ripple"   => do_reactive_ripple(&kbd, effect),
-                "rain"              => do_rain(&kbd, effect),
-                "gradient"          => do_gradient(&kbd, effect),
-                "fade"              => do_fade(&kbd, effect),
-
-                "screen-capture" =>
-                    do_software_effect(&mut kbd, effect,
-                                       software_effects::screen_capture),
-
-                "sound-spectrum" =>
-                    do_software_effect(&mut kbd, effect,
-                                       software_effects::sound_spectrum),
-
-                "x-key-ids" =>
-                    do_software_effect(&mut kbd, effect,
-                                       software_effects::x_key_ids),
-
-                x => Err(format!("Unrecognized effect “{}”", x)),
-            };
+            Keyboard::open_devices(device)
+                .and_then(|devs|
+                    devs.iter().try_for_each(|(dev, _, _)|
+                        transport::replay(file, dev)));
 
         if let Err(e) = result {
             eprintln!("{}", e);
             std::process::exit(1);
         }
+        std::process::exit(0);
+    }
+
+    if let Err(e) = signals::install() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let open_canvas = || -> Result<Canvas, String> {
+        let kbds =
+            if dry_run || record_file.is_some() {
+                let layouts = layout::load_all()?;
+                let layout = layout::find_model(&layouts, model, layout_name)?;
+
+                let dev: Box<dyn Transport> =
+                    match record_file {
+                        Some(file) => Box::new(FileTransport::create(file)?),
+                        None => Box::new(DryRunTransport),
+                    };
+                vec![Keyboard::with_transport(dev, layout.clone(),
+                                              labels.clone(), zones.clone())]
+            } else {
+                Keyboard::open(device, layout_name, &labels, &zones)?
+            };
+
+        match &arrangement {
+            None => Ok(Canvas::side_by_side(kbds)),
+
+            Some(positions) => {
+                if positions.len() != kbds.len() {
+                    return Err(format!("--arrangement gives {} positions, \
+                                        but {} keyboards are in use",
+                                       positions.len(), kbds.len()));
+                }
+                Ok(Canvas::new(kbds, positions))
+            }
+        }
+    };
+
+    let apply = |canvas: &mut Canvas| -> Result<(), String> {
+        /* Layers are not drawn by the keyboards */
+        if !layers {
+            check_effects(canvas, &argv)?;
+        }
+        canvas.set_profile(profile)?;
+        match preview {
+            None => apply_effects(canvas, &argv, layers),
+            Some(duration) => do_preview(canvas, duration, &argv, layers),
+        }
+    };
+
+    if daemon && subcommand.is_some() {
+        eprintln!("--daemon cannot be used with subcommands");
+        std::process::exit(1);
+    }
+
+    let result =
+        match subcommand {
+            Some("raw") => probe::parse_hex(&positional[1..]).and_then(|req| {
+                let mut canvas = open_canvas()?;
+                canvas.set_profile(profile)?;
+                probe::raw(&canvas, &req)
+            }),
+
+            Some("probe") => {
+                if positional.len() > 1 {
+                    eprintln!("probe takes its parameters separated by \
+                               slashes (probe/ids=…/modes=…)");
+                    std::process::exit(1);
+                }
+
+                parse_params(positional[0]).and_then(|mut params| {
+                    params.remove("name");
+
+                    let mut canvas = open_canvas()?;
+                    canvas.set_profile(profile)?;
+                    do_software_effect(&mut canvas, params, probe::probe)
+                })
+            }
+
+            #[cfg(target_os = "linux")]
+            Some("calibrate") => {
+                if positional.len() > 2 {
+                    eprintln!("calibrate takes at most one file");
+                    std::process::exit(1);
+                }
+
+                parse_params(positional[0]).and_then(|mut params| {
+                    params.remove("name");
+                    let all = params.remove("all").is_some();
+                    check_superfluous_params(params)?;
+
+                    let mut canvas = open_canvas()?;
+                    canvas.software_effect_start();
+                    let res = calibrate::calibrate(&canvas, all,
+                                                   positional.get(1).copied());
+                    let end_res = canvas.software_effect_end();
+                    res.and(end_res)
+                })
+            }
+
+            #[cfg(not(target_os = "linux"))]
+            Some("calibrate") =>
+                Err(String::from("calibrate is only supported on Linux")),
+
+            _ if daemon => {
+                if dry_run || record_file.is_some() {
+                    eprintln!("--daemon cannot be used with --dry-run or \
+                               --record");
+                    std::process::exit(1);
+                }
+
+                daemon::run(device, open_canvas, apply)
+            }
+
+            _ => open_canvas().and_then(|mut canvas| apply(&mut canvas)),
+        };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(sig) = signals::received() {
+        std::process::exit(signals::exit_status(sig));
     }
 }
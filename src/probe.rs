@@ -0,0 +1,132 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Tools for exploring the parts of the protocol that doc.txt does not know
+ * yet.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::canvas::Canvas;
+use crate::check_superfluous_params;
+use crate::heartbeat;
+use crate::protocol::Effect;
+use crate::signals;
+use crate::transport::hex_bytes;
+use crate::types::{Color, ColorMethods};
+
+
+/* How long to wait for (further) input reports after a request */
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    match u8::from_str_radix(s, 16) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!("{} is not a hex byte: {}", s, e)),
+    }
+}
+
+/* Parse hex bytes, given in any number of arguments (e.g. “0f 0e0102”) */
+pub fn parse_hex(args: &[&str]) -> Result<Vec<u8>, String> {
+    let hex: String = args.concat().split_whitespace().collect();
+
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(format!("“{}” is not a sequence of hex bytes", hex));
+    }
+
+    (0..(hex.len() / 2)).map(|i| parse_byte(&hex[(i * 2)..(i * 2 + 2)]))
+                        .collect()
+}
+
+/* Parse a list of hex bytes and ranges, e.g. “04,05,08-0e” */
+fn parse_byte_list(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                bytes.extend(parse_byte(first)?..=parse_byte(last)?);
+            }
+
+            None => bytes.push(parse_byte(part)?),
+        }
+    }
+
+    Ok(bytes)
+}
+
+/* Print all input reports the keyboards send within READ_TIMEOUT */
+fn print_input(canvas: &Canvas) -> Result<(), String> {
+    for (i, kbd) in canvas.keyboards.iter().enumerate() {
+        while let Some(report) = kbd.read_report(READ_TIMEOUT)? {
+            println!("keyboard {} → {}", i, hex_bytes(&report));
+        }
+    }
+
+    Ok(())
+}
+
+
+/* Send an arbitrary request, and show what the keyboards reply */
+pub fn raw(canvas: &Canvas, req: &[u8]) -> Result<(), String> {
+    if req.is_empty() {
+        return Err(String::from("raw requires a request to send"));
+    }
+
+    for kbd in &canvas.keyboards {
+        kbd.send_req(req)?;
+    }
+
+    print_input(canvas)
+}
+
+/*
+ * Preview all combinations of the given effect IDs and modes, assuming the
+ * common <mode> <RGB> <speed> <param> layout, pausing after each one.
+ */
+pub fn probe(canvas: &Canvas, mut params: HashMap<&str, &str>)
+    -> Result<(), String>
+{
+    let ids = parse_byte_list(params.remove("ids").unwrap_or("04,05,08,0e"))?;
+    let modes = parse_byte_list(params.remove("modes").unwrap_or("00-03"))?;
+    let color =
+        match params.remove("color") {
+            Some(rgb) => Color::from_str(rgb)?,
+            None => Color::RED,
+        };
+    let speed = parse_byte(params.remove("speed").unwrap_or("32"))?;
+    let param = parse_byte(params.remove("param").unwrap_or("01"))?;
+    let pause =
+        match params.remove("pause").unwrap_or("3").parse::<u64>() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(e) => return Err(format!("Invalid pause: {}", e)),
+        };
+
+    check_superfluous_params(params)?;
+
+    for id in &ids {
+        for mode in &modes {
+            let effect_params =
+                vec![*mode, color.0, color.1, color.2, speed, param];
+
+            println!("effect {:02x}, mode {:02x}: 0f {:02x} {}",
+                     id, mode, id, hex_bytes(&effect_params));
+
+            for kbd in &canvas.keyboards {
+                kbd.effect(Effect::Unknown(*id, effect_params.clone()))?;
+            }
+            print_input(canvas)?;
+
+            if heartbeat::with_heartbeat(canvas,
+                                         || signals::sleep(Some(pause)))
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,538 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * The STREAK command set (see doc.txt), and how requests are split into HID
+ * reports.
+ */
+
+use crate::transport::{Report, REPORT_SIZE};
+use crate::types::{Color, Direction, Gradient};
+
+
+/* Request bytes per report (after report ID, command, length, and offset) */
+pub const CHUNK_SIZE: usize = REPORT_SIZE - 8;
+
+/* Size of a serialized gradient (color count plus ten color/position pairs) */
+pub const GRADIENT_SIZE: usize = 41;
+
+
+pub enum Command {
+    /* 04 <profile> */
+    SwitchProfile(u8),
+    /* 07: “Still alive” during preview */
+    KeepAlive,
+    /* fe: Sent along with 07 */
+    KeepAliveFe,
+    /* 13: Save changes to profile (probably) */
+    Save,
+    /* 05 <profile> 02 <effect specification> */
+    SetProfileEffect(u8, Effect),
+    /* 0f <effect specification> */
+    Preview(Effect),
+
+    /* Anything else (complete request, including the command byte) */
+    Unknown(Vec<u8>),
+}
+
+pub enum Effect {
+    /* 03 {<RGB>} */
+    AllKeys(Vec<Color>),
+    /* 06 <mode> <RGB> <speed> */
+    Pulse { mode: u8, color: Color, speed: u8 },
+    /* 07 <mode> <RGB> <speed> <direction> */
+    Wave { mode: u8, color: Color, speed: u8, direction: Direction },
+    /* 09 <mode> <RGB> <speed> <when> */
+    Reactive { mode: u8, color: Color, speed: u8, keydown: bool },
+    /* 0a <mode> <RGB> <speed> <when> */
+    Ripple { mode: u8, color: Color, speed: u8, keydown: bool },
+    /* 0b <mode> <RGB> <speed> <direction> */
+    Rain { mode: u8, color: Color, speed: u8, direction: Direction },
+    /* 0c <gradient> */
+    Gradient(Gradient),
+    /* 0d <mode> <gradient> <speed> */
+    Fade { mode: u8, gradient: Gradient, speed: u8 },
+
+    /* Any other effect ID, followed by its parameters */
+    Unknown(u8, Vec<u8>),
+}
+
+/* Collects the reports belonging to a single request */
+#[derive(Default)]
+pub struct Reassembler {
+    cmd: u8,
+    len: usize,
+    data: Vec<u8>,
+}
+
+
+fn get_u24(bytes: &[u8]) -> usize {
+    bytes[0] as usize | (bytes[1] as usize) << 8 | (bytes[2] as usize) << 16
+}
+
+fn put_u24(bytes: &mut [u8], val: usize) {
+    bytes[0] = val as u8;
+    bytes[1] = (val >> 8) as u8;
+    bytes[2] = (val >> 16) as u8;
+}
+
+fn decode_direction(val: u8) -> Result<Direction, String> {
+    match val {
+        1 => Ok(Direction::Right),
+        2 => Ok(Direction::Left),
+        3 => Ok(Direction::Down),
+        4 => Ok(Direction::Up),
+
+        x => Err(format!("Invalid direction {:#04x}", x)),
+    }
+}
+
+fn decode_when(val: u8) -> Result<bool, String> {
+    match val {
+        0 => Ok(false),
+        1 => Ok(true),
+
+        x => Err(format!("Invalid trigger {:#04x}", x)),
+    }
+}
+
+/* Check that @data has exactly @len bytes */
+fn expect_len(data: &[u8], len: usize, what: &str) -> Result<(), String> {
+    if data.len() == len {
+        Ok(())
+    } else {
+        Err(format!("{} has {} bytes instead of {}", what, data.len(), len))
+    }
+}
+
+
+/*
+ * Split a request into reports: Each report carries the command byte, the
+ * request's total length, and the offset of the chunk it contains.
+ */
+pub fn chunk(req: &[u8]) -> Vec<Report> {
+    assert!(!req.is_empty());
+
+    let mut reports = Vec::with_capacity(req.len().div_ceil(CHUNK_SIZE));
+
+    for (i, data) in req.chunks(CHUNK_SIZE).enumerate() {
+        let mut report = [0u8; REPORT_SIZE];
+
+        report[0] = 0x00;
+        report[1] = req[0];
+        put_u24(&mut report[2..5], req.len());
+        put_u24(&mut report[5..8], i * CHUNK_SIZE);
+        report[8..(8 + data.len())].copy_from_slice(data);
+
+        reports.push(report);
+    }
+
+    reports
+}
+
+/* Reassemble reports (e.g. returned by chunk()) into requests */
+#[cfg(test)]
+pub fn unchunk(reports: &[Report]) -> Result<Vec<Vec<u8>>, String> {
+    let mut reassembler = Reassembler::default();
+    let mut reqs = Vec::new();
+
+    for report in reports {
+        if let Some(req) = reassembler.push(report)? {
+            reqs.push(req);
+        }
+    }
+
+    if reassembler.pending() {
+        return Err(String::from("Incomplete request at the end"));
+    }
+
+    Ok(reqs)
+}
+
+
+impl Reassembler {
+    /* Whether a request has been started, but not completed yet */
+    pub fn pending(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    /* Add a report; returns the complete request once all parts are there */
+    pub fn push(&mut self, report: &Report) -> Result<Option<Vec<u8>>, String> {
+        let cmd = report[1];
+        let len = get_u24(&report[2..5]);
+        let ofs = get_u24(&report[5..8]);
+
+        if ofs == 0 {
+            if self.pending() {
+                let dropped = std::mem::take(&mut self.data);
+                return Err(format!("Request {:02x} incomplete ({} of {} \
+                                    bytes) when the next one started",
+                                   self.cmd, dropped.len(), self.len));
+            }
+
+            if len == 0 {
+                return Err(format!("Request {:02x} has length 0", cmd));
+            }
+
+            self.cmd = cmd;
+            self.len = len;
+        } else if !self.pending() || cmd != self.cmd || len != self.len ||
+                  ofs != self.data.len()
+        {
+            self.data.clear();
+            return Err(format!("Unexpected report for request {:02x} (length \
+                                {}, offset {})",
+                               cmd, len, ofs));
+        }
+
+        let chunk_len = (len - ofs).min(CHUNK_SIZE);
+        self.data.extend_from_slice(&report[8..(8 + chunk_len)]);
+
+        if self.data.len() < self.len {
+            Ok(None)
+        } else {
+            Ok(Some(std::mem::take(&mut self.data)))
+        }
+    }
+}
+
+
+impl Command {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Command::SwitchProfile(profile) => vec![0x04, *profile],
+            Command::KeepAlive => vec![0x07],
+            Command::KeepAliveFe => vec![0xfe],
+            Command::Save => vec![0x13],
+
+            Command::SetProfileEffect(profile, effect) => {
+                let mut req = vec![0x05, *profile, 0x02];
+                req.append(&mut effect.encode());
+                req
+            }
+
+            Command::Preview(effect) => {
+                let mut req = vec![0x0f];
+                req.append(&mut effect.encode());
+                req
+            }
+
+            Command::Unknown(req) => req.clone(),
+        }
+    }
+
+    pub fn decode(req: &[u8]) -> Result<Command, String> {
+        if req.is_empty() {
+            return Err(String::from("Empty request"));
+        }
+
+        match req[0] {
+            0x04 => {
+                expect_len(req, 2, "Switch profile request")?;
+                Ok(Command::SwitchProfile(req[1]))
+            }
+
+            0x07 if req.len() == 1 => Ok(Command::KeepAlive),
+            0xfe if req.len() == 1 => Ok(Command::KeepAliveFe),
+            0x13 if req.len() == 1 => Ok(Command::Save),
+
+            0x05 if req.len() > 3 && req[2] == 0x02 =>
+                Ok(Command::SetProfileEffect(req[1],
+                                             Effect::decode(&req[3..])?)),
+
+            0x0f if req.len() > 1 =>
+                Ok(Command::Preview(Effect::decode(&req[1..])?)),
+
+            _ => Ok(Command::Unknown(req.to_vec())),
+        }
+    }
+
+    pub fn reports(&self) -> Vec<Report> {
+        chunk(&self.encode())
+    }
+}
+
+
+impl Effect {
+    pub fn id(&self) -> u8 {
+        match self {
+            Effect::AllKeys(_) => 0x03,
+            Effect::Pulse { .. } => 0x06,
+            Effect::Wave { .. } => 0x07,
+            Effect::Reactive { .. } => 0x09,
+            Effect::Ripple { .. } => 0x0a,
+            Effect::Rain { .. } => 0x0b,
+            Effect::Gradient(_) => 0x0c,
+            Effect::Fade { .. } => 0x0d,
+
+            Effect::Unknown(id, _) => *id,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut spec = vec![self.id()];
+
+        match self {
+            Effect::AllKeys(colors) => {
+                for c in colors {
+                    spec.extend_from_slice(&[c.0, c.1, c.2]);
+                }
+            }
+
+            Effect::Pulse { mode, color, speed } =>
+                spec.extend_from_slice(&[*mode,
+                                         color.0, color.1, color.2,
+                                         *speed]),
+
+            Effect::Wave { mode, color, speed, direction } |
+            Effect::Rain { mode, color, speed, direction } =>
+                spec.extend_from_slice(&[*mode,
+                                         color.0, color.1, color.2,
+                                         *speed,
+                                         *direction as u8]),
+
+            Effect::Reactive { mode, color, speed, keydown } |
+            Effect::Ripple { mode, color, speed, keydown } =>
+                spec.extend_from_slice(&[*mode,
+                                         color.0, color.1, color.2,
+                                         *speed,
+                                         *keydown as u8]),
+
+            Effect::Gradient(gradient) => {
+                spec.resize(1 + GRADIENT_SIZE, 0);
+                gradient.serialize(&mut spec[1..]);
+            }
+
+            Effect::Fade { mode, gradient, speed } => {
+                spec.push(*mode);
+                spec.resize(2 + GRADIENT_SIZE, 0);
+                gradient.serialize(&mut spec[2..]);
+                spec.push(*speed);
+            }
+
+            Effect::Unknown(_, params) => spec.extend_from_slice(params),
+        }
+
+        spec
+    }
+
+    pub fn decode(spec: &[u8]) -> Result<Effect, String> {
+        if spec.is_empty() {
+            return Err(String::from("Empty effect specification"));
+        }
+
+        let params = &spec[1..];
+        let color = || (params[1], params[2], params[3]);
+
+        match spec[0] {
+            0x03 => {
+                if !params.len().is_multiple_of(3) {
+                    return Err(format!("All-keys colors have {} bytes, \
+                                        which is not a multiple of 3",
+                                       params.len()));
+                }
+
+                Ok(Effect::AllKeys(
+                    params.chunks(3).map(|c| (c[0], c[1], c[2])).collect()
+                ))
+            }
+
+            0x06 => {
+                expect_len(params, 5, "Pulse parameters")?;
+                Ok(Effect::Pulse {
+                    mode: params[0],
+                    color: color(),
+                    speed: params[4],
+                })
+            }
+
+            0x07 => {
+                expect_len(params, 6, "Wave parameters")?;
+                Ok(Effect::Wave {
+                    mode: params[0],
+                    color: color(),
+                    speed: params[4],
+                    direction: decode_direction(params[5])?,
+                })
+            }
+
+            0x09 => {
+                expect_len(params, 6, "Reactive parameters")?;
+                Ok(Effect::Reactive {
+                    mode: params[0],
+                    color: color(),
+                    speed: params[4],
+                    keydown: decode_when(params[5])?,
+                })
+            }
+
+            0x0a => {
+                expect_len(params, 6, "Reactive ripple parameters")?;
+                Ok(Effect::Ripple {
+                    mode: params[0],
+                    color: color(),
+                    speed: params[4],
+                    keydown: decode_when(params[5])?,
+                })
+            }
+
+            0x0b => {
+                expect_len(params, 6, "Rain parameters")?;
+                Ok(Effect::Rain {
+                    mode: params[0],
+                    color: color(),
+                    speed: params[4],
+                    direction: decode_direction(params[5])?,
+                })
+            }
+
+            0x0c => {
+                expect_len(params, GRADIENT_SIZE, "Gradient parameters")?;
+                Ok(Effect::Gradient(Gradient::deserialize(params)?))
+            }
+
+            0x0d => {
+                expect_len(params, GRADIENT_SIZE + 2, "Fade parameters")?;
+                Ok(Effect::Fade {
+                    mode: params[0],
+                    gradient: Gradient::deserialize(&params[1..])?,
+                    speed: params[GRADIENT_SIZE + 1],
+                })
+            }
+
+            id => Ok(Effect::Unknown(id, params.to_vec())),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ColorMethods;
+
+    fn round_trip(cmd: Command, expected: &[u8]) {
+        let req = cmd.encode();
+        assert_eq!(req, expected);
+
+        let reqs = unchunk(&cmd.reports()).unwrap();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0], expected);
+
+        assert_eq!(Command::decode(&reqs[0]).unwrap().encode(), expected);
+    }
+
+    #[test]
+    fn report_layout() {
+        let reports = Command::SetProfileEffect(
+            2,
+            Effect::Wave {
+                mode: 1,
+                color: Color::BLACK,
+                speed: 50,
+                direction: Direction::Up,
+            }
+        ).reports();
+
+        let mut expected = [0u8; REPORT_SIZE];
+        expected[..18].copy_from_slice(&[
+            0x00, 0x05, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x05, 0x02, 0x02, 0x07, 0x01, 0x00, 0x00, 0x00, 0x32, 0x04,
+        ]);
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0], expected);
+    }
+
+    #[test]
+    fn multi_report() {
+        let colors: Vec<Color> =
+            (0..106).map(|i| (i as u8, 0x80, 0xff - i as u8)).collect();
+        let reports = Command::Preview(Effect::AllKeys(colors)).reports();
+
+        /* 0f 03 + 106 * 3 bytes */
+        assert_eq!(reports.len(), 6);
+        for (i, report) in reports.iter().enumerate() {
+            assert_eq!(report[1], 0x0f);
+            assert_eq!(get_u24(&report[2..5]), 320);
+            assert_eq!(get_u24(&report[5..8]), i * CHUNK_SIZE);
+        }
+        assert_eq!(&reports[0][8..13], &[0x0f, 0x03, 0x00, 0x80, 0xff]);
+    }
+
+    #[test]
+    fn commands() {
+        round_trip(Command::SwitchProfile(3), &[0x04, 0x03]);
+        round_trip(Command::KeepAlive, &[0x07]);
+        round_trip(Command::KeepAliveFe, &[0xfe]);
+        round_trip(Command::Save, &[0x13]);
+        round_trip(Command::Unknown(vec![0x42, 0x01]), &[0x42, 0x01]);
+    }
+
+    #[test]
+    fn effects() {
+        let color = (0x40, 0xff, 0x00);
+
+        round_trip(Command::Preview(Effect::Pulse {
+                       mode: 0, color, speed: 20,
+                   }),
+                   &[0x0f, 0x06, 0x00, 0x40, 0xff, 0x00, 0x14]);
+
+        round_trip(Command::SetProfileEffect(1, Effect::Rain {
+                       mode: 0, color, speed: 20, direction: Direction::Down,
+                   }),
+                   &[0x05, 0x01, 0x02, 0x0b, 0x00, 0x40, 0xff, 0x00, 0x14,
+                     0x03]);
+
+        round_trip(Command::Preview(Effect::Reactive {
+                       mode: 1, color: Color::BLACK, speed: 50, keydown: true,
+                   }),
+                   &[0x0f, 0x09, 0x01, 0x00, 0x00, 0x00, 0x32, 0x01]);
+
+        round_trip(Command::Preview(Effect::Ripple {
+                       mode: 2, color: Color::BLACK, speed: 50, keydown: false,
+                   }),
+                   &[0x0f, 0x0a, 0x02, 0x00, 0x00, 0x00, 0x32, 0x00]);
+
+        round_trip(Command::Preview(Effect::Unknown(0x0e, vec![0x01, 0x02])),
+                   &[0x0f, 0x0e, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn gradients() {
+        let gradient = Gradient {
+            colors: vec![(Color::RED, 0), (Color::BLUE, 100)],
+        };
+
+        let mut expected = vec![0x0f, 0x0c, 0x02,
+                                0xff, 0x00, 0x00, 0,
+                                0x00, 0x00, 0xff, 100];
+        expected.resize(2 + GRADIENT_SIZE, 0);
+        round_trip(Command::Preview(Effect::Gradient(gradient.clone())),
+                   &expected);
+
+        let mut expected = vec![0x05, 0x04, 0x02, 0x0d, 0x03, 0x02,
+                                0xff, 0x00, 0x00, 0,
+                                0x00, 0x00, 0xff, 100];
+        expected.resize(5 + GRADIENT_SIZE, 0);
+        expected.push(80);
+        round_trip(Command::SetProfileEffect(4, Effect::Fade {
+                       mode: 3, gradient, speed: 80,
+                   }),
+                   &expected);
+    }
+
+    #[test]
+    fn bad_reports() {
+        let reports = Command::Preview(Effect::AllKeys(vec![Color::RED; 40]))
+                          .reports();
+
+        /* Missing the last report */
+        assert!(unchunk(&reports[..2]).is_err());
+        /* Missing a report in between */
+        assert!(unchunk(&[reports[0], reports[2]]).is_err());
+
+        assert!(Command::decode(&[0x0f, 0x07, 0x01]).is_err());
+        assert!(Command::decode(&[0x0f, 0x07, 0, 0, 0, 0, 0, 5]).is_err());
+    }
+}
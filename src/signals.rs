@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+
+/* Number of the last termination signal received (0 if none) */
+static RECEIVED: OnceLock<Arc<AtomicUsize>> = OnceLock::new();
+
+
+/*
+ * Catch SIGINT and SIGTERM, so that long-running effects can stop and
+ * restore the keyboard's profile instead of being killed
+ */
+pub fn install() -> Result<(), String> {
+    let received = RECEIVED.get_or_init(|| Arc::new(AtomicUsize::new(0)));
+
+    for sig in [SIGINT, SIGTERM] {
+        let res = signal_hook::flag::register_usize(sig, Arc::clone(received),
+                                                    sig as usize);
+        if let Err(e) = res {
+            return Err(format!("Failed to install signal handler: {}", e));
+        }
+    }
+
+    Ok(())
+}
+
+/* Returns the termination signal received, if any */
+pub fn received() -> Option<i32> {
+    match RECEIVED.get()?.load(Ordering::Relaxed) {
+        0 => None,
+        sig => Some(sig as i32),
+    }
+}
+
+/* Shell convention for the exit status of a process killed by a signal */
+pub fn exit_status(sig: i32) -> i32 {
+    128 + sig
+}
+
+/*
+ * Sleep for the given duration (or forever), but return early when a
+ * termination signal is received.  Returns whether that happened.
+ */
+pub fn sleep(duration: Option<Duration>) -> bool {
+    let start = Instant::now();
+
+    while received().is_none() {
+        let step =
+            match duration {
+                None => Duration::from_millis(100),
+                Some(d) => {
+                    let left = d.saturating_sub(start.elapsed());
+                    if left.is_zero() {
+                        return false;
+                    }
+                    left.min(Duration::from_millis(100))
+                }
+            };
+
+        std::thread::sleep(step);
+    }
+
+    true
+}
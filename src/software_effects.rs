@@ -1,10 +1,198 @@
 // SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+/*
+ * Software effects: leddy keeps running and draws every frame itself.  An
+ * effect only renders frames (see SoftwareEffect); run() calls it at the
+ * effect's frame rate, sends the frames to the keyboards, keeps their preview
+ * alive, and restores the profile's effect at the end.
+ */
+
+pub mod emulated;
+pub mod expr;
+pub mod heatmap;
+pub mod layers;
+pub mod ripple;
+#[cfg(feature = "scripting")]
+pub mod script;
 pub mod screen_capture;
 pub mod sound_spectrum;
 pub mod x_key_ids;
 
+pub use emulated::emulated;
+pub use expr::expr;
+pub use heatmap::heatmap;
+pub use ripple::ripple;
+#[cfg(feature = "scripting")]
+pub use script::script;
 pub use screen_capture::screen_capture;
 pub use sound_spectrum::sound_spectrum;
 pub use x_key_ids::x_key_ids;
+
+use std::time::{Duration, Instant};
+
+use crate::canvas::Canvas;
+use crate::heartbeat;
+#[cfg(target_os = "linux")]
+use crate::input::Input;
+use crate::signals;
+use crate::types::{Color, ColorMethods, KeyEvent, KeyMap};
+
+
+/* Limits for the time between frames (whatever the effect's fps is) */
+const MIN_PERIOD: Duration = Duration::from_millis(1);
+const MAX_PERIOD: Duration = Duration::from_secs(3600);
+
+
+/* What to do with a frame that SoftwareEffect::render() was asked for */
+pub enum Frame {
+    /* Send it to the keyboards */
+    Draw,
+    /*
+     * Nothing has changed (or render() has sent something to the keyboards
+     * itself), keep showing the last frame
+     */
+    Keep,
+    /* Show the profile's effect until the next Draw */
+    Release,
+    /* The effect is over (e.g. its input has ended) */
+    Done,
+}
+
+pub trait SoftwareEffect {
+    /* Frames per second */
+    fn fps(&self) -> f32 {
+        60.0
+    }
+
+    /* Whether render() needs key events (see input.rs) */
+    fn wants_input(&self) -> bool {
+        false
+    }
+
+    /* Called once, before the first frame */
+    fn init(&mut self, _canvas: &Canvas) -> Result<(), String> {
+        Ok(())
+    }
+
+    /*
+     * Render the frame at @elapsed since the first one into @frame (which is
+     * all black, one color per canvas LED), given the key events since the
+     * last frame
+     */
+    fn render(&mut self, canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, events: &[KeyEvent])
+        -> Result<Frame, String>;
+
+    /* Called once at the end (also if init() or a frame failed) */
+    fn shutdown(&mut self, _canvas: &Canvas) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+
+/* Time between frames for the given fps */
+pub fn frame_period(fps: f32) -> Duration {
+    Duration::try_from_secs_f32(1.0 / fps).unwrap_or(MAX_PERIOD)
+        .clamp(MIN_PERIOD, MAX_PERIOD)
+}
+
+/* @n times @period (Duration only multiplies by u32) */
+pub fn periods(period: Duration, n: u64) -> Duration {
+    let nanos = period.as_nanos() * n as u128;
+    Duration::new((nanos / 1_000_000_000) as u64,
+                  (nanos % 1_000_000_000) as u32)
+}
+
+/*
+ * Render frames with a fixed timestep (@elapsed advances by exactly one frame
+ * period each time; frames that are too late are skipped) until the effect is
+ * done or a termination signal is received
+ */
+fn render_loop(canvas: &Canvas, effect: &mut dyn SoftwareEffect)
+    -> Result<(), String>
+{
+    #[cfg(target_os = "linux")]
+    let input =
+        if effect.wants_input() {
+            Some(Input::open(&canvas.keyboards)?)
+        } else {
+            None
+        };
+
+    #[cfg(not(target_os = "linux"))]
+    if effect.wants_input() {
+        return Err(String::from("Key events can only be read on Linux"));
+    }
+
+    effect.init(canvas)?;
+
+    let period = frame_period(effect.fps());
+    let mut frame = KeyMap { map: vec![Color::BLACK; canvas.led_count] };
+
+    let start = Instant::now();
+    let mut frame_i = 0u64;
+    let mut last_sent = start;
+    let mut released = false;
+
+    while signals::received().is_none() {
+        #[cfg(target_os = "linux")]
+        let events =
+            match &input {
+                Some(input) => input.pending()?,
+                None => Vec::new(),
+            };
+        #[cfg(not(target_os = "linux"))]
+        let events = Vec::new();
+
+        frame.map.fill(Color::BLACK);
+
+        let elapsed = periods(period, frame_i);
+        match effect.render(canvas, &mut frame, elapsed, &events)? {
+            Frame::Draw => {
+                canvas.all_keys_raw(frame.raw())?;
+                last_sent = Instant::now();
+                released = false;
+            }
+
+            Frame::Keep => {
+                if !released && last_sent.elapsed() >= heartbeat::INTERVAL {
+                    canvas.keep_alive()?;
+                    last_sent = Instant::now();
+                }
+            }
+
+            Frame::Release => {
+                if !released {
+                    canvas.refresh_profile()?;
+                    released = true;
+                }
+            }
+
+            Frame::Done => return Ok(()),
+        }
+
+        /* The frame whose time has come (if we are late) */
+        let current_i =
+            (start.elapsed().as_secs_f64() / period.as_secs_f64()) as u64;
+        frame_i = (frame_i + 1).max(current_i);
+
+        let next = start + periods(period, frame_i);
+        signals::sleep(Some(next.saturating_duration_since(Instant::now())));
+    }
+
+    Ok(())
+}
+
+/* Run the given effect until it is done or interrupted */
+pub fn run(canvas: &mut Canvas, mut effect: Box<dyn SoftwareEffect>)
+    -> Result<(), String>
+{
+    canvas.software_effect_start();
+
+    let res = render_loop(canvas, effect.as_mut());
+    let shutdown_res = effect.shutdown(canvas);
+    let end_res = canvas.software_effect_end();
+
+    res.and(shutdown_res).and(end_res)
+}
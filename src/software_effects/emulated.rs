@@ -0,0 +1,194 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Software versions of the keyboards' own effects (selected with a “sw-”
+ * prefix or render=software).  They are not bound by the firmware's limits:
+ * Every effect takes every kind of color parameter, gradients can have any
+ * number of colors, waves and rain can go in any direction (angle=), and
+ * easing= shapes how colors and brightness change over time.
+ */
+
+pub mod fade;
+pub mod pulse;
+pub mod rain;
+pub mod reactive;
+pub mod wave;
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use crate::canvas::Canvas;
+use crate::parse_direction;
+use crate::software_effects::SoftwareEffect;
+use crate::types::{Color, ColorParam, Direction, Gradient, KeyMap};
+
+
+/* Hardware effects that have a software version */
+pub const EFFECTS: [&str; 5] = ["pulse", "wave", "rain", "reactive", "fade"];
+
+
+#[derive(Clone, Copy)]
+enum Easing {
+    Linear,
+    Sine,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+/* What the color parameter turned into */
+enum Colors {
+    Single(Color),
+    Gradient(Gradient),
+    /* Random colors from the gradient (or the gradient, for waves and fades) */
+    Random(Gradient),
+    /* One color per canvas LED */
+    PerKey(KeyMap),
+}
+
+
+fn parse_easing(easing_param: &str) -> Result<Easing, String> {
+    match easing_param {
+        "linear"        => Ok(Easing::Linear),
+        "sine"          => Ok(Easing::Sine),
+        "ease-in"       => Ok(Easing::EaseIn),
+        "ease-out"      => Ok(Easing::EaseOut),
+        "ease-in-out"   => Ok(Easing::EaseInOut),
+
+        x => Err(format!("Invalid easing “{}”", x)),
+    }
+}
+
+/*
+ * Angle (in radians) of the direction given by angle= (in degrees, clockwise
+ * from “right”) or direction=
+ */
+fn parse_angle(params: &mut HashMap<&str, &str>) -> Result<f32, String> {
+    let angle = params.remove("angle");
+    let direction = params.remove("direction");
+
+    let degrees =
+        match (angle, direction) {
+            (Some(_), Some(_)) =>
+                return Err(String::from("Cannot give both angle and \
+                                         direction")),
+
+            (Some(a), None) => match a.parse::<f32>() {
+                Ok(x) if x.is_finite() => x,
+                Ok(_) => return Err(format!("Invalid angle “{}”", a)),
+                Err(e) => return Err(format!("Invalid angle “{}”: {}",
+                                             a, e)),
+            },
+
+            (None, d) => match parse_direction(d.unwrap_or("right"))? {
+                Direction::Right => 0.0,
+                Direction::Down => 90.0,
+                Direction::Left => 180.0,
+                Direction::Up => 270.0,
+            },
+        };
+
+    Ok(degrees.to_radians())
+}
+
+/* Goes from 0 up to 1 and back down to 0 again while @t goes from 0 to 1 */
+fn triangle(t: f32) -> f32 {
+    1.0 - (2.0 * t.rem_euclid(1.0) - 1.0).abs()
+}
+
+fn scale(color: Color, factor: f32) -> Color {
+    ((color.0 as f32 * factor + 0.5) as u8,
+     (color.1 as f32 * factor + 0.5) as u8,
+     (color.2 as f32 * factor + 0.5) as u8)
+}
+
+/* Position of every canvas LED along the given direction (and across it) */
+fn project(canvas: &Canvas, angle: f32) -> Vec<Option<(f32, f32)>> {
+    let (sin, cos) = angle.sin_cos();
+
+    canvas.centers.iter()
+        .map(|c| c.map(|(x, y)| (x * cos + y * sin, y * cos - x * sin)))
+        .collect()
+}
+
+/* Lowest and highest of the given positions */
+fn extent(positions: impl Iterator<Item = f32>) -> (f32, f32) {
+    positions.fold((f32::INFINITY, f32::NEG_INFINITY),
+                   |a, p| (a.0.min(p), a.1.max(p)))
+}
+
+
+impl Easing {
+    /* Map @t from [0, 1] to [0, 1] */
+    fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+
+        match self {
+            Easing::Linear => t,
+            Easing::Sine => (1.0 - (PI * t).cos()) / 2.0,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut =>
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    1.0 - (2.0 - 2.0 * t) * (2.0 - 2.0 * t) / 2.0
+                },
+        }
+    }
+}
+
+impl Colors {
+    fn new(canvas: &Canvas, cp: ColorParam) -> Result<Self, String> {
+        Ok(match cp {
+            ColorParam::Color(c) => Colors::Single(c),
+            ColorParam::Randomized => Colors::Random(cp.gradient()),
+            ColorParam::PerKey(spec) => Colors::PerKey(KeyMap {
+                map: spec.keymaps(&canvas.keyboards)?.into_iter()
+                         .flat_map(|keymap| keymap.map)
+                         .collect(),
+            }),
+            ColorParam::Rainbow | ColorParam::Gradient(_) =>
+                Colors::Gradient(cp.gradient()),
+        })
+    }
+
+    /* Whether the colors change with the position given to at() */
+    fn is_palette(&self) -> bool {
+        matches!(self, Colors::Gradient(_) | Colors::Random(_))
+    }
+
+    /* Color of the given canvas LED at the given gradient position (0 to 1) */
+    fn at(&self, led: usize, pos: f32) -> Color {
+        match self {
+            Colors::Single(c) => *c,
+            Colors::Gradient(g) | Colors::Random(g) => g.sample(pos * 100.0),
+            Colors::PerKey(keymap) => keymap.map[led],
+        }
+    }
+
+    /* Like at(), but random ones for color=randomized */
+    fn pick(&self, led: usize, pos: f32) -> Color {
+        match self {
+            Colors::Random(g) => g.sample(rand::random::<f32>() * 100.0),
+            _ => self.at(led, pos),
+        }
+    }
+}
+
+
+/* The software version of the given hardware effect (see EFFECTS) */
+pub fn emulated(canvas: &Canvas, name: &str, params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    match name {
+        "pulse"     => pulse::pulse(canvas, params),
+        "wave"      => wave::wave(canvas, params),
+        "rain"      => rain::rain(canvas, params),
+        "reactive"  => reactive::reactive(canvas, params),
+        "fade"      => fade::fade(canvas, params),
+
+        x => Err(format!("“{}” has no software version", x)),
+    }
+}
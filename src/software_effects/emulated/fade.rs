@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * All LEDs go through the gradient together, starting over at its beginning
+ * once they have reached its end.  Single colors and per-key colors fade in
+ * and out instead.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{parse_easing, scale, triangle, Colors, Easing};
+use crate::canvas::Canvas;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{KeyEvent, KeyMap};
+use crate::{check_superfluous_params, parse_speed, parse_sw_color};
+
+
+/* Times per second to go through the gradient, per speed parameter step */
+const CYCLES_PER_SEC_PER_SPEED: f32 = 0.002;
+/* Times per second to go through the gradient for speed=0 */
+const MIN_CYCLES_PER_SEC: f32 = 0.02;
+
+
+struct Fade {
+    colors: Colors,
+    easing: Easing,
+    cycles_per_sec: f32,
+}
+
+
+impl SoftwareEffect for Fade {
+    fn render(&mut self, _canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, _events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let t = elapsed.as_secs_f32() * self.cycles_per_sec;
+
+        for (led, color) in frame.map.iter_mut().enumerate() {
+            *color =
+                if self.colors.is_palette() {
+                    self.colors.at(led, self.easing.apply(t.fract()))
+                } else {
+                    scale(self.colors.at(led, 0.0),
+                          self.easing.apply(triangle(t)))
+                };
+        }
+
+        Ok(Frame::Draw)
+    }
+}
+
+
+pub fn fade(canvas: &Canvas, mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let cp = parse_sw_color(params.remove("color").unwrap_or("rainbow"))?;
+    let speed = parse_speed(params.remove("speed").unwrap_or("50"))?;
+    let easing = parse_easing(params.remove("easing").unwrap_or("linear"))?;
+
+    check_superfluous_params(params)?;
+
+    Ok(Box::new(Fade {
+        colors: Colors::new(canvas, cp)?,
+        easing,
+        cycles_per_sec:
+            MIN_CYCLES_PER_SEC + speed as f32 * CYCLES_PER_SEC_PER_SPEED,
+    }))
+}
@@ -0,0 +1,88 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * All LEDs fade in and out together.  Gradients are gone through color by
+ * color, one per pulse; color=randomized gives every pulse a random color.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{parse_easing, scale, triangle, Colors, Easing};
+use crate::canvas::Canvas;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{Color, ColorMethods, KeyEvent, KeyMap};
+use crate::{check_superfluous_params, parse_speed, parse_sw_color};
+
+
+/* Pulses per second, per speed parameter step */
+const PULSES_PER_SEC_PER_SPEED: f32 = 0.01;
+/* Pulses per second for speed=0 */
+const MIN_PULSES_PER_SEC: f32 = 0.1;
+
+
+struct Pulse {
+    colors: Colors,
+    easing: Easing,
+    pulses_per_sec: f32,
+
+    /* Current pulse, and its color (unless per-key colors) */
+    pulse_i: Option<u64>,
+    color: Color,
+}
+
+
+impl SoftwareEffect for Pulse {
+    fn render(&mut self, _canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, _events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let t = elapsed.as_secs_f32() * self.pulses_per_sec;
+        let pulse_i = t as u64;
+
+        if self.pulse_i != Some(pulse_i) {
+            self.pulse_i = Some(pulse_i);
+            self.color =
+                match &self.colors {
+                    Colors::Gradient(g) =>
+                        g.colors[pulse_i as usize % g.colors.len()].0,
+                    colors => colors.pick(0, 0.0),
+                };
+        }
+
+        let brightness = self.easing.apply(triangle(t));
+
+        for (led, color) in frame.map.iter_mut().enumerate() {
+            let c =
+                match &self.colors {
+                    Colors::PerKey(_) => self.colors.at(led, 0.0),
+                    _ => self.color,
+                };
+            *color = scale(c, brightness);
+        }
+
+        Ok(Frame::Draw)
+    }
+}
+
+
+pub fn pulse(canvas: &Canvas, mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let cp = parse_sw_color(params.remove("color").unwrap_or("rainbow"))?;
+    let speed = parse_speed(params.remove("speed").unwrap_or("50"))?;
+    let easing = parse_easing(params.remove("easing").unwrap_or("sine"))?;
+
+    check_superfluous_params(params)?;
+
+    Ok(Box::new(Pulse {
+        colors: Colors::new(canvas, cp)?,
+        easing,
+        pulses_per_sec:
+            MIN_PULSES_PER_SEC + speed as f32 * PULSES_PER_SEC_PER_SPEED,
+
+        pulse_i: None,
+        color: Color::BLACK,
+    }))
+}
@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Drops with fading trails run over the keyboard(s) in the given direction,
+ * each in a random lane (a one key wide strip along the direction).  Drops
+ * take the gradient's color at their lane's position; color=randomized gives
+ * every drop a random color.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{extent, parse_angle, parse_easing, project, scale};
+use super::{Colors, Easing};
+use crate::canvas::Canvas;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{Color, KeyEvent, KeyMap};
+use crate::{check_superfluous_params, parse_speed, parse_sw_color};
+
+
+/* Drop speed in keyboard units per second, per speed parameter step */
+const UNITS_PER_SEC_PER_SPEED: f32 = 0.2;
+/* Drop speed for speed=0 */
+const MIN_UNITS_PER_SEC: f32 = 2.0;
+/* Length of a drop's trail, in keyboard units */
+const TRAIL: f32 = 3.0;
+/* Average number of drops underway in every lane */
+const DROPS_PER_LANE: f32 = 1.0;
+
+
+struct Drop {
+    /* Position across the direction */
+    lane: f32,
+    color: Color,
+    /* Time of the frame it was started in */
+    start: Duration,
+}
+
+struct Rain {
+    colors: Colors,
+    easing: Easing,
+    units_per_sec: f32,
+
+    /* Position of every canvas LED along and across the direction */
+    positions: Vec<Option<(f32, f32)>>,
+    /* Range of the positions along and across the direction */
+    along: (f32, f32),
+    across: (f32, f32),
+
+    drops: Vec<Drop>,
+    /* Drops to start, accumulated over the frames */
+    to_start: f32,
+    last_frame: Duration,
+}
+
+
+impl Rain {
+    fn start_drop(&mut self, start: Duration) {
+        let lane_pos = rand::random::<f32>();
+        let lane = self.across.0 - 0.5 +
+                   lane_pos * (self.across.1 - self.across.0 + 1.0);
+
+        self.drops.push(Drop {
+            lane,
+            /* Per-key colors are taken from the LEDs when drawing */
+            color: self.colors.pick(0, lane_pos),
+            start,
+        });
+    }
+}
+
+impl SoftwareEffect for Rain {
+    fn render(&mut self, _canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, _events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let lanes = self.across.1 - self.across.0 + 1.0;
+        let crossing_secs =
+            (self.along.1 - self.along.0 + TRAIL) / self.units_per_sec;
+
+        let frame_secs = (elapsed - self.last_frame).as_secs_f32();
+        self.last_frame = elapsed;
+
+        self.to_start += lanes * DROPS_PER_LANE / crossing_secs * frame_secs;
+        while self.to_start >= 1.0 {
+            self.to_start -= 1.0;
+            self.start_drop(elapsed);
+        }
+
+        let units_per_sec = self.units_per_sec;
+        let along = self.along;
+        let head = |drop: &Drop| {
+            along.0 - 0.5 +
+                (elapsed - drop.start).as_secs_f32() * units_per_sec
+        };
+
+        self.drops.retain(|drop| head(drop) - TRAIL <= along.1 + 0.5);
+
+        for (led, (color, pos)) in
+            frame.map.iter_mut().zip(&self.positions).enumerate()
+        {
+            let Some((pos_along, pos_across)) = pos else {
+                continue;
+            };
+
+            for drop in &self.drops {
+                let behind = head(drop) - pos_along;
+                if (pos_across - drop.lane).abs() >= 0.5 ||
+                   !(0.0..TRAIL).contains(&behind)
+                {
+                    continue;
+                }
+
+                let drop_color =
+                    match &self.colors {
+                        Colors::PerKey(_) => self.colors.at(led, 0.0),
+                        _ => drop.color,
+                    };
+                let c = scale(drop_color,
+                              self.easing.apply(1.0 - behind / TRAIL));
+
+                *color = (color.0.max(c.0), color.1.max(c.1),
+                          color.2.max(c.2));
+            }
+        }
+
+        Ok(Frame::Draw)
+    }
+}
+
+
+pub fn rain(canvas: &Canvas, mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let cp = parse_sw_color(params.remove("color").unwrap_or("randomized"))?;
+    let speed = parse_speed(params.remove("speed").unwrap_or("50"))?;
+    let angle = parse_angle(&mut params)?;
+    let easing = parse_easing(params.remove("easing").unwrap_or("linear"))?;
+
+    check_superfluous_params(params)?;
+
+    let positions = project(canvas, angle);
+    let along = extent(positions.iter().flatten().map(|p| p.0));
+    let across = extent(positions.iter().flatten().map(|p| p.1));
+
+    Ok(Box::new(Rain {
+        colors: Colors::new(canvas, cp)?,
+        easing,
+        units_per_sec:
+            MIN_UNITS_PER_SEC + speed as f32 * UNITS_PER_SEC_PER_SPEED,
+
+        positions,
+        along,
+        across,
+
+        drops: Vec::new(),
+        to_start: 0.0,
+        last_frame: Duration::ZERO,
+    }))
+}
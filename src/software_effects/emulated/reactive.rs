@@ -0,0 +1,125 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Pressed keys light up and fade out again.  Gradients color the keys by
+ * their horizontal position; color=randomized gives every press a random
+ * color.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{parse_easing, scale, Colors, Easing};
+use crate::canvas::Canvas;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{Color, KeyEvent, KeyMap};
+use crate::{check_superfluous_params, parse_keyup, parse_speed};
+use crate::parse_sw_color;
+
+
+/* Seconds until a key is dark again for speed=0 */
+const MAX_FADE_SECS: f32 = 3.0;
+/* Seconds less per speed parameter step */
+const FADE_SECS_PER_SPEED: f32 = 0.025;
+/* Shortest fade (for speeds above 100) */
+const MIN_FADE_SECS: f32 = 0.1;
+
+
+struct Reactive {
+    colors: Colors,
+    easing: Easing,
+    keyup: bool,
+    fade_secs: f32,
+
+    /* When each canvas LED was lit, and in which color */
+    lit: Vec<Option<(Duration, Color)>>,
+    /* Whether the last frame sent had any lit LEDs on it */
+    drawn: bool,
+}
+
+
+impl SoftwareEffect for Reactive {
+    fn wants_input(&self) -> bool {
+        true
+    }
+
+    fn render(&mut self, canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        for ev in events {
+            if ev.down == self.keyup {
+                continue;
+            }
+
+            let leds: Vec<usize> =
+                canvas.keyboards[ev.keyboard].layout.leds_by_keycode(ev.code)
+                    .iter().map(|l| canvas.led(ev.keyboard, l.index))
+                    .collect();
+
+            /* One random color for all of the key's LEDs */
+            let press_color = self.colors.pick(0, 0.0);
+
+            for led in leds {
+                let pos =
+                    canvas.centers[led].map_or(0.0, |c| c.0 / canvas.width);
+                let color =
+                    match &self.colors {
+                        Colors::Random(_) => press_color,
+                        colors => colors.at(led, pos),
+                    };
+                self.lit[led] = Some((elapsed, color));
+            }
+        }
+
+        let mut any_lit = false;
+        for (color, lit) in frame.map.iter_mut().zip(&mut self.lit) {
+            let Some((start, c)) = *lit else {
+                continue;
+            };
+
+            let t = (elapsed - start).as_secs_f32() / self.fade_secs;
+            if t >= 1.0 {
+                *lit = None;
+                continue;
+            }
+
+            *color = scale(c, 1.0 - self.easing.apply(t));
+            any_lit = true;
+        }
+
+        /* Send one black frame once everything has faded */
+        if !any_lit && !self.drawn {
+            return Ok(Frame::Keep);
+        }
+        self.drawn = any_lit;
+
+        Ok(Frame::Draw)
+    }
+}
+
+
+pub fn reactive(canvas: &Canvas, mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let cp = parse_sw_color(params.remove("color").unwrap_or("rainbow"))?;
+    let speed = parse_speed(params.remove("speed").unwrap_or("50"))?;
+    let keyup = parse_keyup(params.remove("keyup"), params.remove("keydown"))?;
+    let easing = parse_easing(params.remove("easing").unwrap_or("linear"))?;
+
+    check_superfluous_params(params)?;
+
+    let fade_secs =
+        (MAX_FADE_SECS - speed as f32 * FADE_SECS_PER_SPEED).max(MIN_FADE_SECS);
+
+    Ok(Box::new(Reactive {
+        colors: Colors::new(canvas, cp)?,
+        easing,
+        keyup,
+        fade_secs,
+
+        lit: vec![None; canvas.led_count],
+        drawn: false,
+    }))
+}
@@ -0,0 +1,96 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * The gradient rolls over the keyboard(s) in the given direction, spanning
+ * all of them.  Single colors and per-key colors roll over as a band of light
+ * instead.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{extent, parse_angle, parse_easing, project, scale, triangle};
+use super::{Colors, Easing};
+use crate::canvas::Canvas;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{KeyEvent, KeyMap};
+use crate::{check_superfluous_params, parse_speed, parse_sw_color};
+
+
+/* Wave speed in keyboard units per second, per speed parameter step */
+const UNITS_PER_SEC_PER_SPEED: f32 = 0.2;
+/* Wave speed for speed=0 */
+const MIN_UNITS_PER_SEC: f32 = 1.0;
+
+
+struct Wave {
+    colors: Colors,
+    easing: Easing,
+    units_per_sec: f32,
+
+    /* Position of every canvas LED along the wave's direction */
+    positions: Vec<Option<f32>>,
+    /* Where the wave starts, and the distance over which it repeats */
+    first: f32,
+    length: f32,
+}
+
+
+impl SoftwareEffect for Wave {
+    fn render(&mut self, _canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, _events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let shift = elapsed.as_secs_f32() * self.units_per_sec;
+
+        for (led, (color, pos)) in
+            frame.map.iter_mut().zip(&self.positions).enumerate()
+        {
+            let Some(pos) = pos else {
+                continue;
+            };
+
+            let phase =
+                ((pos - self.first - shift) / self.length).rem_euclid(1.0);
+
+            *color =
+                if self.colors.is_palette() {
+                    self.colors.at(led, self.easing.apply(phase))
+                } else {
+                    scale(self.colors.at(led, 0.0),
+                          self.easing.apply(triangle(phase)))
+                };
+        }
+
+        Ok(Frame::Draw)
+    }
+}
+
+
+pub fn wave(canvas: &Canvas, mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let cp = parse_sw_color(params.remove("color").unwrap_or("rainbow"))?;
+    let speed = parse_speed(params.remove("speed").unwrap_or("50"))?;
+    let angle = parse_angle(&mut params)?;
+    let easing = parse_easing(params.remove("easing").unwrap_or("linear"))?;
+
+    check_superfluous_params(params)?;
+
+    let positions: Vec<Option<f32>> =
+        project(canvas, angle).into_iter().map(|p| p.map(|p| p.0)).collect();
+    let (first, last) = extent(positions.iter().flatten().copied());
+
+    Ok(Box::new(Wave {
+        colors: Colors::new(canvas, cp)?,
+        easing,
+        units_per_sec:
+            MIN_UNITS_PER_SEC + speed as f32 * UNITS_PER_SEC_PER_SPEED,
+
+        positions,
+        first,
+        /* One key more, so the first and the last key differ */
+        length: (last - first).max(0.0) + 1.0,
+    }))
+}
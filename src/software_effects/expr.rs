@@ -0,0 +1,205 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Colors every key according to math expressions (see expr.rs), evaluated
+ * per LED and frame: Either one for each channel (r=, g=, b=), or one for the
+ * whole color (color=, e.g. “hsv(x+t*0.25,1,1)”).
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::canvas::Canvas;
+use crate::check_superfluous_params;
+use crate::expr::{Expr, Value};
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{KeyEvent, KeyMap};
+
+
+/*
+ * Variables the expressions can use: Key position (0 to 1 from left to right
+ * and top to bottom), LED index and count, seconds since the effect started,
+ * and seconds since the key was last pressed (infinite if never)
+ */
+const VARIABLES: [&str; 6] = ["x", "y", "i", "n", "t", "p"];
+const VAR_T: usize = 4;
+const VAR_P: usize = 5;
+
+
+enum Channels {
+    Separate(Option<Expr>, Option<Expr>, Option<Expr>),
+    Color(Expr),
+}
+
+struct ExprEffect {
+    channels: Channels,
+    fps: f32,
+
+    /* Time of the last press per canvas LED */
+    pressed: Vec<Option<Duration>>,
+    /* Whether the frame can change after the first one */
+    animated: bool,
+    drawn: bool,
+}
+
+
+fn parse_channel(params: &mut HashMap<&str, &str>, name: &str)
+    -> Result<Option<Expr>, String>
+{
+    let Some(text) = params.remove(name) else {
+        return Ok(None);
+    };
+
+    let expr = Expr::parse(text, &VARIABLES)?;
+    if expr.is_color() {
+        return Err(format!("{}= must be a number, not a color (use color= \
+                            for colors)",
+                           name));
+    }
+    Ok(Some(expr))
+}
+
+/* Color channel value (NaN is 0) */
+fn channel(value: f32) -> u8 {
+    if value.is_nan() {
+        0
+    } else {
+        (value.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+    }
+}
+
+
+impl Channels {
+    fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Channels::Separate(r, g, b) =>
+                [r, g, b].into_iter().flatten().collect(),
+            Channels::Color(c) => vec![c],
+        }
+    }
+
+    fn eval(&self, variables: &[f32]) -> (f32, f32, f32) {
+        let number = |e: &Option<Expr>| match e {
+            None => 0.0,
+            Some(e) => match e.eval(variables) {
+                Value::Number(x) => x,
+                Value::Color(r, _, _) => r,
+            },
+        };
+
+        match self {
+            Channels::Separate(r, g, b) => (number(r), number(g), number(b)),
+            Channels::Color(c) => match c.eval(variables) {
+                Value::Number(x) => (x, x, x),
+                Value::Color(r, g, b) => (r, g, b),
+            },
+        }
+    }
+}
+
+
+impl SoftwareEffect for ExprEffect {
+    fn fps(&self) -> f32 {
+        self.fps
+    }
+
+    fn wants_input(&self) -> bool {
+        self.channels.exprs().iter().any(|e| e.uses(VAR_P))
+    }
+
+    fn init(&mut self, canvas: &Canvas) -> Result<(), String> {
+        self.pressed = vec![None; canvas.led_count];
+        Ok(())
+    }
+
+    fn render(&mut self, canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        for ev in events.iter().filter(|ev| ev.down) {
+            for led in canvas.keyboards[ev.keyboard].layout
+                             .leds_by_keycode(ev.code)
+            {
+                self.pressed[canvas.led(ev.keyboard, led.index)] =
+                    Some(elapsed);
+            }
+        }
+
+        if self.drawn && !self.animated {
+            return Ok(Frame::Keep);
+        }
+
+        let t = elapsed.as_secs_f32();
+        let n = canvas.led_count as f32;
+
+        for (i, (color, center)) in frame.map.iter_mut()
+            .zip(&canvas.centers).enumerate()
+        {
+            let Some((x, y)) = center else {
+                continue;
+            };
+
+            let p =
+                match self.pressed[i] {
+                    Some(pressed) => (elapsed - pressed).as_secs_f32(),
+                    None => f32::INFINITY,
+                };
+            let variables =
+                [x / canvas.width, y / canvas.height, i as f32, n, t, p];
+
+            let (r, g, b) = self.channels.eval(&variables);
+            *color = (channel(r), channel(g), channel(b));
+        }
+
+        self.drawn = true;
+        Ok(Frame::Draw)
+    }
+}
+
+
+pub fn expr(mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let r = parse_channel(&mut params, "r")?;
+    let g = parse_channel(&mut params, "g")?;
+    let b = parse_channel(&mut params, "b")?;
+    let color = params.remove("color");
+
+    let channels =
+        match (color, r.is_some() || g.is_some() || b.is_some()) {
+            (Some(_), true) =>
+                return Err(String::from("Cannot give both color and r, g, \
+                                         or b")),
+            (Some(c), false) => Channels::Color(Expr::parse(c, &VARIABLES)?),
+            (None, true) => Channels::Separate(r, g, b),
+            (None, false) =>
+                return Err(String::from("Need an expression (color=, or r=, \
+                                         g=, and b=)")),
+        };
+
+    let fps =
+        match params.remove("fps") {
+            None => 60.0,
+            Some(f) => match f.parse::<f32>() {
+                Ok(x) if x.is_finite() && x > 0.0 => x,
+                Ok(_) => return Err(format!("Invalid fps value “{}”", f)),
+                Err(e) => return Err(format!("Invalid fps value “{}”: {}",
+                                             f, e)),
+            },
+        };
+
+    check_superfluous_params(params)?;
+
+    let animated =
+        channels.exprs().iter().any(|e| e.uses(VAR_T) || e.uses(VAR_P));
+
+    Ok(Box::new(ExprEffect {
+        channels,
+        fps,
+
+        pressed: Vec::new(),
+        animated,
+        drawn: false,
+    }))
+}
@@ -0,0 +1,173 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Counts key presses per LED and shows them as a heat map (see heatmap.rs
+ * for how the counts are stored)
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::canvas::Canvas;
+use crate::heatmap::{self, Heatmap};
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{Color, ColorMethods, ColorParam, Gradient, KeyEvent, KeyMap};
+use crate::{check_superfluous_params, parse_sw_color};
+
+
+/* Default palette: cold (dark blue) to hot (white) */
+const HEAT_GRADIENT: &str = "000010@0,0000ff@20,ff0000@50,ffff00@80,ffffff@100";
+/*
+ * How often to write new counts to the statistics file (which is re-read and
+ * rewritten every time; counts are also written when the effect ends)
+ */
+const SAVE_INTERVAL: Duration = Duration::from_secs(600);
+/* Limits redraws while typing (this is the frame period) */
+const DRAW_INTERVAL: Duration = Duration::from_millis(100);
+/* How often to redraw to show decaying heat */
+const DECAY_DRAW_INTERVAL: Duration = Duration::from_secs(10);
+
+
+struct HeatmapEffect {
+    gradient: Gradient,
+    half_life: Option<f64>,
+
+    /* Counts from the statistics file, and those not written there yet */
+    stored: Heatmap,
+    unsaved: Heatmap,
+
+    last_save: Duration,
+    last_draw: Option<Duration>,
+    dirty: bool,
+}
+
+
+impl HeatmapEffect {
+    fn draw(&self, canvas: &Canvas, frame: &mut KeyMap) {
+        let mut heat = vec![0.0; canvas.led_count];
+
+        for (kbd_i, kbd) in canvas.keyboards.iter().enumerate() {
+            for hm in [&self.stored, &self.unsaved] {
+                for (led, h) in hm.heat(&kbd.layout.model, self.half_life) {
+                    if led < kbd.led_count {
+                        heat[canvas.led(kbd_i, led)] += h;
+                    }
+                }
+            }
+        }
+
+        let max = heat.iter().copied().fold(0.0, f64::max);
+
+        for (color, h) in frame.map.iter_mut().zip(&heat) {
+            let pos = if max > 0.0 { (h / max * 100.0) as f32 } else { 0.0 };
+            *color = self.gradient.sample(pos);
+        }
+    }
+}
+
+impl SoftwareEffect for HeatmapEffect {
+    /* Only for reading key events, frames are drawn far less often */
+    fn fps(&self) -> f32 {
+        1.0 / DRAW_INTERVAL.as_secs_f32()
+    }
+
+    fn wants_input(&self) -> bool {
+        true
+    }
+
+    fn init(&mut self, _canvas: &Canvas) -> Result<(), String> {
+        self.stored = Heatmap::load()?;
+        Ok(())
+    }
+
+    fn render(&mut self, canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        for ev in events.iter().filter(|ev| ev.down) {
+            let kbd = &canvas.keyboards[ev.keyboard];
+            let hour = heatmap::now_hours() as u64;
+
+            for led in kbd.layout.leds_by_keycode(ev.code) {
+                self.unsaved.add(&kbd.layout.model, led.index, hour, 1);
+                self.dirty = true;
+            }
+        }
+
+        if elapsed - self.last_save >= SAVE_INTERVAL {
+            if !self.unsaved.is_empty() {
+                self.stored = self.unsaved.save()?;
+                self.unsaved = Heatmap::default();
+            }
+            self.last_save = elapsed;
+        }
+
+        let redraw =
+            match self.last_draw {
+                None => true,
+                Some(t) => self.dirty ||
+                           (self.half_life.is_some() &&
+                            elapsed - t >= DECAY_DRAW_INTERVAL),
+            };
+        if !redraw {
+            return Ok(Frame::Keep);
+        }
+
+        self.draw(canvas, frame);
+        self.last_draw = Some(elapsed);
+        self.dirty = false;
+
+        Ok(Frame::Draw)
+    }
+
+    /* Keep what was counted, even if the keyboard is gone */
+    fn shutdown(&mut self, _canvas: &Canvas) -> Result<(), String> {
+        if !self.unsaved.is_empty() {
+            self.unsaved.save()?;
+            self.unsaved = Heatmap::default();
+        }
+        Ok(())
+    }
+}
+
+
+pub fn heatmap(mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let gradient =
+        match params.remove("color").map(parse_sw_color).transpose()? {
+            None => Gradient::from_str(HEAT_GRADIENT)?,
+            Some(ColorParam::Color(c)) => Gradient {
+                colors: vec![(Color::BLACK, 0), (c, 100)],
+            },
+            Some(ColorParam::PerKey(_)) =>
+                return Err(String::from("heatmap does not support per-key \
+                                         colors")),
+            Some(cp) => cp.gradient(),
+        };
+    let half_life =
+        match params.remove("decay") {
+            None => None,
+            Some(hl) => match hl.parse::<f64>() {
+                Ok(x) if x.is_finite() && x > 0.0 => Some(x),
+                Ok(_) => return Err(format!("Invalid decay “{}”", hl)),
+                Err(e) => return Err(format!("Invalid decay “{}”: {}",
+                                             hl, e)),
+            },
+        };
+
+    check_superfluous_params(params)?;
+
+    Ok(Box::new(HeatmapEffect {
+        gradient,
+        half_life,
+
+        stored: Heatmap::default(),
+        unsaved: Heatmap::default(),
+
+        last_save: Duration::ZERO,
+        last_draw: None,
+        dirty: false,
+    }))
+}
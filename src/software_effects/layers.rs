@@ -0,0 +1,307 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Stacks software effects (and static key maps) as layers (see --layers):
+ * Each layer is rendered into its own frame, at its own fps (with its own
+ * fixed timestep, keeping its last frame in between).  Whenever one of them
+ * has changed, the frames are blended onto each other from the bottom up,
+ * starting from black.  A layer only covers the LEDs in its zone (if it has
+ * one), and its opacity scales how much it changes the layers below.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::canvas::Canvas;
+use crate::software_effects::{frame_period, periods, Frame, SoftwareEffect};
+use crate::types::{Color, ColorMethods, KeyEvent, KeyMap};
+
+
+#[derive(Clone, Copy)]
+enum BlendMode {
+    /* The layer's colors replace those below */
+    Normal,
+    /* Add up the colors */
+    Add,
+    /* Multiply the colors (darkens; black hides everything below) */
+    Multiply,
+    /* Inverse of multiplying the inverse colors (brightens) */
+    Screen,
+    /* Take the brighter color channels */
+    Max,
+}
+
+pub struct Layer {
+    effect: Box<dyn SoftwareEffect>,
+    mode: BlendMode,
+    /* In [0, 1] */
+    opacity: f32,
+    /* Canvas LEDs the layer covers (all if None) */
+    mask: Option<Vec<bool>>,
+
+    /* Last frame the effect has drawn */
+    frame: KeyMap,
+    /* Whether that frame is to be shown (see Frame::Release) */
+    visible: bool,
+
+    /* The effect's own frame period, and the next frame to render */
+    period: Duration,
+    frame_i: u64,
+    /* Key events since the effect's last frame */
+    events: Vec<KeyEvent>,
+}
+
+struct Layers {
+    layers: Vec<Layer>,
+}
+
+/* A key map that does not change */
+struct StaticMap {
+    map: KeyMap,
+    drawn: bool,
+}
+
+
+fn parse_blend_mode(mode_param: &str) -> Result<BlendMode, String> {
+    match mode_param {
+        "normal"    => Ok(BlendMode::Normal),
+        "add"       => Ok(BlendMode::Add),
+        "multiply"  => Ok(BlendMode::Multiply),
+        "screen"    => Ok(BlendMode::Screen),
+        "max"       => Ok(BlendMode::Max),
+
+        x => Err(format!("Invalid blend mode “{}”", x)),
+    }
+}
+
+
+impl BlendMode {
+    /* Blend one color channel (in [0, 1]) of a layer onto the one below */
+    fn apply(self, below: f32, layer: f32) -> f32 {
+        match self {
+            BlendMode::Normal => layer,
+            BlendMode::Add => (below + layer).min(1.0),
+            BlendMode::Multiply => below * layer,
+            BlendMode::Screen => 1.0 - (1.0 - below) * (1.0 - layer),
+            BlendMode::Max => below.max(layer),
+        }
+    }
+}
+
+impl Layer {
+    /*
+     * Takes the layer parameters (opacity=, blend-mode=, zone=) from
+     * @params, and creates the layer's effect from the rest
+     */
+    pub fn new<'a, F>(canvas: &Canvas, mut params: HashMap<&'a str, &'a str>,
+                      effect_fn: F)
+        -> Result<Self, String>
+        where F: FnOnce(HashMap<&'a str, &'a str>)
+                     -> Result<Box<dyn SoftwareEffect>, String>
+    {
+        let opacity =
+            match params.remove("opacity") {
+                None => 100.0,
+                Some(o) => match o.parse::<f32>() {
+                    Ok(x) if (0.0..=100.0).contains(&x) => x,
+                    Ok(_) => return Err(format!("Invalid opacity “{}” \
+                                                 (must be in [0, 100])",
+                                                o)),
+                    Err(e) => return Err(format!("Invalid opacity “{}”: {}",
+                                                 o, e)),
+                },
+            };
+        let mode =
+            parse_blend_mode(params.remove("blend-mode").unwrap_or("normal"))?;
+
+        let mask =
+            match params.remove("zone") {
+                None => None,
+                Some(name) => {
+                    let mut mask = vec![false; canvas.led_count];
+                    for (kbd_i, kbd) in canvas.keyboards.iter().enumerate() {
+                        let leds =
+                            kbd.zone_leds(name).ok_or_else(|| {
+                                format!("Unknown zone “{}” (available: {})",
+                                        name, kbd.zones.names().join(", "))
+                            })?;
+                        for led in leds {
+                            mask[canvas.led(kbd_i, led)] = true;
+                        }
+                    }
+                    Some(mask)
+                }
+            };
+
+        Ok(Layer {
+            effect: effect_fn(params)?,
+            mode,
+            opacity: opacity / 100.0,
+            mask,
+
+            frame: KeyMap { map: vec![Color::BLACK; canvas.led_count] },
+            visible: false,
+
+            period: Duration::ZERO,
+            frame_i: 0,
+            events: Vec::new(),
+        })
+    }
+
+    /* Blend the layer's frame onto @below (one [0, 1] triplet per LED) */
+    fn blend(&self, below: &mut [(f32, f32, f32)]) {
+        for (i, (dst, src)) in below.iter_mut().zip(&self.frame.map)
+            .enumerate()
+        {
+            if self.mask.as_ref().is_some_and(|mask| !mask[i]) {
+                continue;
+            }
+
+            let src = (src.0 as f32 / 255.0,
+                       src.1 as f32 / 255.0,
+                       src.2 as f32 / 255.0);
+            let blended = (self.mode.apply(dst.0, src.0),
+                           self.mode.apply(dst.1, src.1),
+                           self.mode.apply(dst.2, src.2));
+
+            dst.0 += (blended.0 - dst.0) * self.opacity;
+            dst.1 += (blended.1 - dst.1) * self.opacity;
+            dst.2 += (blended.2 - dst.2) * self.opacity;
+        }
+    }
+}
+
+
+impl SoftwareEffect for Layers {
+    /* The fastest layer's; slower ones skip frames (see render()) */
+    fn fps(&self) -> f32 {
+        self.layers.iter().map(|l| l.effect.fps()).fold(1.0, f32::max)
+    }
+
+    fn wants_input(&self) -> bool {
+        self.layers.iter().any(|l| l.effect.wants_input())
+    }
+
+    fn init(&mut self, canvas: &Canvas) -> Result<(), String> {
+        for layer in &mut self.layers {
+            layer.effect.init(canvas)?;
+            layer.period = frame_period(layer.effect.fps());
+        }
+        Ok(())
+    }
+
+    /* Done as soon as any layer is done (e.g. because its input has ended) */
+    fn render(&mut self, canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let mut layer_frame =
+            KeyMap { map: vec![Color::BLACK; canvas.led_count] };
+        let mut changed = false;
+        /* Tolerance for the layers' frame times not being exact multiples */
+        let slack = frame_period(self.fps()) / 2;
+
+        for layer in &mut self.layers {
+            if layer.effect.wants_input() {
+                layer.events.extend_from_slice(events);
+            }
+
+            /* Each layer keeps its own fixed timestep */
+            let layer_elapsed = periods(layer.period, layer.frame_i);
+            if elapsed + slack < layer_elapsed {
+                continue;
+            }
+
+            let current_i =
+                (elapsed.as_secs_f64() / layer.period.as_secs_f64()) as u64;
+            layer.frame_i = (layer.frame_i + 1).max(current_i);
+
+            layer_frame.map.fill(Color::BLACK);
+            let layer_events = std::mem::take(&mut layer.events);
+
+            match layer.effect.render(canvas, &mut layer_frame, layer_elapsed,
+                                      &layer_events)?
+            {
+                Frame::Draw => {
+                    std::mem::swap(&mut layer.frame, &mut layer_frame);
+                    layer.visible = true;
+                    changed = true;
+                }
+
+                Frame::Keep => (),
+
+                Frame::Release => {
+                    changed |= layer.visible;
+                    layer.visible = false;
+                }
+
+                Frame::Done => return Ok(Frame::Done),
+            }
+        }
+
+        if !changed {
+            return Ok(Frame::Keep);
+        }
+        if !self.layers.iter().any(|l| l.visible) {
+            return Ok(Frame::Release);
+        }
+
+        let mut pixels = vec![(0.0f32, 0.0f32, 0.0f32); canvas.led_count];
+        for layer in self.layers.iter().filter(|l| l.visible) {
+            layer.blend(&mut pixels);
+        }
+
+        for (color, pixel) in frame.map.iter_mut().zip(&pixels) {
+            *color = ((pixel.0 * 255.0 + 0.5) as u8,
+                      (pixel.1 * 255.0 + 0.5) as u8,
+                      (pixel.2 * 255.0 + 0.5) as u8);
+        }
+
+        Ok(Frame::Draw)
+    }
+
+    /* Shuts down all layers, even if one fails */
+    fn shutdown(&mut self, canvas: &Canvas) -> Result<(), String> {
+        let mut res = Ok(());
+        for layer in &mut self.layers {
+            res = res.and(layer.effect.shutdown(canvas));
+        }
+        res
+    }
+}
+
+impl SoftwareEffect for StaticMap {
+    fn fps(&self) -> f32 {
+        1.0
+    }
+
+    fn render(&mut self, _canvas: &Canvas, frame: &mut KeyMap,
+              _elapsed: Duration, _events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        if self.drawn {
+            return Ok(Frame::Keep);
+        }
+
+        frame.map.copy_from_slice(&self.map.map);
+        self.drawn = true;
+        Ok(Frame::Draw)
+    }
+}
+
+
+/* Layer showing the given key map (one color per canvas LED) */
+pub fn static_map(map: KeyMap) -> Box<dyn SoftwareEffect> {
+    Box::new(StaticMap {
+        map,
+        drawn: false,
+    })
+}
+
+/* Stack the given layers, the first one at the bottom */
+pub fn layers(layers: Vec<Layer>) -> Box<dyn SoftwareEffect> {
+    Box::new(Layers {
+        layers,
+    })
+}
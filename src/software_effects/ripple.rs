@@ -0,0 +1,304 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Software version of reactive-ripple: Every key press sends a ring over the
+ * keyboard(s), starting from the key's position.  Any number of ripples can
+ * be underway at the same time.
+ */
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::canvas::Canvas;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{Color, ColorParam, Gradient, KeyEvent, KeyMap};
+use crate::{check_superfluous_params, parse_keyup, parse_speed, parse_sw_color};
+
+
+/* Ring speed in keyboard units per second, per speed parameter step */
+const UNITS_PER_SEC_PER_SPEED: f32 = 0.4;
+/* Ring speed for speed=0 */
+const MIN_UNITS_PER_SEC: f32 = 2.0;
+
+
+#[derive(Clone, Copy)]
+enum Decay {
+    Linear,
+    Quadratic,
+    Exponential,
+}
+
+#[derive(Clone, Copy)]
+enum Blend {
+    /* Add up overlapping ripples' colors */
+    Add,
+    /* Take the brightest color channels */
+    Max,
+    /* Draw newer ripples over older ones */
+    Over,
+}
+
+/* How ripples are colored */
+enum Colors {
+    Single(Color),
+    /* Sampled at the key's horizontal position */
+    Gradient(Gradient),
+    /* Random ones from the gradient */
+    Random(Gradient),
+    /* Color of the pressed key (per keyboard) */
+    PerKey(Vec<KeyMap>),
+}
+
+struct Ripple {
+    /* Center, in canvas units */
+    origin: (f32, f32),
+    color: Color,
+    /* Time of the frame it was started in */
+    start: Duration,
+}
+
+
+fn f32_param(params: &mut HashMap<&str, &str>, name: &str, default: f32)
+    -> Result<f32, String>
+{
+    match params.remove(name) {
+        None => Ok(default),
+        Some(val) => match val.parse::<f32>() {
+            Ok(x) if x.is_finite() && x > 0.0 => Ok(x),
+            Ok(_) => Err(format!("Invalid {} value “{}”", name, val)),
+            Err(e) => Err(format!("Invalid {} value “{}”: {}",
+                                  name, val, e)),
+        },
+    }
+}
+
+fn parse_decay(decay_param: &str) -> Result<Decay, String> {
+    match decay_param {
+        "linear"        => Ok(Decay::Linear),
+        "quadratic"     => Ok(Decay::Quadratic),
+        "exponential"   => Ok(Decay::Exponential),
+
+        x => Err(format!("Invalid decay “{}”", x)),
+    }
+}
+
+fn parse_blend(blend_param: &str) -> Result<Blend, String> {
+    match blend_param {
+        "add"   => Ok(Blend::Add),
+        "max"   => Ok(Blend::Max),
+        "over"  => Ok(Blend::Over),
+
+        x => Err(format!("Invalid blend mode “{}”", x)),
+    }
+}
+
+
+impl Decay {
+    /* Brightness once a ripple has covered the given part of its radius */
+    fn brightness(self, progress: f32) -> f32 {
+        let progress = progress.min(1.0);
+
+        match self {
+            Decay::Linear => 1.0 - progress,
+            Decay::Quadratic => (1.0 - progress) * (1.0 - progress),
+            Decay::Exponential => (-5.0 * progress).exp(),
+        }
+    }
+}
+
+impl Blend {
+    /* Draw @color with the given intensity (in [0, 1]) onto @dst */
+    fn apply(self, dst: &mut (f32, f32, f32), color: Color, intensity: f32) {
+        let src = (color.0 as f32 * intensity,
+                   color.1 as f32 * intensity,
+                   color.2 as f32 * intensity);
+
+        *dst =
+            match self {
+                Blend::Add => (dst.0 + src.0, dst.1 + src.1, dst.2 + src.2),
+                Blend::Max => (dst.0.max(src.0), dst.1.max(src.1),
+                               dst.2.max(src.2)),
+                Blend::Over => (dst.0 * (1.0 - intensity) + src.0,
+                                dst.1 * (1.0 - intensity) + src.1,
+                                dst.2 * (1.0 - intensity) + src.2),
+            };
+    }
+}
+
+impl Colors {
+    fn new(canvas: &Canvas, cp: ColorParam) -> Result<Self, String> {
+        Ok(match cp {
+            ColorParam::Color(c) => Colors::Single(c),
+            ColorParam::Randomized => Colors::Random(cp.gradient()),
+            ColorParam::PerKey(spec) =>
+                Colors::PerKey(spec.keymaps(&canvas.keyboards)?),
+            ColorParam::Rainbow | ColorParam::Gradient(_) =>
+                Colors::Gradient(cp.gradient()),
+        })
+    }
+
+    /* Color for a ripple from the given LEDs of the given keyboard */
+    fn color(&self, canvas: &Canvas, kbd_i: usize, leds: &[usize],
+             origin: (f32, f32))
+        -> Color
+    {
+        match self {
+            Colors::Single(c) => *c,
+            Colors::Gradient(g) => g.sample(origin.0 / canvas.width * 100.0),
+            Colors::Random(g) => g.sample(rand::random::<f32>() * 100.0),
+            Colors::PerKey(keymaps) => keymaps[kbd_i].map[leds[0]],
+        }
+    }
+}
+
+
+struct RippleEffect {
+    color_param: Option<ColorParam>,
+    /* Created from color_param in init() */
+    colors: Option<Colors>,
+    keyup: bool,
+    radius: f32,
+    width: f32,
+    decay: Decay,
+    blend: Blend,
+    fps: f32,
+    units_per_sec: f32,
+
+    ripples: Vec<Ripple>,
+    /* Whether the last frame sent had any ripples on it */
+    lit: bool,
+}
+
+
+impl SoftwareEffect for RippleEffect {
+    fn fps(&self) -> f32 {
+        self.fps
+    }
+
+    fn wants_input(&self) -> bool {
+        true
+    }
+
+    fn init(&mut self, canvas: &Canvas) -> Result<(), String> {
+        if let Some(cp) = self.color_param.take() {
+            self.colors = Some(Colors::new(canvas, cp)?);
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let Some(colors) = &self.colors else {
+            return Ok(Frame::Done);
+        };
+
+        for ev in events {
+            if ev.down == self.keyup {
+                continue;
+            }
+
+            let kbd_i = ev.keyboard;
+            let leds: Vec<usize> =
+                canvas.keyboards[kbd_i].layout.leds_by_keycode(ev.code)
+                    .iter().map(|l| l.index).collect();
+
+            /* Keys with several LEDs (e.g. space) start from their center */
+            let centers: Vec<(f32, f32)> =
+                leds.iter()
+                    .filter_map(|led| canvas.centers[canvas.led(kbd_i, *led)])
+                    .collect();
+            if centers.is_empty() {
+                continue;
+            }
+
+            let n = centers.len() as f32;
+            let origin = (centers.iter().map(|c| c.0).sum::<f32>() / n,
+                          centers.iter().map(|c| c.1).sum::<f32>() / n);
+
+            self.ripples.push(Ripple {
+                origin,
+                color: colors.color(canvas, kbd_i, &leds, origin),
+                start: elapsed,
+            });
+        }
+
+        let units_per_sec = self.units_per_sec;
+        let max_r = self.radius + self.width;
+        self.ripples.retain(|ripple| {
+            (elapsed - ripple.start).as_secs_f32() * units_per_sec < max_r
+        });
+
+        if self.ripples.is_empty() {
+            /* Clear the last ripple once it is over */
+            if self.lit {
+                self.lit = false;
+                return Ok(Frame::Draw);
+            }
+            return Ok(Frame::Keep);
+        }
+
+        let mut pixels = vec![(0.0f32, 0.0f32, 0.0f32); canvas.led_count];
+
+        for ripple in &self.ripples {
+            let r = (elapsed - ripple.start).as_secs_f32() * units_per_sec;
+            let brightness = self.decay.brightness(r / self.radius);
+
+            for (pixel, center) in pixels.iter_mut().zip(&canvas.centers) {
+                let Some((x, y)) = center else {
+                    continue;
+                };
+
+                let d = ((x - ripple.origin.0).powi(2) +
+                         (y - ripple.origin.1).powi(2)).sqrt();
+                let ring = 1.0 - (d - r).abs() / self.width;
+                if ring > 0.0 {
+                    self.blend.apply(pixel, ripple.color, ring * brightness);
+                }
+            }
+        }
+
+        for (color, pixel) in frame.map.iter_mut().zip(&pixels) {
+            *color = (pixel.0.min(255.0) as u8,
+                      pixel.1.min(255.0) as u8,
+                      pixel.2.min(255.0) as u8);
+        }
+
+        self.lit = true;
+        Ok(Frame::Draw)
+    }
+}
+
+
+pub fn ripple(mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let cp = parse_sw_color(params.remove("color").unwrap_or("rainbow"))?;
+    let speed = parse_speed(params.remove("speed").unwrap_or("50"))?;
+    let keyup = parse_keyup(params.remove("keyup"), params.remove("keydown"))?;
+    let radius = f32_param(&mut params, "radius", 6.0)?;
+    let width = f32_param(&mut params, "width", 1.5)?;
+    let decay = parse_decay(params.remove("decay").unwrap_or("linear"))?;
+    let blend = parse_blend(params.remove("blend").unwrap_or("add"))?;
+    let fps = f32_param(&mut params, "fps", 60.0)?;
+
+    check_superfluous_params(params)?;
+
+    Ok(Box::new(RippleEffect {
+        color_param: Some(cp),
+        colors: None,
+        keyup,
+        radius,
+        width,
+        decay,
+        blend,
+        fps,
+        units_per_sec:
+            MIN_UNITS_PER_SEC + speed as f32 * UNITS_PER_SEC_PER_SPEED,
+
+        ripples: Vec::new(),
+        lit: false,
+    }))
+}
@@ -3,10 +3,19 @@
 
 use std::collections::HashMap;
 use std::io::Read;
-use std::process::{Command, Stdio};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::Duration;
 
+use crate::canvas::Canvas;
 use crate::check_superfluous_params;
-use crate::keyboard::Keyboard;
+use crate::signals;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{KeyEvent, KeyMap};
+
+
+/* Resolution of the captured image, relative to the keyboard size */
+const PIXELS_PER_UNIT: f32 = 4.0;
 
 
 fn isize_param(params: &mut HashMap<&str, &str>, name: &str)
@@ -54,104 +63,217 @@ fn xrandr_res() -> Result<(isize, isize), String> {
     Ok((xrandr_w, xrandr_h))
 }
 
-pub fn screen_capture(kbd: &Keyboard, mut params: HashMap<&str, &str>)
-    -> Result<(), String>
-{
-    #[cfg(not(target_os = "windows"))]
-    let (def_w, def_h) = xrandr_res()?;
-
-    let ffmpeg_path = params.remove("ffmpeg-bin").unwrap_or("ffmpeg");
-    let fps = isize_param(&mut params, "fps")?.unwrap_or(60);
-    let x = isize_param(&mut params, "x")?;
-    let y = isize_param(&mut params, "y")?;
-    let w = isize_param(&mut params, "w")?;
-    let h = isize_param(&mut params, "h")?;
+struct ScreenCapture {
+    ffmpeg_path: String,
+    fps: isize,
+    x: Option<isize>,
+    y: Option<isize>,
+    w: Option<isize>,
+    h: Option<isize>,
     #[cfg(not(target_os = "windows"))]
-    let display = params.remove("display").unwrap_or(":0");
-    let scale_alg = params.remove("scale-algorithm").unwrap_or("area");
+    display: String,
+    scale_alg: String,
 
-    check_superfluous_params(params)?;
+    ffmpeg: Option<Child>,
+    /* Screenshots read from ffmpeg (see read_screens()) */
+    screens: Option<Receiver<Result<Vec<u8>, String>>>,
+    /* Pixel to sample for every LED (its key's center) */
+    led_pixels: Vec<Option<usize>>,
+}
 
-    let mut ffmpeg_cmd = Command::new(ffmpeg_path);
 
-    #[cfg(not(target_os = "windows"))]
-    {
-        ffmpeg_cmd.arg("-video_size")
-                  .arg(format!("{}x{}",
-                               w.unwrap_or(def_w), h.unwrap_or(def_h)));
-    }
+/* Forward screenshots of the given size from ffmpeg until it exits */
+fn read_screens(mut ffmpeg_stdout: ChildStdout, size: usize,
+                tx: Sender<Result<Vec<u8>, String>>)
+{
+    loop {
+        let mut screen = vec![0u8; size];
 
-    #[cfg(target_os = "windows")]
-    {
-        if w.is_some() || h.is_some() {
-            if w.is_none() || h.is_none() {
-                return Err(String::from("You need to specify either both of w \
-                                         and h, or neither"));
+        if let Err(e) = ffmpeg_stdout.read_exact(&mut screen) {
+            /* ffmpeg has probably received the same signal */
+            if signals::received().is_none() {
+                let _ = tx.send(Err(format!("Failed to read from ffmpeg: {}",
+                                            e)));
             }
-            ffmpeg_cmd.arg("-video_size")
-                      .arg(format!("{}x{}", w.unwrap(), h.unwrap()));
+            return;
+        }
+
+        if tx.send(Ok(screen)).is_err() {
+            return;
         }
     }
+}
 
-    ffmpeg_cmd.arg("-framerate").arg(format!("{}", fps));
 
-    #[cfg(not(target_os = "windows"))]
-    {
-        ffmpeg_cmd.arg("-f").arg("x11grab")
-                  .arg("-i")
-                  .arg(format!("{}+{},{}",
-                               display, x.unwrap_or(0), y.unwrap_or(0)));
+impl SoftwareEffect for ScreenCapture {
+    fn fps(&self) -> f32 {
+        self.fps as f32
     }
 
-    #[cfg(target_os = "windows")]
-    {
-        if let Some(xv) = x {
-            ffmpeg_cmd.arg("-offset_x").arg(format!("{}", xv));
+    fn init(&mut self, canvas: &Canvas) -> Result<(), String> {
+        #[cfg(not(target_os = "windows"))]
+        let (def_w, def_h) = xrandr_res()?;
+
+        let mut ffmpeg_cmd = Command::new(&self.ffmpeg_path);
+
+        #[cfg(not(target_os = "windows"))]
+        {
+            ffmpeg_cmd.arg("-video_size")
+                      .arg(format!("{}x{}",
+                                   self.w.unwrap_or(def_w),
+                                   self.h.unwrap_or(def_h)));
         }
-        if let Some(yv) = y {
-            ffmpeg_cmd.arg("-offset_y").arg(format!("{}", yv));
+
+        #[cfg(target_os = "windows")]
+        {
+            if let (Some(w), Some(h)) = (self.w, self.h) {
+                ffmpeg_cmd.arg("-video_size").arg(format!("{}x{}", w, h));
+            }
         }
-        ffmpeg_cmd.arg("-f").arg("gdigrab")
-                  .arg("-i").arg("desktop");
-    }
 
-    ffmpeg_cmd.arg("-vf").arg(format!("scale={}x6:sws_flags={}",
-                                      kbd.width, scale_alg))
-              .arg("-vcodec").arg("rawvideo")
-              .arg("-f").arg("rawvideo")
-              .arg("pipe:1")
-              .stdin(Stdio::null())
-              .stdout(Stdio::piped())
-              .stderr(Stdio::null());
-
-    let ffmpeg =
-        match ffmpeg_cmd.spawn() {
-            Ok(p) => p,
+        ffmpeg_cmd.arg("-framerate").arg(format!("{}", self.fps));
 
-            Err(e) =>
-                return Err(format!("Failed to launch ffmpeg: {}", e)),
+        #[cfg(not(target_os = "windows"))]
+        {
+            ffmpeg_cmd.arg("-f").arg("x11grab")
+                      .arg("-i")
+                      .arg(format!("{}+{},{}",
+                                   self.display, self.x.unwrap_or(0),
+                                   self.y.unwrap_or(0)));
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            if let Some(xv) = self.x {
+                ffmpeg_cmd.arg("-offset_x").arg(format!("{}", xv));
+            }
+            if let Some(yv) = self.y {
+                ffmpeg_cmd.arg("-offset_y").arg(format!("{}", yv));
+            }
+            ffmpeg_cmd.arg("-f").arg("gdigrab")
+                      .arg("-i").arg("desktop");
+        }
+
+        let width = (canvas.width * PIXELS_PER_UNIT).ceil() as usize;
+        let height = (canvas.height * PIXELS_PER_UNIT).ceil() as usize;
+
+        self.led_pixels =
+            canvas.centers.iter().map(|center| center.map(|(x, y)| {
+                let px = ((x * PIXELS_PER_UNIT) as usize).min(width - 1);
+                let py = ((y * PIXELS_PER_UNIT) as usize).min(height - 1);
+                py * width + px
+            })).collect();
+
+        ffmpeg_cmd.arg("-vf").arg(format!("scale={}x{}:sws_flags={}",
+                                          width, height, self.scale_alg))
+                  .arg("-vcodec").arg("rawvideo")
+                  .arg("-f").arg("rawvideo")
+                  .arg("pipe:1")
+                  .stdin(Stdio::null())
+                  .stdout(Stdio::piped())
+                  .stderr(Stdio::null());
+
+        let mut ffmpeg =
+            match ffmpeg_cmd.spawn() {
+                Ok(p) => p,
+
+                Err(e) =>
+                    return Err(format!("Failed to launch ffmpeg: {}", e)),
+            };
+
+        let ffmpeg_stdout = ffmpeg.stdout.take().unwrap();
+        self.ffmpeg = Some(ffmpeg);
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            read_screens(ffmpeg_stdout, width * height * 4, tx)
+        });
+        self.screens = Some(rx);
+
+        Ok(())
+    }
+
+    fn render(&mut self, _canvas: &Canvas, frame: &mut KeyMap,
+              _elapsed: Duration, _events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let Some(screens) = &self.screens else {
+            return Ok(Frame::Done);
         };
 
-    let mut ffmpeg_stdout = ffmpeg.stdout.unwrap();
-    let mut screen = vec![0u8; kbd.width * 6 * 4];
-    let mut keys = vec![0u8; kbd.led_count * 3];
+        /* Only show the latest screenshot */
+        let mut screen = None;
+        loop {
+            match screens.try_recv() {
+                Ok(s) => screen = Some(s?),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(Frame::Done),
+            }
+        }
 
-    loop {
-        ffmpeg_stdout.read_exact(&mut screen).unwrap();
-
-        for i in 0..(kbd.width * 6) {
-            match kbd.ledmap[i] {
-                0xff => (),
-                m => {
-                    let m_base = m as usize * 3;
-
-                    keys[m_base + 0] = screen[i * 4 + 2];
-                    keys[m_base + 1] = screen[i * 4 + 1];
-                    keys[m_base + 2] = screen[i * 4 + 0];
-                }
+        let Some(screen) = screen else {
+            return Ok(Frame::Keep);
+        };
+
+        for (m, pixel) in self.led_pixels.iter().enumerate() {
+            if let Some(i) = pixel {
+                frame.map[m] = (screen[i * 4 + 2],
+                                screen[i * 4 + 1],
+                                screen[i * 4]);
             }
         }
 
-        kbd.all_keys_raw(&keys);
+        Ok(Frame::Draw)
+    }
+
+    fn shutdown(&mut self, _canvas: &Canvas) -> Result<(), String> {
+        if let Some(mut ffmpeg) = self.ffmpeg.take() {
+            let _ = ffmpeg.kill();
+            let _ = ffmpeg.wait();
+        }
+        Ok(())
+    }
+}
+
+
+pub fn screen_capture(mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let ffmpeg_path = params.remove("ffmpeg-bin").unwrap_or("ffmpeg");
+    let fps = isize_param(&mut params, "fps")?.unwrap_or(60);
+    let x = isize_param(&mut params, "x")?;
+    let y = isize_param(&mut params, "y")?;
+    let w = isize_param(&mut params, "w")?;
+    let h = isize_param(&mut params, "h")?;
+    #[cfg(not(target_os = "windows"))]
+    let display = params.remove("display").unwrap_or(":0");
+    let scale_alg = params.remove("scale-algorithm").unwrap_or("area");
+
+    check_superfluous_params(params)?;
+
+    if fps <= 0 {
+        return Err(format!("Invalid fps value “{}”", fps));
     }
+
+    #[cfg(target_os = "windows")]
+    if w.is_some() != h.is_some() {
+        return Err(String::from("You need to specify either both of w and h, \
+                                 or neither"));
+    }
+
+    Ok(Box::new(ScreenCapture {
+        ffmpeg_path: String::from(ffmpeg_path),
+        fps,
+        x,
+        y,
+        w,
+        h,
+        #[cfg(not(target_os = "windows"))]
+        display: String::from(display),
+        scale_alg: String::from(scale_alg),
+
+        ffmpeg: None,
+        screens: None,
+        led_pixels: Vec::new(),
+    }))
 }
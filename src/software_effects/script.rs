@@ -0,0 +1,376 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * User-defined software effects: Rhai scripts in
+ * $XDG_CONFIG_HOME/leddy/effects/<name>.rhai, used like built-in effects by
+ * their name (which must differ from the built-in ones').  A script declares
+ * its parameters with their defaults (values given on the command line are
+ * converted to the defaults' types; fps is taken by every script):
+ *
+ *   const PARAMS = #{ color: "ff0000", decay: 1.0 };
+ *
+ * and defines these functions, which are called with “this” bound to a map
+ * that keeps the effect's state from one call to the next:
+ *
+ *   fn init(params)        Optional; called once, with all parameters
+ *   fn render(t)           Returns the frame at t seconds: An array of colors
+ *   fn render(t, events)   (0xRRGGBB), one per LED, or () to keep the last
+ *                          frame.  Taking events means key events (maps with
+ *                          keyboard, code, down, and leds) are read from
+ *                          /dev/input.
+ *
+ * Besides Rhai's own, scripts can use these functions: keys() (all keys with
+ * a position, as maps with led, x, y, name, code, and keyboard), leds(key)
+ * (the LEDs of the key with the given name or label, one per keyboard),
+ * zone(name) (the zone's LEDs), led_count(), width(), height() (of the
+ * canvas, in keyboard units), new_frame() (all black), rgb(r, g, b) (each 0
+ * to 255), hsv(h, s, v) (each 0 to 1), and color("RRGGBB").
+ */
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope,
+           AST, FLOAT, INT};
+
+use crate::canvas::Canvas;
+use crate::check_superfluous_params;
+use crate::signals;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{hsv_to_rgb, Color, ColorMethods, KeyEvent, KeyMap};
+
+
+struct Script {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    /* Variables of the script's top level */
+    scope: Scope<'static>,
+    /* “this” for init() and render() */
+    state: Dynamic,
+    params: Map,
+    fps: f32,
+    /* Whether render() takes key events */
+    input: bool,
+}
+
+
+fn script_dir() -> Result<PathBuf, String> {
+    let dir =
+        match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".config"),
+                None => return Err(String::from("Cannot find the \
+                                                 configuration directory \
+                                                 (set $XDG_CONFIG_HOME)")),
+            },
+        };
+
+    Ok(dir.join("leddy").join("effects"))
+}
+
+/* The script for the effect with the given name, if there is one */
+pub fn find(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.starts_with('.') {
+        return None;
+    }
+
+    let path = script_dir().ok()?.join(format!("{}.rhai", name));
+    path.is_file().then_some(path)
+}
+
+/* Parse a parameter as the type of its default */
+fn param_value(name: &str, value: &str, default: &Dynamic)
+    -> Result<Dynamic, String>
+{
+    let invalid = |e: &dyn std::fmt::Display| {
+        format!("Invalid {} value “{}”: {}", name, value, e)
+    };
+
+    if default.is_float() {
+        value.parse::<FLOAT>().map(Dynamic::from).map_err(|e| invalid(&e))
+    } else if default.is_int() {
+        value.parse::<INT>().map(Dynamic::from).map_err(|e| invalid(&e))
+    } else if default.is_bool() {
+        match value {
+            "" | "true" => Ok(Dynamic::TRUE),
+            "false" => Ok(Dynamic::FALSE),
+            _ => Err(format!("Invalid {} value “{}” (must be true or false)",
+                             name, value)),
+        }
+    } else {
+        Ok(Dynamic::from(value.to_string()))
+    }
+}
+
+fn color_to_int(color: Color) -> INT {
+    ((color.0 as INT) << 16) | ((color.1 as INT) << 8) | color.2 as INT
+}
+
+fn int_to_color(color: INT) -> Color {
+    ((color >> 16) as u8, (color >> 8) as u8, color as u8)
+}
+
+/* Functions that do not depend on the canvas */
+fn register_helpers(engine: &mut Engine) {
+    engine.register_fn("rgb", |r: INT, g: INT, b: INT| {
+        color_to_int((r.clamp(0, 255) as u8, g.clamp(0, 255) as u8,
+                      b.clamp(0, 255) as u8))
+    });
+
+    engine.register_fn("hsv", |h: FLOAT, s: FLOAT, v: FLOAT| {
+        let (r, g, b) = hsv_to_rgb(h.rem_euclid(1.0), s.clamp(0.0, 1.0),
+                                   v.clamp(0.0, 1.0));
+        color_to_int(((r * 255.0 + 0.5) as u8, (g * 255.0 + 0.5) as u8,
+                      (b * 255.0 + 0.5) as u8))
+    });
+
+    engine.register_fn("color", |s: &str| -> Result<INT, Box<EvalAltResult>> {
+        Ok(color_to_int(Color::from_str(s)?))
+    });
+}
+
+/* Functions that describe the canvas */
+fn register_canvas(engine: &mut Engine, canvas: &Canvas) {
+    let mut keys = Array::new();
+    for (kbd_i, kbd) in canvas.keyboards.iter().enumerate() {
+        for led in &kbd.layout.leds {
+            let canvas_led = canvas.led(kbd_i, led.index);
+            let Some((x, y)) = canvas.centers[canvas_led] else {
+                continue;
+            };
+
+            let mut key = Map::new();
+            key.insert("led".into(), Dynamic::from(canvas_led as INT));
+            key.insert("x".into(), Dynamic::from(x as FLOAT));
+            key.insert("y".into(), Dynamic::from(y as FLOAT));
+            key.insert("name".into(), Dynamic::from(led.key.clone()));
+            key.insert("code".into(), match led.keycode {
+                Some(code) => Dynamic::from(code as INT),
+                None => Dynamic::UNIT,
+            });
+            key.insert("keyboard".into(), Dynamic::from(kbd_i as INT));
+            keys.push(key.into());
+        }
+    }
+    engine.register_fn("keys", move || keys.clone());
+
+    /* Keys by name and by label (which can differ between keyboards) */
+    let mut key_leds = HashMap::<String, Array>::new();
+    for (kbd_i, kbd) in canvas.keyboards.iter().enumerate() {
+        for led in &kbd.layout.leds {
+            let canvas_led = canvas.led(kbd_i, led.index) as INT;
+            let mut names = kbd.labels.labels(&led.key);
+            names.push(&led.key);
+
+            for name in names {
+                /* Labels take precedence over key names */
+                if kbd.led(name) != Some(led.index) {
+                    continue;
+                }
+
+                let leds = key_leds.entry(String::from(name)).or_default();
+                if !leds.iter().any(|l| l.as_int() == Ok(canvas_led)) {
+                    leds.push(Dynamic::from(canvas_led));
+                }
+            }
+        }
+    }
+    engine.register_fn("leds", move |name: &str|
+        -> Result<Array, Box<EvalAltResult>>
+    {
+        key_leds.get(name).cloned()
+            .ok_or_else(|| format!("Unknown key “{}”", name).into())
+    });
+
+    let mut zones = HashMap::<String, Array>::new();
+    for (kbd_i, kbd) in canvas.keyboards.iter().enumerate() {
+        for name in kbd.zones.names() {
+            let leds = zones.entry(String::from(name)).or_default();
+            for led in kbd.zone_leds(name).unwrap_or_default() {
+                leds.push(Dynamic::from(canvas.led(kbd_i, led) as INT));
+            }
+        }
+    }
+    engine.register_fn("zone", move |name: &str|
+        -> Result<Array, Box<EvalAltResult>>
+    {
+        zones.get(name).cloned()
+            .ok_or_else(|| format!("Unknown zone “{}”", name).into())
+    });
+
+    let led_count = canvas.led_count;
+    let (width, height) = (canvas.width, canvas.height);
+    engine.register_fn("led_count", move || led_count as INT);
+    engine.register_fn("width", move || width as FLOAT);
+    engine.register_fn("height", move || height as FLOAT);
+    engine.register_fn("new_frame", move || vec![Dynamic::from(0 as INT);
+                                                 led_count]);
+}
+
+fn event_map(canvas: &Canvas, ev: &KeyEvent) -> Dynamic {
+    let leds: Array =
+        canvas.keyboards[ev.keyboard].layout.leds_by_keycode(ev.code).iter()
+            .map(|l| Dynamic::from(canvas.led(ev.keyboard, l.index) as INT))
+            .collect();
+
+    let mut map = Map::new();
+    map.insert("keyboard".into(), Dynamic::from(ev.keyboard as INT));
+    map.insert("code".into(), Dynamic::from(ev.code as INT));
+    map.insert("down".into(), Dynamic::from(ev.down));
+    map.insert("leds".into(), leds.into());
+    map.into()
+}
+
+
+impl Script {
+    /*
+     * Call the given function; if it was aborted by a termination signal,
+     * return (), so the runner can stop as usual
+     */
+    fn call(&mut self, name: &str, args: impl rhai::FuncArgs)
+        -> Result<Dynamic, String>
+    {
+        let options = CallFnOptions::new().eval_ast(false)
+                                          .bind_this_ptr(&mut self.state);
+
+        match self.engine.call_fn_with_options(options, &mut self.scope,
+                                               &self.ast, name, args)
+        {
+            Ok(result) => Ok(result),
+            Err(e) if matches!(*e, EvalAltResult::ErrorTerminated(..)) =>
+                Ok(Dynamic::UNIT),
+            Err(e) => Err(format!("{}: {}", self.path.display(), e)),
+        }
+    }
+}
+
+
+impl SoftwareEffect for Script {
+    fn fps(&self) -> f32 {
+        self.fps
+    }
+
+    fn wants_input(&self) -> bool {
+        self.input
+    }
+
+    fn init(&mut self, canvas: &Canvas) -> Result<(), String> {
+        register_canvas(&mut self.engine, canvas);
+
+        if !self.ast.iter_functions().any(|f| f.name == "init") {
+            return Ok(());
+        }
+
+        let params = self.params.clone();
+        self.call("init", (params,)).map(|_| ())
+    }
+
+    fn render(&mut self, canvas: &Canvas, frame: &mut KeyMap,
+              elapsed: Duration, events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let t = elapsed.as_secs_f32() as FLOAT;
+
+        let result =
+            if self.input {
+                let events: Array =
+                    events.iter().map(|ev| event_map(canvas, ev)).collect();
+                self.call("render", (t, events))?
+            } else {
+                self.call("render", (t,))?
+            };
+
+        if result.is_unit() {
+            return Ok(Frame::Keep);
+        }
+
+        let Some(colors) = result.try_cast::<Array>() else {
+            return Err(format!("{}: render() must return an array of colors \
+                                or ()",
+                               self.path.display()));
+        };
+
+        for (color, value) in frame.map.iter_mut().zip(colors) {
+            let Ok(value) = value.as_int() else {
+                return Err(format!("{}: Colors must be integers (0xRRGGBB)",
+                                   self.path.display()));
+            };
+            *color = int_to_color(value);
+        }
+
+        Ok(Frame::Draw)
+    }
+}
+
+
+/* Load the given script (see find()) as an effect */
+pub fn script(path: &Path, mut params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    let error = |e: Box<EvalAltResult>| format!("{}: {}", path.display(), e);
+
+    let mut engine = Engine::new();
+    register_helpers(&mut engine);
+
+    /* Scripts may loop forever, but must still let leddy stop on a signal */
+    engine.on_progress(|_| {
+        signals::received().map(|sig| Dynamic::from(sig as INT))
+    });
+
+    let ast = engine.compile_file(path.to_path_buf()).map_err(error)?;
+
+    /* Run the top level once, which also declares the parameters */
+    let mut scope = Scope::new();
+    engine.run_ast_with_scope(&mut scope, &ast).map_err(error)?;
+
+    let declared = scope.get_value::<Map>("PARAMS").unwrap_or_default();
+    if declared.contains_key("fps") {
+        return Err(format!("{}: PARAMS cannot declare fps (every script \
+                            effect takes it)",
+                           path.display()));
+    }
+
+    let mut values = Map::new();
+    for (name, default) in declared {
+        let value =
+            match params.remove(name.as_str()) {
+                Some(v) => param_value(&name, v, &default)?,
+                None => default,
+            };
+        values.insert(name, value);
+    }
+
+    let fps =
+        match params.remove("fps") {
+            None => 30.0,
+            Some(f) => match f.parse::<f32>() {
+                Ok(x) if x.is_finite() && x > 0.0 => x,
+                Ok(_) => return Err(format!("Invalid fps value “{}”", f)),
+                Err(e) => return Err(format!("Invalid fps value “{}”: {}",
+                                             f, e)),
+            },
+        };
+
+    check_superfluous_params(params)?;
+
+    let Some(render) = ast.iter_functions().find(|f| f.name == "render")
+    else {
+        return Err(format!("{}: No render() function", path.display()));
+    };
+    let input = render.params.len() >= 2;
+
+    Ok(Box::new(Script {
+        path: path.to_path_buf(),
+        engine,
+        ast,
+        scope,
+        state: Map::new().into(),
+        params: values,
+        fps,
+        input,
+    }))
+}
@@ -2,14 +2,20 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::collections::HashMap;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::time::Duration;
 
-use rustfft::FftPlanner;
+use rustfft::{Fft, FftPlanner};
 use rustfft::num_complex::Complex;
 use rustfft::num_traits::Zero;
 
+use crate::canvas::Canvas;
 use crate::check_superfluous_params;
-use crate::keyboard::Keyboard;
+use crate::signals;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{hsv_to_rgb, KeyEvent, KeyMap};
 
 
 const SAMPLES: usize = 2205;
@@ -18,135 +24,70 @@ const SAMPLES_USED: usize = 200; /* up to 4 kHz */
 const INACTIVITY_MSECS: usize = 5000;
 
 
-fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32) {
-    let c = v * s;
-    let h1 = h * 6.0;
-    let x = c * (1.0 - (h1 % 2.0 - 1.0).abs());
-
-    let (r1, g1, b1) =
-        if h1 < 1.0 {
-            (c, x, 0.0)
-        } else if h1 < 2.0 {
-            (x, c, 0.0)
-        } else if h1 < 3.0 {
-            (0.0, c, x)
-        } else if h1 < 4.0 {
-            (0.0, x, c)
-        } else if h1 < 5.0 {
-            (x, 0.0, c)
-        } else {
-            (c, 0.0, x)
-        };
-
-    let m = v - c;
-    (r1 + m, g1 + m, b1 + m)
+struct SoundSpectrum {
+    /* Sample blocks read from stdin (see read_samples()) */
+    samples: Option<Receiver<Result<Vec<u16>, String>>>,
+
+    buf: Vec<Complex<f32>>,
+    fft_vals: Vec<f32>,
+    fft: Arc<dyn Fft<f32>>,
+
+    /* Keys showing the bass peak and the peak above */
+    peak_keys: [Vec<usize>; 2],
+    /* Low bass on space, alt; mid bass on meta, fn, menu; high bass on
+     * control */
+    bass_keys: [Vec<usize>; 3],
+    /* Length of the frequency bars on the main key block (keyboard units) */
+    bar_len: f32,
+    /* Bar row (0 to 4, from the top) and x position of the LEDs on them */
+    bar_keys: Vec<(usize, usize, f32)>,
+
+    scale: f32,
+    last_lengths: [f32; 5],
+    freqs: [f32; 18],
+    inactivity_msecs: usize,
 }
 
-pub fn sound_spectrum(kbd: &Keyboard, params: HashMap<&str, &str>)
-    -> Result<(), String>
-{
-    check_superfluous_params(params)?;
-
-    let samples = [0u16; SAMPLES];
-
-    let mut buf = vec![Complex::<f32>::zero(); SAMPLES];
-    let mut fft_vals = vec![0f32; SAMPLES_USED];
 
-    let mut fft_planner = FftPlanner::new();
-    let fft = fft_planner.plan_fft_forward(SAMPLES);
-
-    let peak_keys = [
-        if kbd.mini {
-            vec![
-                Keyboard::LEFT,
-                Keyboard::DOWN,
-                Keyboard::RIGHT,
-                Keyboard::UP,
-            ]
-        } else {
-            vec![
-                Keyboard::LEFT,
-                Keyboard::DOWN,
-                Keyboard::RIGHT,
-                Keyboard::UP,
-                Keyboard::DELETE,
-                Keyboard::INSERT,
-                Keyboard::PRINT,
-                Keyboard::SCROLL_LOCK,
-                Keyboard::HOME,
-                Keyboard::END,
-                Keyboard::PAGE_DOWN,
-                Keyboard::PAUSE,
-                Keyboard::PAGE_UP,
-            ]
-        },
-
-        if kbd.mini {
-            vec![
-                Keyboard::DELETE,
-                Keyboard::INSERT,
-                Keyboard::PRINT,
-                Keyboard::MUTE_MIC,
-                Keyboard::MUTE_SPEAKER,
-                Keyboard::SCROLL_LOCK,
-                Keyboard::HOME,
-                Keyboard::END,
-                Keyboard::PAGE_DOWN,
-                Keyboard::GAMING_MODE,
-                Keyboard::PAUSE,
-                Keyboard::PAGE_UP,
-                Keyboard::MINI_SIG_PLATE,
-            ]
-        } else {
-            vec![
-                Keyboard::NUM_LOCK,
-                Keyboard::NUM_7,
-                Keyboard::NUM_4,
-                Keyboard::NUM_1,
-                Keyboard::NUM_0,
-                Keyboard::NUM_2,
-                Keyboard::NUM_5,
-                Keyboard::NUM_8,
-                Keyboard::NUM_SLASH,
-                Keyboard::NUM_ASTERISK,
-                Keyboard::NUM_9,
-                Keyboard::NUM_6,
-                Keyboard::NUM_3,
-                Keyboard::NUM_DECIMAL,
-                Keyboard::NUM_ENTER,
-                Keyboard::NUM_PLUS,
-                Keyboard::NUM_MINUS,
-                Keyboard::VOLUME_KNOB,
-                Keyboard::FULL_SIG_PLATE,
-            ]
-        },
-    ];
-
-    let mut keys = vec![0u8; kbd.led_count * 3];
-
-    let mut scale = 0.0015f32;
-
-    let mut last_lengths = [0.0f32; 5];
-    let mut freqs = [0.0f32; 18];
-
-    let mut inactivity_msecs = 0usize;
+/* Forward blocks of SAMPLES samples from stdin until it ends */
+fn read_samples(tx: SyncSender<Result<Vec<u16>, String>>) {
+    let mut bytes = [0u8; SAMPLES * 2];
 
     loop {
-        for key in &mut keys {
-            *key = 0;
+        if let Err(e) = std::io::stdin().read_exact(&mut bytes) {
+            /* End of input (or the producer was interrupted, too) */
+            if e.kind() != ErrorKind::UnexpectedEof &&
+               signals::received().is_none()
+            {
+                let _ = tx.send(Err(format!("Failed to read samples: {}", e)));
+            }
+            return;
         }
 
-        std::io::stdin().read_exact(unsafe {
-            std::slice::from_raw_parts_mut(samples.as_ptr() as *mut u8,
-                                           SAMPLES * 2)
-        }).unwrap();
+        let samples =
+            bytes.chunks(2).map(|b| u16::from_ne_bytes([b[0], b[1]]))
+                .collect();
+        if tx.send(Ok(samples)).is_err() {
+            return;
+        }
+    }
+}
+
+
+impl SoundSpectrum {
+    /* Analyze a block of samples and draw it into @frame */
+    fn process(&mut self, samples: &[u16], frame: &mut KeyMap) -> Frame {
+        let SoundSpectrum {
+            buf, fft_vals, fft, peak_keys, bass_keys, bar_len, bar_keys,
+            scale, last_lengths, freqs, inactivity_msecs, ..
+        } = self;
 
         for i in 0..SAMPLES {
             buf[i].re = samples[i] as f32 / 32768.0;
             buf[i].im = 0.0;
         }
 
-        fft.process(&mut buf);
+        fft.process(buf);
 
         /* FIXME: .norm() is wrong (should be .re.abs()) */
         for i in 0..SAMPLES_USED {
@@ -177,17 +118,12 @@ pub fn sound_spectrum(kbd: &Keyboard, params: HashMap<&str, &str>)
         }
 
         if freqs_max <= 0.01f32 {
-            let was_below = inactivity_msecs < INACTIVITY_MSECS;
-
-            inactivity_msecs = inactivity_msecs.saturating_add(SAMPLES_MSECS);
-            if inactivity_msecs >= INACTIVITY_MSECS {
-                if was_below {
-                    kbd.refresh_profile();
-                }
-                continue;
+            *inactivity_msecs = inactivity_msecs.saturating_add(SAMPLES_MSECS);
+            if *inactivity_msecs >= INACTIVITY_MSECS {
+                return Frame::Release;
             }
         } else {
-            inactivity_msecs = 0;
+            *inactivity_msecs = 0;
         }
 
         let avgs = [
@@ -195,11 +131,11 @@ pub fn sound_spectrum(kbd: &Keyboard, params: HashMap<&str, &str>)
             freqs[3..18].iter().fold(0.0f32, |c, x| c + x) * (1.0 / 15.0),
         ];
 
-        let scaled_max = freqs_max * scale;
+        let scaled_max = freqs_max * *scale;
         if scaled_max > 1.0 {
-            scale /= scaled_max;
+            *scale /= scaled_max;
         } else {
-            scale = (0.995 * scale + 0.005 * scale / scaled_max).min(0.003);
+            *scale = (0.995 * *scale + 0.005 * *scale / scaled_max).min(0.003);
         }
 
         let peaks: [(usize, f32); 2] = [
@@ -228,9 +164,9 @@ pub fn sound_spectrum(kbd: &Keyboard, params: HashMap<&str, &str>)
 
             /* Red until 260 Hz, green at 680 Hz, capped at violet
              * (log2 scale) */
-            let col = (((peaks[i].0 as f32).log2() - 3.7) * 0.2402466743058456)
-                        .max(0.0).min(5.0 / 6.0);
-            let val = (peaks[i].1 * scale).powf(2.0).min(1.0);
+            let col = (((peaks[i].0 as f32).log2() - 3.7) * 0.240_246_67)
+                        .clamp(0.0, 5.0 / 6.0);
+            let val = (peaks[i].1 * *scale).powf(2.0).min(1.0);
 
             let rgb = hsv_to_rgb(col, sat, val);
             let int_rgb = ((rgb.0 * 255.0 + 0.5) as u8,
@@ -238,40 +174,27 @@ pub fn sound_spectrum(kbd: &Keyboard, params: HashMap<&str, &str>)
                            (rgb.2 * 255.0 + 0.5) as u8);
 
             for key_i in &peak_keys[i] {
-                keys[key_i * 3 + 0] = int_rgb.0;
-                keys[key_i * 3 + 1] = int_rgb.1;
-                keys[key_i * 3 + 2] = int_rgb.2;
+                frame.map[*key_i] = int_rgb;
+            }
+        }
+
+        for (i, bass_keys_i) in bass_keys.iter().enumerate() {
+            let intensity = (freqs[i] * *scale).powf(2.0) * 255.0;
+            let intensity = (intensity.min(255.0) + 0.5) as u8;
+
+            for key_i in bass_keys_i {
+                frame.map[*key_i].0 = intensity;
             }
         }
 
-        /* Low bass on space, alt */
-        let intensity =
-            (((freqs[0] * scale).powf(2.0) * 255.0).min(255.0) + 0.5) as u8;
-        keys[Keyboard::LALT  * 3 + 0] = intensity;
-        keys[Keyboard::SPACE * 3 + 0] = intensity;
-        keys[Keyboard::RALT  * 3 + 0] = intensity;
-
-        /* Mid bass on meta, fn, menu */
-        let intensity =
-            (((freqs[1] * scale).powf(2.0) * 255.0).min(255.0) + 0.5) as u8;
-        keys[Keyboard::META * 3 + 0] = intensity;
-        keys[Keyboard::FN   * 3 + 0] = intensity;
-        keys[Keyboard::MENU * 3 + 0] = intensity;
-
-        /* High bass on control */
-        let intensity =
-            (((freqs[2] * scale).powf(2.0) * 255.0).min(255.0) + 0.5) as u8;
-        keys[Keyboard::LCONTROL * 3 + 0] = intensity;
-        keys[Keyboard::RCONTROL * 3 + 0] = intensity;
-
-        for row_i in 0..5 {
+        for (row_i, last_length) in last_lengths.iter_mut().enumerate() {
             let fqib = 4 - row_i + 3;
 
-            let raw_length = freqs[fqib + 0]
+            let raw_length = freqs[fqib]
                         .max(freqs[fqib + 5])
                         .max(freqs[fqib + 10]);
 
-            let rgb = ((freqs[fqib +  0] / raw_length).powf(2.0),
+            let rgb = ((freqs[fqib] / raw_length).powf(2.0),
                        (freqs[fqib +  5] / raw_length).powf(2.0),
                        (freqs[fqib + 10] / raw_length).powf(2.0));
 
@@ -280,28 +203,137 @@ pub fn sound_spectrum(kbd: &Keyboard, params: HashMap<&str, &str>)
                            (rgb.2 * 255.0 + 0.5) as u8);
 
             let length =
-                if raw_length > last_lengths[row_i] {
+                if raw_length > *last_length {
                     raw_length
                 } else {
-                    (raw_length + last_lengths[row_i]) * 0.5
+                    (raw_length + *last_length) * 0.5
                 };
 
-            last_lengths[row_i] = length;
+            *last_length = length;
+
+            let filled_len = (length * *scale * *bar_len).min(*bar_len);
+
+            for (ki, _, _) in bar_keys.iter().filter(|(_, r, x)|
+                                  *r == row_i && *x < filled_len)
+            {
+                frame.map[*ki] = rgb_int;
+            }
+        }
+
+
+        Frame::Draw
+    }
+}
+
+impl SoftwareEffect for SoundSpectrum {
+    /* One frame per block of samples */
+    fn fps(&self) -> f32 {
+        1000.0 / SAMPLES_MSECS as f32
+    }
+
+    fn init(&mut self, canvas: &Canvas) -> Result<(), String> {
+        let mut peak_keys = [Vec::new(), Vec::new()];
+        let mut bass_keys = [Vec::new(), Vec::new(), Vec::new()];
+
+        for (kbd_i, kbd) in canvas.keyboards.iter().enumerate() {
+            /* Keyboards with a number block show the higher peak there */
+            let has_numpad =
+                kbd.zone_leds("numpad").is_some_and(|leds| !leds.is_empty());
+            let kbd_peak_zones: [&[&str]; 2] =
+                if has_numpad {
+                    [&["arrows", "nav"], &["numpad", "knob", "plate"]]
+                } else {
+                    [&["arrows"], &["nav", "media", "plate"]]
+                };
 
-            let filled_bars =
-                ((length * scale * 15.0).min(15.0) + 0.5) as usize;
+            let kbd_bass_keys: [&[&str]; 3] = [
+                &["lalt", "space", "ralt"],
+                &["meta", "fn", "menu"],
+                &["lcontrol", "rcontrol"],
+            ];
 
-            for j in 0..filled_bars {
-                let ki = kbd.ledmap[row_i * kbd.width + j] as usize;
+            let canvas_leds = |leds: Vec<usize>| -> Vec<usize> {
+                leds.into_iter().map(|led| canvas.led(kbd_i, led)).collect()
+            };
 
-                if ki != 0xff {
-                    keys[ki * 3 + 0] = rgb_int.0;
-                    keys[ki * 3 + 1] = rgb_int.1;
-                    keys[ki * 3 + 2] = rgb_int.2;
+            for i in 0..2 {
+                for zone in kbd_peak_zones[i] {
+                    let leds = kbd.zone_leds(zone).unwrap_or_default();
+                    peak_keys[i].extend(canvas_leds(leds));
                 }
             }
+            for i in 0..3 {
+                let leds =
+                    kbd_bass_keys[i].iter()
+                        .filter_map(|key| kbd.layout.led(key));
+                bass_keys[i].extend(canvas_leds(leds.collect()));
+            }
         }
 
-        kbd.all_keys_raw(&keys);
+        let bar_len = 15.0 * canvas.width / canvas.keyboards[0].width;
+
+        let bar_keys: Vec<(usize, usize, f32)> =
+            canvas.centers.iter().enumerate()
+                .filter_map(|(ki, center)| {
+                    let (x, y) = (*center)?;
+                    let row_i = (y * 6.0 / canvas.keyboards[0].height) as usize;
+                    (row_i < 5).then_some((ki, row_i, x))
+                })
+                .collect();
+
+        self.peak_keys = peak_keys;
+        self.bass_keys = bass_keys;
+        self.bar_len = bar_len;
+        self.bar_keys = bar_keys;
+
+        /* Holds back the reader when reading faster than real time */
+        let (tx, rx) = mpsc::sync_channel(1);
+        std::thread::spawn(move || read_samples(tx));
+        self.samples = Some(rx);
+
+        Ok(())
+    }
+
+    fn render(&mut self, _canvas: &Canvas, frame: &mut KeyMap,
+              _elapsed: Duration, _events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let Some(rx) = &self.samples else {
+            return Ok(Frame::Done);
+        };
+
+        /* One block per frame, so that it takes as long as the input */
+        match rx.try_recv() {
+            Ok(samples) => Ok(self.process(&samples?, frame)),
+            Err(TryRecvError::Empty) => Ok(Frame::Keep),
+            Err(TryRecvError::Disconnected) => Ok(Frame::Done),
+        }
     }
 }
+
+
+pub fn sound_spectrum(params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    check_superfluous_params(params)?;
+
+    let mut fft_planner = FftPlanner::new();
+
+    Ok(Box::new(SoundSpectrum {
+        samples: None,
+
+        buf: vec![Complex::<f32>::zero(); SAMPLES],
+        fft_vals: vec![0f32; SAMPLES_USED],
+        fft: fft_planner.plan_fft_forward(SAMPLES),
+
+        peak_keys: [Vec::new(), Vec::new()],
+        bass_keys: [Vec::new(), Vec::new(), Vec::new()],
+        bar_len: 0.0,
+        bar_keys: Vec::new(),
+
+        scale: 0.0015,
+        last_lengths: [0.0; 5],
+        freqs: [0.0; 18],
+        inactivity_msecs: 0,
+    }))
+}
@@ -2,45 +2,68 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::collections::HashMap;
+use std::time::Duration;
 
+use crate::canvas::Canvas;
 use crate::check_superfluous_params;
-use crate::keyboard::Keyboard;
+use crate::software_effects::{Frame, SoftwareEffect};
+use crate::types::{Color, ColorMethods, KeyEvent, KeyMap};
 
 
-pub fn x_key_ids(kbd: &Keyboard, params: HashMap<&str, &str>)
-    -> Result<(), String>
-{
-    check_superfluous_params(params)?;
+/* How long to show each pattern */
+const PATTERN_SECS: u64 = 5;
+/* LEDs to light, also those that no layout knows */
+const LEDS: usize = 32 * 6;
 
-    let mut keys = [0u8; 32 * 6 * 3];
+const COLORS: [Color; 6] = [
+    Color::RED,
+    Color::GREEN,
+    Color::BLUE,
+    Color::YELLOW,
+    Color::CYAN,
+    Color::MAGENTA,
+];
 
-    let colors = [
-        0xff, 0x00, 0x00,
-        0x00, 0xff, 0x00,
-        0x00, 0x00, 0xff,
-        0xff, 0xff, 0x00,
-        0x00, 0xff, 0xff,
-        0xff, 0x00, 0xff
-    ];
 
-    for i in 0..(32 * 6 * 3) {
-        let color_i = ((i / 18) * 3) % 18;
-        keys[i] = colors[color_i + i % 3];
-    }
+struct XKeyIds;
 
-    for _ in 0..5 {
-        kbd.all_keys_raw(&keys);
-        std::thread::sleep(std::time::Duration::from_secs(1));
-    }
 
-    for i in 0..(32 * 6 * 3) {
-        keys[i] = colors[i % 18];
+impl SoftwareEffect for XKeyIds {
+    fn fps(&self) -> f32 {
+        1.0
     }
 
-    for _ in 0..5 {
-        kbd.all_keys_raw(&keys);
-        std::thread::sleep(std::time::Duration::from_secs(1));
+    /* Sends the pattern itself, frames only have the layouts' LEDs */
+    fn render(&mut self, canvas: &Canvas, _frame: &mut KeyMap,
+              elapsed: Duration, _events: &[KeyEvent])
+        -> Result<Frame, String>
+    {
+        let pattern = elapsed.as_secs() / PATTERN_SECS;
+        if pattern >= 2 {
+            return Ok(Frame::Done);
+        }
+
+        let mut keys = [0u8; LEDS * 3];
+        for (led, rgb) in keys.chunks_mut(3).enumerate() {
+            /* First six LEDs per color, then one after the other */
+            let color_i = if pattern == 0 { (led / 6) % 6 } else { led % 6 };
+            let color = COLORS[color_i];
+            rgb.copy_from_slice(&[color.0, color.1, color.2]);
+        }
+
+        for kbd in &canvas.keyboards {
+            kbd.all_leds_raw(&keys)?;
+        }
+
+        Ok(Frame::Keep)
     }
+}
+
+
+pub fn x_key_ids(params: HashMap<&str, &str>)
+    -> Result<Box<dyn SoftwareEffect>, String>
+{
+    check_superfluous_params(params)?;
 
-    Ok(())
+    Ok(Box::new(XKeyIds))
 }
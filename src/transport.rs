@@ -0,0 +1,183 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::time::Duration;
+
+use hidapi::HidDevice;
+
+
+/* Report ID (always 0) plus 64 bytes of payload */
+pub const REPORT_SIZE: usize = 65;
+
+pub type Report = [u8; REPORT_SIZE];
+
+
+/* Something that can take HID output reports for the keyboard */
+pub trait Transport: Send {
+    fn write(&self, report: &Report) -> Result<(), String>;
+
+    /* Wait up to @timeout for an input report (None if none arrives) */
+    fn read(&self, _timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+        Ok(None)
+    }
+}
+
+
+/* The real thing: A keyboard opened through hidapi */
+pub struct HidTransport {
+    dev: HidDevice,
+}
+
+/* Writes every report as a line of hex digits into a file */
+pub struct FileTransport {
+    file: File,
+}
+
+/* Prints every report in a human-readable form instead of sending it */
+pub struct DryRunTransport;
+
+
+impl HidTransport {
+    pub fn new(dev: HidDevice) -> Self {
+        HidTransport {
+            dev,
+        }
+    }
+}
+
+impl Transport for HidTransport {
+    fn write(&self, report: &Report) -> Result<(), String> {
+        match self.dev.write(report) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to write to HID device: {}", e)),
+        }
+    }
+
+    fn read(&self, timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+        let mut buf = [0u8; REPORT_SIZE];
+
+        match self.dev.read_timeout(&mut buf, timeout.as_millis() as i32) {
+            Ok(0) => Ok(None),
+            Ok(len) => Ok(Some(buf[..len].to_vec())),
+            Err(e) => Err(format!("Failed to read from HID device: {}", e)),
+        }
+    }
+}
+
+
+impl FileTransport {
+    pub fn create(path: &str) -> Result<Self, String> {
+        match File::create(path) {
+            Ok(file) => Ok(FileTransport { file }),
+            Err(e) => Err(format!("Failed to create {}: {}", path, e)),
+        }
+    }
+}
+
+impl Transport for FileTransport {
+    fn write(&self, report: &Report) -> Result<(), String> {
+        let mut line = String::with_capacity(REPORT_SIZE * 2 + 1);
+        for byte in report {
+            line.push_str(&format!("{:02x}", byte));
+        }
+        line.push('\n');
+
+        match (&self.file).write_all(line.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Failed to write recording: {}", e)),
+        }
+    }
+}
+
+
+impl Transport for DryRunTransport {
+    fn write(&self, report: &Report) -> Result<(), String> {
+        let cmd = report[1];
+        let len = report[2] as usize
+                | (report[3] as usize) << 8
+                | (report[4] as usize) << 16;
+        let ofs = report[5] as usize
+                | (report[6] as usize) << 8
+                | (report[7] as usize) << 16;
+
+        /* Only show the part of the payload that is actually used */
+        let payload_len = len.saturating_sub(ofs).min(REPORT_SIZE - 8);
+
+        let mut line = format!("cmd={:02x} len={:06x} ofs={:06x} data:",
+                               cmd, len, ofs);
+        for byte in &report[8..(8 + payload_len)] {
+            line.push_str(&format!(" {:02x}", byte));
+        }
+
+        println!("{}", line);
+        Ok(())
+    }
+}
+
+
+/* Bytes as space-separated hex, e.g. for logging */
+pub fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/*
+ * Parse a single line of a recording.  Whitespace between the bytes is
+ * allowed, empty lines and lines starting with # yield None.
+ */
+pub fn parse_report(line: &str) -> Result<Option<Report>, String> {
+    let hex: Vec<u8> =
+        line.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+
+    if hex.is_empty() || hex[0] == b'#' {
+        return Ok(None);
+    }
+
+    if !hex.is_ascii() {
+        return Err(format!("“{}” is not a sequence of hex bytes",
+                           line.trim()));
+    }
+
+    if hex.len() != REPORT_SIZE * 2 {
+        return Err(format!("Report has {} hex digits instead of {}",
+                           hex.len(), REPORT_SIZE * 2));
+    }
+
+    let mut report = [0u8; REPORT_SIZE];
+    for (i, byte) in report.iter_mut().enumerate() {
+        let digits = std::str::from_utf8(&hex[i * 2..i * 2 + 2]).unwrap();
+        *byte =
+            match u8::from_str_radix(digits, 16) {
+                Ok(x) => x,
+                Err(_) => return Err(format!("Invalid hex byte “{}”", digits)),
+            };
+    }
+
+    Ok(Some(report))
+}
+
+/* Send all reports from a recording through the given transport */
+pub fn replay(path: &str, transport: &dyn Transport) -> Result<(), String> {
+    let file =
+        match File::open(path) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Failed to open {}: {}", path, e)),
+        };
+
+    for (i, line_opt) in BufReader::new(file).lines().enumerate() {
+        let line =
+            match line_opt {
+                Ok(l) => l,
+                Err(e) => return Err(format!("Read error: {}", e)),
+            };
+
+        match parse_report(&line) {
+            Ok(Some(report)) => transport.write(&report)?,
+            Ok(None) => (),
+            Err(e) => return Err(format!("{}:{}: {}", path, i + 1, e)),
+        }
+    }
+
+    Ok(())
+}
@@ -2,13 +2,15 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use rand::seq::SliceRandom;
-use std::io::BufRead;
+
+use crate::keyspec::KeySpec;
 
 
 pub type Color = (u8, u8, u8);
 
 pub trait ColorMethods: std::marker::Sized {
     const BLACK: Self;
+    const WHITE: Self;
 
     const RED: Self;
     const GREEN: Self;
@@ -21,23 +23,38 @@ pub trait ColorMethods: std::marker::Sized {
     fn from_str(s: &str) -> Result<Self, String>;
 }
 
+#[derive(Clone)]
 pub struct Gradient {
     pub colors: Vec<(Color, u8)>,
 }
 
+#[derive(Clone)]
 pub struct KeyMap {
     pub map: Vec<Color>,
 }
 
+#[derive(Clone)]
 pub enum ColorParam {
     Color(Color),
     Rainbow,
     Randomized,
     Gradient(Gradient),
 
-    PerKey(KeyMap),
+    PerKey(KeySpec),
+}
+
+/* A key press or release, read from a keyboard (see input.rs) */
+#[derive(Clone, Copy)]
+pub struct KeyEvent {
+    /* Index of the keyboard (in the slice given to Input::open()) */
+    pub keyboard: usize,
+    /* KEY_* code from linux/input-event-codes.h */
+    pub code: u16,
+    /* Pressed (or released) */
+    pub down: bool,
 }
 
+#[derive(Clone, Copy)]
 pub enum Direction {
     Right = 1,
     Left = 2,
@@ -46,8 +63,35 @@ pub enum Direction {
 }
 
 
+/* Convert HSV (each in [0, 1]) to RGB (each in [0, 1]) */
+pub fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32) {
+    let c = v * s;
+    let h1 = h * 6.0;
+    let x = c * (1.0 - (h1 % 2.0 - 1.0).abs());
+
+    let (r1, g1, b1) =
+        if h1 < 1.0 {
+            (c, x, 0.0)
+        } else if h1 < 2.0 {
+            (x, c, 0.0)
+        } else if h1 < 3.0 {
+            (0.0, c, x)
+        } else if h1 < 4.0 {
+            (0.0, x, c)
+        } else if h1 < 5.0 {
+            (x, 0.0, c)
+        } else {
+            (c, 0.0, x)
+        };
+
+    let m = v - c;
+    (r1 + m, g1 + m, b1 + m)
+}
+
+
 impl ColorMethods for Color {
     const BLACK: Color      = (0x00, 0x00, 0x00);
+    const WHITE: Color      = (0xff, 0xff, 0xff);
 
     const RED: Color        = (0xff, 0x00, 0x00);
     const GREEN: Color      = (0x00, 0xff, 0x00);
@@ -59,9 +103,9 @@ impl ColorMethods for Color {
 
     fn from_str(s: &str) -> Result<Color, String> {
         fn hex_nibble(c: u8) -> u8 {
-            if c >= 48 && c <= 57 {
+            if (48..=57).contains(&c) {
                 c - 48
-            } else if c >= 97 && c <= 102 {
+            } else if (97..=102).contains(&c) {
                 c - 97 + 10
             } else {
                 unreachable!();
@@ -106,17 +150,18 @@ impl ColorParam {
             ColorParam::Gradient(g) => g.colors[0].0,
 
             ColorParam::PerKey(pk) => {
+                let colors = pk.colors();
                 let mut color = (0u32, 0u32, 0u32);
 
-                for c in &pk.map {
+                for c in &colors {
                     color.0 += c.0 as u32;
                     color.1 += c.1 as u32;
                     color.2 += c.2 as u32;
                 }
 
-                ((color.0 / pk.map.len() as u32) as u8,
-                 (color.1 / pk.map.len() as u32) as u8,
-                 (color.2 / pk.map.len() as u32) as u8)
+                ((color.0 / colors.len() as u32) as u8,
+                 (color.1 / colors.len() as u32) as u8,
+                 (color.2 / colors.len() as u32) as u8)
             }
         }
     }
@@ -173,6 +218,7 @@ impl ColorParam {
             }
 
             ColorParam::PerKey(pk) => {
+                let colors = pk.colors();
                 let mut i = 0;
                 let mut gc = vec![
                     (Color::BLACK,   0),
@@ -187,21 +233,29 @@ impl ColorParam {
                     (Color::BLACK, 100)
                 ];
 
-                for gci in 0..10 {
-                    let end_i = (gci * pk.map.len() + 5) / 10;
+                /* Every stop gets the average of its tenth of the colors */
+                for (gci, stop) in gc.iter_mut().enumerate() {
+                    let end_i = ((gci + 1) * colors.len() + 5) / 10;
                     let mut color = (0u32, 0u32, 0u32);
                     let diff_i = (end_i - i) as u32;
 
+                    /* Fewer than ten colors: Take the next one */
+                    if diff_i == 0 {
+                        stop.0 = colors.get(i).or(colors.last()).copied()
+                                       .unwrap_or(Color::BLACK);
+                        continue;
+                    }
+
                     while i < end_i {
-                        color.0 += pk.map[i].0 as u32;
-                        color.1 += pk.map[i].1 as u32;
-                        color.2 += pk.map[i].2 as u32;
+                        color.0 += colors[i].0 as u32;
+                        color.1 += colors[i].1 as u32;
+                        color.2 += colors[i].2 as u32;
                         i += 1;
                     }
 
-                    (gc[gci].0).0 = (color.0 / diff_i) as u8;
-                    (gc[gci].0).1 = (color.1 / diff_i) as u8;
-                    (gc[gci].0).2 = (color.2 / diff_i) as u8;
+                    stop.0 = ((color.0 / diff_i) as u8,
+                              (color.1 / diff_i) as u8,
+                              (color.2 / diff_i) as u8);
                 }
 
                 Gradient {
@@ -214,6 +268,10 @@ impl ColorParam {
 
 
 impl Gradient {
+    /* Most colors the keyboards can take (see serialize()) */
+    pub const MAX_COLORS: usize = 10;
+
+    /* Takes any number of colors (see Gradient::MAX_COLORS, though) */
     pub fn from_str(s: &str) -> Result<Gradient, String> {
         let mut proto_vec = Vec::<(Color, Option<u8>)>::new();
 
@@ -223,7 +281,14 @@ impl Gradient {
 
             let coli =
                 if let Some(is) = gcis.next() {
-                    let val = is.parse().unwrap();
+                    let val =
+                        match is.parse::<u8>() {
+                            Ok(x) => x,
+                            Err(e) =>
+                                return Err(format!("Invalid gradient \
+                                                    position “{}”: {}",
+                                                   is, e)),
+                        };
                     if val > 100 {
                         return Err(String::from("Gradient positions must not \
                                                  exceed 100"));
@@ -239,9 +304,6 @@ impl Gradient {
 
         if proto_vec.is_empty() {
             return Err(String::from("Gradients must have at least one color"));
-        } else if proto_vec.len() > 10 {
-            return Err(String::from("Gradients cannot have more than ten \
-                                     colors"));
         }
 
         if let Some(x) = proto_vec.first_mut() {
@@ -293,6 +355,48 @@ impl Gradient {
         Ok(gradient)
     }
 
+    /* Color at the given position (in [0, 100]), interpolating linearly */
+    pub fn sample(&self, pos: f32) -> Color {
+        let cv = &self.colors;
+
+        let mut j = 0;
+        while j < cv.len() && (cv[j].1 as f32) < pos {
+            j += 1;
+        }
+
+        if j == cv.len() {
+            cv[j - 1].0
+        } else if j == 0 {
+            cv[j].0
+        } else {
+            let a = (pos - cv[j - 1].1 as f32)
+                  / (cv[j].1 - cv[j - 1].1) as f32;
+
+            (((1.0 - a) * (cv[j - 1].0).0 as f32 +
+              a * (cv[j].0).0 as f32 + 0.5) as u8,
+             ((1.0 - a) * (cv[j - 1].0).1 as f32 +
+              a * (cv[j].0).1 as f32 + 0.5) as u8,
+             ((1.0 - a) * (cv[j - 1].0).2 as f32 +
+              a * (cv[j].0).2 as f32 + 0.5) as u8)
+        }
+    }
+
+    pub fn deserialize(from: &[u8]) -> Result<Gradient, String> {
+        let len = from[0] as usize;
+
+        if len == 0 || len > 10 {
+            return Err(format!("Invalid gradient color count {}", len));
+        }
+
+        Ok(Gradient {
+            colors: (0..len).map(|i| ((from[i * 4 + 1],
+                                       from[i * 4 + 2],
+                                       from[i * 4 + 3]),
+                                      from[i * 4 + 4]))
+                            .collect(),
+        })
+    }
+
     pub fn serialize(&self, to: &mut [u8]) {
         let len = self.colors.len();
 
@@ -322,25 +426,6 @@ impl Gradient {
 
 
 impl KeyMap {
-    pub fn from_stdin() -> Result<KeyMap, String> {
-        let mut map = Vec::with_capacity(121);
-        for _ in 0..121 {
-            map.push(Color::BLACK);
-        }
-
-        for (i, line_opt) in std::io::stdin().lock().lines().enumerate() {
-            let line =
-                match line_opt {
-                    Ok(l) => l,
-                    Err(e) => return Err(format!("Read error: {}", e)),
-                };
-
-            map[i] = Color::from_str(&line)?;
-        }
-
-        Ok(KeyMap { map })
-    }
-
     pub fn raw(&self) -> &[u8] {
         unsafe {
             std::slice::from_raw_parts(self.map.as_ptr() as *const u8,
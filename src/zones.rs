@@ -0,0 +1,252 @@
+// SPDX-FileCopyrightText: 2024 Hanna Czenczek <hanna@xanclic.moe>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/*
+ * Zones: Named groups of keys (e.g. “wasd” or “numpad”) that per-key color
+ * specifications can refer to, and that effects can be limited to (zone=).
+ * Besides the built-in ones, zones can be defined in the configuration file
+ * (see config.rs); such a zone replaces a built-in one with the same name.
+ *
+ * Keys that a keyboard does not have are left out of a zone on it, so the
+ * same zone works for all keyboard models.
+ */
+
+use crate::labels::Labels;
+use crate::layout::Layout;
+
+
+/* Built-in zones, by their (US position) key names */
+const BUILTIN: [(&str, &[&str]); 11] = [
+    ("frow", &["escape", "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8", "f9",
+               "f10", "f11", "f12"]),
+    ("numrow", &["backtick", "1", "2", "3", "4", "5", "6", "7", "8", "9", "0",
+                 "minus", "equal"]),
+    ("letters", &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l",
+                  "m", "n", "o", "p", "q", "r", "s", "t", "u", "v", "w", "x",
+                  "y", "z"]),
+    ("wasd", &["w", "a", "s", "d"]),
+    ("modifiers", &["lshift", "rshift", "lcontrol", "rcontrol", "lalt",
+                    "ralt", "meta", "fn", "menu"]),
+    ("arrows", &["up", "down", "left", "right"]),
+    ("nav", &["print", "scroll-lock", "pause", "insert", "home", "page-up",
+              "delete", "end", "page-down"]),
+    ("numpad", &["num-lock", "num-slash", "num-asterisk", "num-minus",
+                 "num-7", "num-8", "num-9", "num-plus", "num-4", "num-5",
+                 "num-6", "num-1", "num-2", "num-3", "num-enter", "num-0",
+                 "num-decimal"]),
+    ("media", &["mute-mic", "mute-speaker", "gaming-mode"]),
+    ("plate", &["signature-plate"]),
+    ("knob", &["volume-knob"]),
+];
+
+
+#[derive(Clone)]
+pub enum KeyRef {
+    /* Key name or (localized) label, as given by the user */
+    Label(String),
+    /* Key name (as used in layout files) */
+    Key(String),
+}
+
+#[derive(Clone)]
+pub struct Zone {
+    pub name: String,
+    pub keys: Vec<KeyRef>,
+}
+
+#[derive(Clone)]
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+
+/* Split a number off the end of @s, e.g. “f12” → (“f”, 12) */
+fn split_number(s: &str) -> Option<(&str, usize)> {
+    let prefix = s.trim_end_matches(|c: char| c.is_ascii_digit());
+    s[prefix.len()..].parse().ok().map(|n| (prefix, n))
+}
+
+/* Expand a key range like “f1..f12”, “num-0..num-9”, or “a..f” */
+fn expand_range(first: &str, last: &str) -> Result<Vec<String>, String> {
+    let invalid = || format!("Invalid key range “{}..{}”", first, last);
+
+    if let (Some((fp, f)), Some((lp, l))) =
+        (split_number(first), split_number(last))
+    {
+        if fp != lp || f > l {
+            return Err(invalid());
+        }
+
+        return Ok((f..=l).map(|n| format!("{}{}", fp, n)).collect());
+    }
+
+    let mut fc = first.chars();
+    let mut lc = last.chars();
+    match (fc.next(), fc.next(), lc.next(), lc.next()) {
+        (Some(f), None, Some(l), None) if f.is_alphabetic() && f <= l =>
+            Ok((f..=l).map(|c| c.to_string()).collect()),
+
+        _ => Err(invalid()),
+    }
+}
+
+/*
+ * Parse a comma-separated list of key names, labels, zone names, and ranges
+ * (e.g. “f1..f12”).  A leading backslash makes a key be taken literally (e.g.
+ * “\#” for the key labelled #).  Returns the names and labels in lower case,
+ * zone names are not resolved.
+ */
+pub fn parse_keys(keys: &str) -> Result<Vec<String>, String> {
+    let mut names = Vec::new();
+
+    for key in keys.split(',').map(|k| k.trim().to_lowercase()) {
+        if key.is_empty() {
+            return Err(format!("Empty key name in “{}”", keys));
+        }
+
+        if let Some(label) = key.strip_prefix('\\') {
+            names.push(String::from(label));
+        } else if let Some((first, last)) = key.split_once("..") {
+            names.extend(expand_range(first, last)?);
+        } else {
+            names.push(key);
+        }
+    }
+
+    Ok(names)
+}
+
+
+impl Zones {
+    /*
+     * The built-in zones plus the given (name, key list) definitions (see
+     * parse_keys()).  Definitions can refer to zones defined before them.
+     */
+    pub fn new(definitions: &[(String, String)]) -> Result<Zones, String> {
+        let mut zones = Zones {
+            zones: BUILTIN.iter().map(|(name, keys)| Zone {
+                name: String::from(*name),
+                keys: keys.iter().map(|k| KeyRef::Key(String::from(*k)))
+                          .collect(),
+            }).collect(),
+        };
+
+        for (name, keys) in definitions {
+            let mut refs = Vec::new();
+            for key in parse_keys(keys)? {
+                match zones.get(&key) {
+                    Some(zone) => refs.extend(zone.keys.iter().cloned()),
+                    None => refs.push(KeyRef::Label(key)),
+                }
+            }
+
+            zones.zones.retain(|z| z.name != *name);
+            zones.zones.push(Zone {
+                name: name.clone(),
+                keys: refs,
+            });
+        }
+
+        Ok(zones)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Zone> {
+        self.zones.iter().find(|z| z.name == name)
+    }
+
+    /*
+     * LEDs of the given zone on a keyboard with the given layout (None if
+     * there is no such zone)
+     */
+    pub fn leds(&self, name: &str, layout: &Layout, labels: &Labels)
+        -> Option<Vec<usize>>
+    {
+        let zone = self.get(name)?;
+
+        Some(zone.keys.iter().filter_map(|key| match key {
+            KeyRef::Label(label) => layout.led(labels.key(label)),
+            KeyRef::Key(key) => layout.led(key),
+        }).collect())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.zones.iter().map(|z| z.name.as_str()).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone_leds(zones: &Zones, name: &str) -> Option<Vec<usize>> {
+        let layout = Layout::parse("test", "model test Test\n\
+                                            pid 0102\n\
+                                            layout ansi\n\
+                                            leds 6\n\
+                                            led 0 w\n\
+                                            led 1 a\n\
+                                            led 2 s\n\
+                                            led 3 d\n\
+                                            led 4 y\n\
+                                            led 5 f1\n").unwrap();
+        let labels = Labels::parse("test", "labels de\nz y\n").unwrap();
+        zones.leds(name, &layout, &labels)
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(expand_range("f1", "f3").unwrap(), ["f1", "f2", "f3"]);
+        assert_eq!(expand_range("num-8", "num-10").unwrap(),
+                   ["num-8", "num-9", "num-10"]);
+        assert_eq!(expand_range("x", "z").unwrap(), ["x", "y", "z"]);
+        assert_eq!(expand_range("f5", "f5").unwrap(), ["f5"]);
+
+        assert_eq!(parse_keys("Escape, F1..F2,\\#").unwrap(),
+                   ["escape", "f1", "f2", "#"]);
+    }
+
+    #[test]
+    fn reversed_ranges() {
+        assert_eq!(expand_range("f12", "f1").unwrap_err(),
+                   "Invalid key range “f12..f1”");
+        assert_eq!(expand_range("z", "a").unwrap_err(),
+                   "Invalid key range “z..a”");
+    }
+
+    #[test]
+    fn bad_endpoints() {
+        for (first, last) in [("f1", "num-3"), ("escape", "f1"), ("a", "5"),
+                              ("", "f1"), ("f1", ""), ("1", "a"),
+                              ("ab", "az")]
+        {
+            assert_eq!(expand_range(first, last).unwrap_err(),
+                       format!("Invalid key range “{}..{}”", first, last));
+        }
+
+        assert_eq!(parse_keys("w,,a").unwrap_err(),
+                   "Empty key name in “w,,a”");
+        assert_eq!(parse_keys("f1..").unwrap_err(),
+                   "Invalid key range “f1..”");
+    }
+
+    #[test]
+    fn overlapping_zones() {
+        let zones = Zones::new(&[
+            (String::from("left"), String::from("wasd,z")),
+            (String::from("top"), String::from("w,f1..f2")),
+            /* Replaces the built-in one, and refers to another zone */
+            (String::from("wasd"), String::from("top,s")),
+        ]).unwrap();
+
+        /* Labels are resolved per keyboard: “z” is key “y” here */
+        assert_eq!(zone_leds(&zones, "left"), Some(vec![0, 1, 2, 3, 4]));
+        /* Keys the keyboard does not have (f2) are left out */
+        assert_eq!(zone_leds(&zones, "top"), Some(vec![0, 5]));
+        assert_eq!(zone_leds(&zones, "wasd"), Some(vec![0, 5, 2]));
+        assert_eq!(zone_leds(&zones, "arrows"), Some(vec![]));
+        assert_eq!(zone_leds(&zones, "nope"), None);
+
+        assert_eq!(zones.names().iter().filter(|n| **n == "wasd").count(), 1);
+    }
+}